@@ -0,0 +1,9 @@
+//! Raw bytes from the first byte of a connection: login, new player or account login, then
+//! game messages if the login went through
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    legbone_fuzz::run_session(data.to_vec());
+});
@@ -0,0 +1,16 @@
+//! Game messages after a valid login. The first byte chooses the protocol, the rest are frames
+//! as sent by the client, lengths included.
+#![no_main]
+
+use legbone::Protocol;
+use legbone_fuzz::{login_frame, run_session};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some((&selector, frames)) = data.split_first() {
+        let protocol = Protocol::ALL[selector as usize % Protocol::ALL.len()];
+        let mut bytes = login_frame(protocol);
+        bytes.extend_from_slice(frames);
+        run_session(bytes);
+    }
+});
@@ -0,0 +1,129 @@
+//! Runs fuzzed bytes through a connection over an in-memory transport, the same way the server
+//! handles a client from its first byte.
+use legbone::{
+    config::{self, Config, CONFIG},
+    map::{self, MapType},
+    network::connection::{transport::MemoryTransport, Connection},
+    world::message::{PlayerToWorldMessage, WorldToPlayerMessage},
+    Protocol,
+};
+use std::sync::{Once, OnceLock};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    runtime::Runtime,
+    sync::mpsc::channel,
+    task,
+};
+
+fn init() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let config = Config {
+            server: config::Server {
+                ip: [127, 0, 0, 1].into(),
+                port: 0,
+                debug_commands: true,
+            },
+            world: config::World {
+                map: config::Map {
+                    map_type: MapType::Checkerboard,
+                    file: None,
+                    tile: None,
+                },
+                day_night_cycle: false,
+            },
+            recording: None,
+            discoveries: None,
+            connection: Default::default(),
+        };
+        map::init_map(&config.world.map).unwrap();
+        CONFIG.set(config).unwrap();
+    });
+}
+
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| Runtime::new().unwrap())
+}
+
+/// Login message of an existing player, length included
+pub fn login_frame(protocol: Protocol) -> Vec<u8> {
+    let mut frame = vec![67, 0, 0x00, 0x00, 0x01, 0x01, 0x00];
+    frame.extend((protocol as u16).to_le_bytes());
+    for (field, length) in [("Fuzz", 30), ("secret", 30)] {
+        frame.extend(field.as_bytes());
+        frame.resize(frame.len() + length - field.len(), 0);
+    }
+    frame
+}
+
+/// Sends `bytes` to a new connection, as the client, and waits until the connection finishes.
+/// Panics of the connection are raised again for the fuzzer. Inputs with the `panic` debug
+/// command are skipped, as that panic is intended.
+pub fn run_session(bytes: Vec<u8>) {
+    if bytes.windows(5).any(|window| window == b"panic") {
+        return;
+    }
+    init();
+
+    runtime().block_on(async move {
+        let (transport, peer) = MemoryTransport::pair(
+            "127.0.0.1:7171".parse().unwrap(),
+            "127.0.0.1:50000".parse().unwrap(),
+        );
+
+        // Stands in for the world, keeping the channel of the player open. Every step is
+        // accepted and every chat heard by the player, so both reach their encoders
+        let (sender, mut receiver) = channel(64);
+        let world = task::spawn(async move {
+            let mut player = None;
+            while let Some(message) = receiver.recv().await {
+                match message {
+                    PlayerToWorldMessage::LoadPlayer(character, sender) => {
+                        let _ = sender.try_send(WorldToPlayerMessage::Loaded(character.position));
+                        player = Some((character, sender));
+                    }
+                    PlayerToWorldMessage::Walk(_, direction) => {
+                        if let Some((character, sender)) = player.as_mut() {
+                            let from = character.position;
+                            character.position = from + direction;
+                            let _ = sender.try_send(WorldToPlayerMessage::Walked {
+                                from,
+                                to: character.position,
+                                direction,
+                            });
+                        }
+                    }
+                    PlayerToWorldMessage::Chat(_, chat_type, message) => {
+                        if let Some((character, sender)) = player.as_ref() {
+                            let _ = sender.try_send(WorldToPlayerMessage::Chat {
+                                chat_type,
+                                message,
+                                sender: character.name.clone(),
+                                position: character.position,
+                            });
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        let connection = task::spawn(Connection::run(transport, sender));
+
+        let (mut reader, mut writer) = tokio::io::split(peer);
+        task::spawn(async move {
+            let _ = writer.write_all(&bytes).await;
+            let _ = writer.shutdown().await;
+        });
+        let mut sent = vec![];
+        let _ = reader.read_to_end(&mut sent).await;
+
+        if let Err(err) = connection.await
+            && err.is_panic()
+        {
+            std::panic::resume_unwind(err.into_panic());
+        }
+        world.abort();
+    });
+}
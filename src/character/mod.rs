@@ -1,15 +1,16 @@
 use num_enum::TryFromPrimitive;
+use serde_derive::{Deserialize, Serialize};
 
 pub mod player;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Gender {
     Female,
     Male,
 }
 
 #[repr(u8)]
-#[derive(Debug, TryFromPrimitive)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive)]
 pub enum FightMode {
     Offensive = 1,
     Normal = 2,
@@ -17,7 +18,7 @@ pub enum FightMode {
 }
 
 #[repr(u8)]
-#[derive(Debug, TryFromPrimitive)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive)]
 pub enum FightStance {
     StandStill = 0,
     Chase = 1,
@@ -27,7 +28,7 @@ pub enum FightStance {
 }
 
 #[repr(u8)]
-#[derive(Debug, Copy, Clone, TryFromPrimitive)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive, Serialize, Deserialize)]
 pub enum Direction {
     North = 0,
     East = 1,
@@ -50,14 +51,14 @@ pub enum HealthStatus {
 }
 
 #[repr(u8)]
-#[derive(Debug, TryFromPrimitive)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive, Serialize, Deserialize)]
 pub enum CharacterUpdateType {
     HealthStatus = 1,
     LightLevel = 2,
     Outfit = 3,
 }
 
-#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub struct Outfit {
     pub(crate) outfit_type: OutfitType,
     pub(crate) colors: OutfitColors,
@@ -80,7 +81,7 @@ impl Outfit {
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub struct OutfitColors {
     pub(crate) head: u8,
     pub(crate) body: u8,
@@ -122,7 +123,7 @@ impl OutfitColors {
 }
 
 #[repr(u8)]
-#[derive(Debug, TryFromPrimitive, Eq, PartialEq, Copy, Clone)]
+#[derive(Debug, TryFromPrimitive, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub enum OutfitType {
     //Since v3.0
     Human = 1,
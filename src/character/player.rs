@@ -1,6 +1,7 @@
 use super::{Gender, OutfitColors};
 use crate::map::position::Position;
 use num_enum::TryFromPrimitive;
+use serde_derive::{Deserialize, Serialize};
 
 #[derive(Clone, Debug)]
 pub struct Player {
@@ -13,7 +14,7 @@ pub struct Player {
     pub(crate) gender: Gender,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Skills {
     pub(crate) sword: u8,
     pub(crate) club: u8,
@@ -26,7 +27,7 @@ pub struct Skills {
     pub(crate) missile: u8,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Stats {
     pub(crate) health_points: u16,
     pub(crate) capacity: u16,
@@ -41,7 +42,7 @@ pub struct Stats {
 }
 
 #[repr(u8)]
-#[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive, Serialize, Deserialize)]
 pub enum InventorySlot {
     Helmet = 1,
     Necklace = 2,
@@ -7,21 +7,33 @@
 ///what is going on. Translating these bytes keeps greater coherence between text
 ///console and screen chars
 pub fn translate(input: &str) -> Vec<u8> {
-    input
-        .as_bytes()
-        .iter()
-        .map(|&c| translate_char(c))
+    to_game_bytes(input)
+        .into_iter()
+        .map(translate_char)
         .collect::<Vec<u8>>()
 }
 
 pub fn translate_upper(input: &str) -> Vec<u8> {
-    input
-        .as_bytes()
-        .iter()
-        .map(|&c| translate_char_upper(c))
+    to_game_bytes(input)
+        .into_iter()
+        .map(translate_char_upper)
         .collect::<Vec<u8>>()
 }
 
+///Clients send one byte per char, which is read as ISO/IEC 8859-1 so that any
+///sequence of bytes is a valid string
+pub fn from_game_bytes(input: &[u8]) -> String {
+    input.iter().map(|&c| c as char).collect()
+}
+
+///Inverse of `from_game_bytes`, chars that don't fit in a byte are sent as '?'
+pub fn to_game_bytes(input: &str) -> Vec<u8> {
+    input
+        .chars()
+        .map(|c| u8::try_from(c).unwrap_or(b'?'))
+        .collect()
+}
+
 const fn translate_char(input: u8) -> u8 {
     match input {
         0x5b => 0xc4, //Ä
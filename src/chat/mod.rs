@@ -1,4 +1,6 @@
+use crate::map::position::{Position, VIEWPORT_HEIGHT, VIEWPORT_WIDTH};
 use num_enum::TryFromPrimitive;
+use serde_derive::{Deserialize, Serialize};
 use std::convert::TryFrom;
 
 pub mod encoding;
@@ -7,7 +9,7 @@ pub mod encoding;
 pub struct InvalidChatQualifier(Option<char>);
 
 #[repr(u8)]
-#[derive(Debug, Copy, Clone, TryFromPrimitive, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, TryFromPrimitive, Eq, PartialEq, Serialize, Deserialize)]
 pub enum ChatType {
     RedScreenOnly = 0x41, //#a
     GreyConsoleOnly = 0x42,
@@ -30,6 +32,37 @@ pub enum ChatType {
     Yell = 0x59,    //#y
 }
 
+/// What is heard of a whisper by players who see the speaker but are not next to it
+pub const WHISPER_FROM_AFAR: &str = "pspsps";
+
+impl ChatType {
+    /// Normal, whispered and yelled messages are heard by other players, the rest only by the
+    /// player who sent them
+    pub const fn is_heard_by_others(self) -> bool {
+        matches!(self, Self::Normal | Self::Whisper | Self::Yell)
+    }
+
+    /// Whether a player on `listener` hears the message said on `speaker`. Normal messages reach
+    /// whoever sees the speaker, whispers only the tiles around it and yells twice the view,
+    /// also on the floors right above and below
+    pub fn is_heard(self, speaker: Position, listener: Position) -> bool {
+        let (dx, dy) = (speaker.x.abs_diff(listener.x), speaker.y.abs_diff(listener.y));
+        match self {
+            Self::Normal => speaker.is_in_viewport(listener),
+            Self::Whisper => speaker.z == listener.z && dx <= 1 && dy <= 1,
+            Self::Yell => speaker.z.abs_diff(listener.z) <= 1 && dx <= VIEWPORT_WIDTH && dy <= VIEWPORT_HEIGHT,
+            _ => speaker == listener,
+        }
+    }
+}
+
+/// Splits a private message, "*name* text", into the name of the receiver and the text
+pub fn parse_private_message(msg: &str) -> Option<(&str, &str)> {
+    let (name, text) = msg.strip_prefix('*')?.split_once('*')?;
+    let (name, text) = (name.trim(), text.trim_start());
+    (!name.is_empty() && !text.is_empty()).then_some((name, text))
+}
+
 impl TryFrom<Option<char>> for ChatType {
     type Error = InvalidChatQualifier;
 
@@ -48,3 +81,18 @@ impl TryFrom<Option<char>> for ChatType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_private_message() {
+        assert_eq!(parse_private_message("*Bob* hello"), Some(("Bob", "hello")));
+        assert_eq!(parse_private_message("*Bob Smith*hi *there*"), Some(("Bob Smith", "hi *there*")));
+        assert_eq!(parse_private_message("*Bob*"), None);
+        assert_eq!(parse_private_message("** hello"), None);
+        assert_eq!(parse_private_message("*Bob hello"), None);
+        assert_eq!(parse_private_message("Bob* hello"), None);
+    }
+}
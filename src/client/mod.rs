@@ -0,0 +1,247 @@
+//! Client side of the protocol, used to script sessions against a server without the game
+//! client. Messages sent by the server are decoded with the `dissector`.
+use crate::{
+    character::{Direction, Gender, OutfitColors},
+    io::WriteExt,
+    map::position::Position,
+    network::{
+        dissector::{self, DecodedMessage, FieldValue, MessageKind},
+        header::{HeaderReceive, HeaderSend},
+    },
+    protocol::{MessageForms, PlayerDataLayout},
+    Protocol,
+};
+use anyhow::{anyhow, Result};
+use std::{
+    collections::VecDeque,
+    io::Cursor,
+    net::{SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct NewPlayer {
+    pub name: String,
+    pub password: String,
+    pub gender: Gender,
+    pub outfit: OutfitColors,
+    pub real_name: String,
+    pub location: String,
+    pub email: String,
+    pub comment: String,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CharacterEntry {
+    pub name: String,
+    pub world: String,
+    pub address: SocketAddrV4,
+}
+
+pub struct Client<S = TcpStream> {
+    stream: S,
+    protocol: Protocol,
+    received: VecDeque<DecodedMessage>,
+}
+
+impl Client {
+    pub async fn connect(address: SocketAddr, protocol: Protocol) -> Result<Self> {
+        Ok(Self::new(TcpStream::connect(address).await?, protocol))
+    }
+
+    /// Account login of 6.5+. The server answers with the character list and the client is
+    /// expected to connect again to the address of the chosen character and `login` with it.
+    pub async fn account_login(
+        address: SocketAddr,
+        protocol: Protocol,
+        account_number: u32,
+        password: &str,
+    ) -> Result<Vec<CharacterEntry>> {
+        let mut client = Self::connect(address, protocol).await?;
+
+        let mut buf = Cursor::new(vec![]);
+        buf.write_all(&[0x01, 0x01, 0x00]).await?;
+        buf.write_u16_le(protocol as u16).await?;
+        buf.write_u32_le(account_number).await?;
+        buf.write_length_and_string(password).await?;
+        client.send_frame(&buf.into_inner()).await?;
+
+        // Unlike the game messages, the length of the character list does not count itself
+        let length = timeout(RECEIVE_TIMEOUT, client.stream.read_u16_le()).await??;
+        let mut frame = vec![0_u8; length as usize];
+        client.stream.read_exact(&mut frame).await?;
+
+        let message = dissector::decode_character_list(&frame, protocol).await?;
+        let characters = match message.field("characters") {
+            Some(FieldValue::List(entries)) => entries
+                .iter()
+                .map(|entry| {
+                    let mut character = CharacterEntry {
+                        name: String::new(),
+                        world: String::new(),
+                        address: SocketAddrV4::new([0, 0, 0, 0].into(), 0),
+                    };
+                    for field in entry {
+                        match (field.name, &field.value) {
+                            ("name", FieldValue::Text(name)) => character.name = name.clone(),
+                            ("world", FieldValue::Text(world)) => character.world = world.clone(),
+                            ("ip", FieldValue::Ipv4(ip)) => character.address.set_ip(*ip),
+                            ("port", FieldValue::Number(port)) => {
+                                character.address.set_port(*port as u16)
+                            }
+                            _ => {}
+                        }
+                    }
+                    character
+                })
+                .collect(),
+            _ => return Err(anyhow!("Invalid character list: {message}")),
+        };
+
+        client.stream.shutdown().await?;
+        Ok(characters)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    /// Uses an already connected stream, e.g. one side of a `tokio::io::duplex`
+    pub fn new(stream: S, protocol: Protocol) -> Self {
+        Self {
+            stream,
+            protocol,
+            received: VecDeque::new(),
+        }
+    }
+
+    pub const fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Logs in with an existing character, using the 67 bytes login message
+    pub async fn login(&mut self, name: &str, password: &str) -> Result<()> {
+        let mut buf = Cursor::new(vec![]);
+        buf.write_all(&[0x00, 0x00, 0x01, 0x01, 0x00]).await?;
+        buf.write_u16_le(self.protocol as u16).await?;
+        buf.write_string_with_fixed_length(name, 30).await?;
+        buf.write_string_with_fixed_length(password, 30).await?;
+
+        self.send_frame(&buf.into_inner()).await
+    }
+
+    /// Creates a new character, using the 221 (1.03), 223 (3.x) or 723 (4.x and 5.01) bytes
+    /// message
+    pub async fn create_new_player(&mut self, player: &NewPlayer) -> Result<()> {
+        let mut buf = Cursor::new(vec![]);
+        buf.write_all(&[0x00, 0x00, 0x00, 0x01, 0x00]).await?;
+        buf.write_u16_le(self.protocol as u16).await?;
+        buf.write_string_with_fixed_length(&player.name, 30).await?;
+        buf.write_string_with_fixed_length(&player.password, 30).await?;
+        buf.write_gender(player.gender, self.protocol).await?;
+        buf.write_all(&[0x01, 0x01]).await?;
+        buf.write_outfit_colors(player.outfit).await?;
+        buf.write_string_with_fixed_length(&player.real_name, 50).await?;
+        let location_size = match self.protocol.capabilities().forms {
+            MessageForms::Short => 48,
+            MessageForms::Full => 50,
+        };
+        buf.write_string_with_fixed_length(&player.location, location_size).await?;
+        buf.write_string_with_fixed_length(&player.email, 50).await?;
+        if self.protocol.capabilities().player_data == PlayerDataLayout::AccountWithComment {
+            buf.write_string_with_fixed_length(&player.comment, 500).await?;
+        }
+
+        self.send_frame(&buf.into_inner()).await
+    }
+
+    pub async fn walk(&mut self, direction: Direction) -> Result<()> {
+        self.send(HeaderReceive::Walk, &[direction as u8]).await
+    }
+
+    pub async fn auto_walk(&mut self, position: Position) -> Result<()> {
+        let mut buf = Cursor::new(vec![]);
+        buf.write_position(position, self.protocol).await?;
+        self.send(HeaderReceive::AutoWalk, &buf.into_inner()).await
+    }
+
+    pub async fn change_direction(&mut self, direction: Direction) -> Result<()> {
+        self.send(HeaderReceive::ChangeDirection, &[direction as u8]).await
+    }
+
+    pub async fn chat(&mut self, message: &str) -> Result<()> {
+        let mut buf = Cursor::new(vec![]);
+        buf.write_length_and_string(message).await?;
+        self.send(HeaderReceive::Chat, &buf.into_inner()).await
+    }
+
+    pub async fn look_at(&mut self, position: Position) -> Result<()> {
+        let mut buf = Cursor::new(vec![]);
+        buf.write_position(position, self.protocol).await?;
+        self.send(HeaderReceive::LookAt, &buf.into_inner()).await
+    }
+
+    pub async fn request_user_list(&mut self) -> Result<()> {
+        self.send(HeaderReceive::UserList, &[]).await
+    }
+
+    pub async fn request_change_data(&mut self) -> Result<()> {
+        self.send(HeaderReceive::RequestChangeData, &[]).await
+    }
+
+    /// Logs out and closes the connection, like the game client does
+    pub async fn logout(mut self) -> Result<()> {
+        self.send(HeaderReceive::Logout, &[]).await?;
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+
+    /// Sends a message with the given header and payload
+    pub async fn send(&mut self, header: HeaderReceive, payload: &[u8]) -> Result<()> {
+        let mut buf = Cursor::new(vec![]);
+        buf.write_u16_le(header as u16).await?;
+        buf.write_all(payload).await?;
+        self.send_frame(&buf.into_inner()).await
+    }
+
+    /// Sends a frame, prepending its length
+    pub async fn send_frame(&mut self, frame: &[u8]) -> Result<()> {
+        self.stream.write_u16_le(frame.len() as u16).await?;
+        self.stream.write_all(frame).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// Returns the next message sent by the server
+    pub async fn receive(&mut self) -> Result<DecodedMessage> {
+        while self.received.is_empty() {
+            let length = timeout(RECEIVE_TIMEOUT, self.stream.read_u16_le()).await??;
+            if length < 2 {
+                return Err(anyhow!("Invalid frame length {length}"));
+            }
+            let mut frame = vec![0_u8; length as usize - 2];
+            self.stream.read_exact(&mut frame).await?;
+
+            self.received
+                .extend(dissector::decode_server_frame(&frame, self.protocol).await?);
+        }
+
+        Ok(self.received.pop_front().unwrap())
+    }
+
+    /// Returns the next message sent by the server with the given header, skipping the others
+    pub async fn receive_until(&mut self, header: HeaderSend) -> Result<DecodedMessage> {
+        loop {
+            let message = self.receive().await?;
+            if message.kind == MessageKind::Server(header) {
+                return Ok(message);
+            }
+            log::trace!("Skipping {}", message.kind);
+        }
+    }
+}
@@ -1,7 +1,8 @@
-use crate::map::MapType;
+use crate::{map::MapType, network::header::HeaderReceive};
 use anyhow::{Result, anyhow};
 use std::net::Ipv4Addr;
 use std::{
+    collections::HashMap,
     sync::OnceLock,
     path::Path
 };
@@ -13,6 +14,10 @@ pub static CONFIG: OnceLock<Config> = OnceLock::new();
 pub struct Config {
     pub server: Server,
     pub world: World,
+    pub recording: Option<Recording>,
+    pub discoveries: Option<Discoveries>,
+    #[serde(default)]
+    pub connection: Connection,
 }
 
 #[derive(Deserialize, Debug)]
@@ -28,6 +33,68 @@ pub struct World {
     pub day_night_cycle: bool,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct Recording {
+    pub enabled: bool,
+    pub directory: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Discoveries {
+    pub enabled: bool,
+    /// JSON lines file shared by every session, appended to
+    pub file: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Connection {
+    /// Frames waiting to be written to the client
+    pub outbound_queue_size: usize,
+    /// Messages from the world waiting to be handled by the connection
+    pub world_queue_size: usize,
+    /// Requests from every player waiting to be handled by the world. When it is full,
+    /// connections wait for room before reading more from their clients
+    pub inbound_queue_size: usize,
+    pub slow_client_policy: SlowClientPolicy,
+    /// Largest frame accepted from the client, length excluded. Clients sending bigger frames
+    /// are disconnected
+    pub max_frame_size: u16,
+    /// Overrides `max_frame_size` for specific messages
+    pub max_frame_sizes: HashMap<HeaderReceive, u16>,
+}
+
+impl Connection {
+    pub fn max_frame_size(&self, header: Option<HeaderReceive>) -> u16 {
+        header
+            .and_then(|header| self.max_frame_sizes.get(&header).copied())
+            .unwrap_or(self.max_frame_size)
+    }
+}
+
+impl Default for Connection {
+    fn default() -> Self {
+        Self {
+            outbound_queue_size: 64,
+            world_queue_size: 64,
+            inbound_queue_size: 1024,
+            slow_client_policy: SlowClientPolicy::default(),
+            max_frame_size: 1024,
+            max_frame_sizes: HashMap::new(),
+        }
+    }
+}
+
+/// What to do when a client does not read its messages as fast as they are produced
+#[derive(Deserialize, Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum SlowClientPolicy {
+    Disconnect,
+    /// Drops updates the client can live without, like world light changes, and disconnects
+    /// only if essential messages can't be delivered
+    #[default]
+    DropNonEssential,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Map {
     pub map_type: MapType,
@@ -1,7 +1,8 @@
 use num_enum::TryFromPrimitive;
+use serde_derive::{Deserialize, Serialize};
 
 #[repr(u8)]
-#[derive(Debug, TryFromPrimitive)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive, Serialize, Deserialize)]
 pub enum MagicEffect {
     //Since v3.0
     DrawBlood = 0,
@@ -44,7 +45,7 @@ pub enum Fluid {
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, Eq, PartialEq, TryFromPrimitive)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, TryFromPrimitive, Serialize, Deserialize)]
 pub enum ObjectUpdateType {
     Remove = 0,
     Add = 1,
@@ -0,0 +1,49 @@
+use anyhow::{anyhow, Result};
+use std::fmt::Write;
+
+/// Encodes bytes as a lowercase hex string without separators
+pub fn encode(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    hex
+}
+
+/// Decodes a hex string. Whitespace between bytes is ignored, so both "0a00ff" and
+/// "0a 00 ff" are accepted
+pub fn decode(hex: &str) -> Result<Vec<u8>> {
+    let digits: Vec<u8> = hex.bytes().filter(|c| !c.is_ascii_whitespace()).collect();
+    if digits.len() % 2 != 0 {
+        return Err(anyhow!("Odd number of hex digits in {hex:?}"));
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair)?;
+            u8::from_str_radix(pair, 16).map_err(|err| anyhow!("Invalid hex byte {pair:?}: {err}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() -> Result<()> {
+        let bytes_before = vec![0x0a, 0x00, 0xff, 0x7f];
+
+        let hex = encode(&bytes_before);
+        assert_eq!(hex, "0a00ff7f");
+
+        let bytes_after = decode("0a 00 FF7f")?;
+        assert_eq!(bytes_before, bytes_after);
+
+        assert!(decode("0a0").is_err());
+        assert!(decode("zz").is_err());
+
+        Ok(())
+    }
+}
@@ -1,8 +1,10 @@
 use crate::{
     character::Gender, character::OutfitColors, map::position::Position,
-    network::header::HeaderSend, Protocol,
+    network::header::HeaderSend,
+    protocol::{GenderEncoding, HeaderFormat, PositionFormat},
+    Protocol,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use tokio::io::{
     AsyncRead,
     AsyncWrite,
@@ -10,14 +12,17 @@ use tokio::io::{
     AsyncWriteExt
 };
 
+pub mod hex;
+
 impl<R: AsyncRead + Unpin> ReadExt for R {}
 
-pub trait ReadExt: AsyncRead + Unpin + Sized {
+pub(crate) trait ReadExt: AsyncRead + Unpin + Sized {
     async fn read_gender(&mut self, protocol: Protocol) -> Result<Gender> {
+        let encoding = protocol.capabilities().gender;
         let gender = match self.read_u8().await? {
             1 => Gender::Male,
-            0 if protocol < Protocol::Tibia501 => Gender::Female,
-            2 if protocol >= Protocol::Tibia501 => Gender::Female,
+            0 if encoding == GenderEncoding::FemaleZero => Gender::Female,
+            2 if encoding == GenderEncoding::FemaleTwo => Gender::Female,
             raw_gender => {
                 log::error!("Unknown gender byte {raw_gender} for protocol {protocol:?}, assuming 'male'.");
                 Gender::Male
@@ -51,7 +56,7 @@ pub trait ReadExt: AsyncRead + Unpin + Sized {
     }
 
     async fn read_position(&mut self, protocol: Protocol) -> Result<Position> {
-        let position = if protocol == Protocol::Tibia103 {
+        let position = if protocol.capabilities().position == PositionFormat::Short {
             let x = self.read_u8().await?;
             let y = self.read_u8().await?;
 
@@ -67,17 +72,6 @@ pub trait ReadExt: AsyncRead + Unpin + Sized {
         Ok(position)
     }
 
-    /// Reads string until null byte is found. Unsafe because it can enter an infinite loop
-    async unsafe fn read_string_until_end(&mut self, buf: &mut String) -> Result<usize> {
-        loop {
-            match self.read_u8().await? {
-                b'\0' => break,
-                c => buf.push(c as char),
-            }
-        }
-        Ok(buf.len())
-    }
-
     /// Reads string until null byte or max_size. Consumes max_size bytes from the stream
     async fn read_string(&mut self, buf: &mut String, max_size: u16) -> Result<usize> {
         for n in 1..=max_size {
@@ -93,19 +87,19 @@ pub trait ReadExt: AsyncRead + Unpin + Sized {
     }
 
     /// Skips an ammount of bytes. Is used in some places where the meaning of the received
-    /// is currently unknown.
-    async fn skip(&mut self, bytes: u16) -> Result<()> {
+    /// is currently unknown. Returns the skipped bytes, to be recorded as discoveries.
+    async fn skip(&mut self, bytes: u16) -> Result<Vec<u8>> {
         let mut buf = vec![0_u8; bytes as usize];
         self.read_exact(&mut buf).await?;
         log::trace!("Skipped {bytes} bytes: {buf:02x?}");
-        Ok(())
+        Ok(buf)
     }
 }
 
 impl<W: AsyncWrite + Unpin> WriteExt for W {}
 
 #[allow(unused)]
-pub trait WriteExt: AsyncWrite + Unpin + Sized {
+pub(crate) trait WriteExt: AsyncWrite + Unpin + Sized {
     async fn write_outfit_colors(&mut self, outfit: OutfitColors) -> Result<()> {
         self.write_u4(outfit.legs, outfit.shoes).await?;
         self.write_u4(outfit.head, outfit.body).await?;
@@ -131,31 +125,44 @@ pub trait WriteExt: AsyncWrite + Unpin + Sized {
     }
 
     async fn write_gender(&mut self, gender: Gender, protocol: Protocol) -> Result<()> {
-        match gender {
-            Gender::Male => self.write_u8(1).await?,
-            Gender::Female if protocol < Protocol::Tibia501 => self.write_u8(0).await?,
-            Gender::Female => self.write_u8(2).await?,
+        match (gender, protocol.capabilities().gender) {
+            (Gender::Male, _) => self.write_u8(1).await?,
+            (Gender::Female, GenderEncoding::FemaleZero) => self.write_u8(0).await?,
+            (Gender::Female, GenderEncoding::FemaleTwo) => self.write_u8(2).await?,
         }
 
         Ok(())
     }
 
-    /// For Tibia 3.x and 4.x the client receives an u8 header. Later clients use u16
-    /// Client 1.03 seems to need 4 bytes before header. Meaning of bytes unknown
+    /// Header width depends on the protocol, see `HeaderFormat`. Fails for headers the
+    /// client does not know
     async fn write_header(&mut self, header: HeaderSend, protocol: Protocol) -> Result<()> {
-        if protocol > Protocol::Tibia400 {
-            self.write_u16_le(header as u16).await?;
-        } else {
-            if protocol == Protocol::Tibia103 {
-                self.write_zeroes(4).await?;
-            }
-            self.write_u8(header as u8).await?;
+        if !protocol.capabilities().supports(header) {
+            return Err(anyhow!("Header {header:?} is not supported by {protocol:?}"));
+        }
+
+        self.write_header_code(header as u16, protocol).await
+    }
+
+    /// Writes any header code the way the protocol writes headers, including codes the client
+    /// may not know. Fails for codes wider than the header
+    async fn write_header_code(&mut self, code: u16, protocol: Protocol) -> Result<()> {
+        let header_format = protocol.capabilities().header;
+        if header_format == HeaderFormat::U16 {
+            return Ok(self.write_u16_le(code).await?);
+        }
+
+        let code = u8::try_from(code)
+            .map_err(|_| anyhow!("Header 0x{code:04x} does not fit in a byte for {protocol:?}"))?;
+        if header_format == HeaderFormat::U8WithPrefix {
+            self.write_zeroes(4).await?;
         }
+        self.write_u8(code).await?;
         Ok(())
     }
 
     async fn write_position(&mut self, position: Position, protocol: Protocol) -> Result<()> {
-        if protocol == Protocol::Tibia103 {
+        if protocol.capabilities().position == PositionFormat::Short {
             self.write_u8(position.x as u8).await?;
             self.write_u8(position.y as u8).await?;
         } else {
@@ -1,15 +1,18 @@
-mod character;
-mod chat;
+pub mod character;
+pub mod chat;
+pub mod client;
 pub mod config;
-mod constants;
-mod io;
+pub mod constants;
+pub mod io;
 pub mod map;
 pub mod network;
 mod persistence;
+pub mod protocol;
 pub mod world;
 
-use clap::{ArgAction, Parser};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 use num_enum::TryFromPrimitive;
+use std::path::PathBuf;
 
 #[repr(u16)]
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, TryFromPrimitive)]
@@ -43,4 +46,52 @@ pub struct Opts {
         help = "Verbosity level (-v or -vv)"
     )]
     pub verbose: u8,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Replays the client side of a session recording and compares the server responses
+    Replay {
+        #[clap(help = "Recording file")]
+        file: PathBuf,
+    },
+    /// Forwards clients to another server, logging the messages of both sides decoded
+    Proxy {
+        #[clap(help = "Address of the server, e.g. 127.0.0.1:7172")]
+        upstream: std::net::SocketAddr,
+    },
+    /// Decodes a session recording or a hex dump of frames, field by field
+    Decode {
+        #[clap(long, help = "Session recording file")]
+        recording: Option<PathBuf>,
+        #[clap(long, help = "Protocol version of the hex dump, e.g. 650")]
+        protocol: Option<u16>,
+        #[clap(long, value_enum, default_value_t = Side::Server, help = "Side that sent the hex dump")]
+        from: Side,
+        #[clap(long, help = "First frame of the hex dump is a login message")]
+        login: bool,
+        #[clap(help = "Hex dump of frames including their lengths, or a file containing it")]
+        hex: Option<String>,
+    },
+    /// Lists the differences between two protocols, message by message
+    Diff {
+        #[clap(help = "Protocol version to compare from, e.g. 412")]
+        from: u16,
+        #[clap(help = "Protocol version to compare to, e.g. 501")]
+        to: u16,
+    },
+    /// Generates the packet layout documentation of every protocol
+    Docs {
+        #[clap(long, help = "Markdown file to write, printed when omitted")]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub enum Side {
+    Client,
+    Server,
 }
@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use tokio::{
     net::TcpListener,
     task,
@@ -7,14 +7,17 @@ use tokio::{
 use clap::Parser;
 use legbone::{
     config,
-    network::connection::Connection,
+    network::{
+        connection::{recorder::RecordDirection, Connection},
+        dissector, docs, proxy, replay,
+    },
     world::{World, WorldOptions},
-    Opts,
+    Command, Opts, Protocol, Side,
 };
 use std::{
     net::SocketAddr,
     sync::Arc,
-    path::Path
+    path::{Path, PathBuf}
 };
 use tokio_stream::{
     StreamExt,
@@ -38,6 +41,23 @@ use log4rs::{
 #[tokio::main]
 async fn main() -> Result<()> {
     let opts: Opts = Opts::parse();
+
+    if let Some(Command::Decode { recording, protocol, from, login, hex }) = opts.command {
+        return decode(recording, protocol, from, login, hex).await;
+    }
+    if let Some(Command::Diff { from, to }) = opts.command {
+        print!("{}", docs::protocol_diff(from.try_into()?, to.try_into()?));
+        return Ok(());
+    }
+    if let Some(Command::Docs { output }) = opts.command {
+        let documentation = docs::protocol_documentation();
+        match output {
+            Some(output) => std::fs::write(output, documentation)?,
+            None => print!("{documentation}"),
+        }
+        return Ok(());
+    }
+
     config::init(Path::new("server.toml"))?;
     let config = config::CONFIG.get().unwrap();
 
@@ -90,14 +110,38 @@ async fn main() -> Result<()> {
         panic!("Error initializing map: {err:?}");
     }
 
-    let world = World::new();
-    let world_options = WorldOptions {
-        day_night_cycle_enabled: config.world.day_night_cycle,
-    };
-
-    let handle = task::spawn(game_loop(world, socket_addr, world_options));
+    let world = World::new(config.connection.inbound_queue_size);
+
+    match opts.command {
+        Some(Command::Replay { file }) => {
+            // Light changes depend on time and would show up as differences
+            let world_options = WorldOptions {
+                day_night_cycle_enabled: false,
+                slow_client_policy: config.connection.slow_client_policy,
+            };
+            let sender = world.read().await.sender();
+            World::init_loop(&world, world_options);
+
+            replay::replay(&file, sender).await?;
+        }
+        Some(Command::Proxy { upstream }) => {
+            proxy::proxy(TcpListener::bind(socket_addr).await?, upstream).await?;
+        }
+        Some(Command::Decode { .. } | Command::Diff { .. } | Command::Docs { .. }) => {
+            unreachable!("decode, diff and docs do not start the server")
+        }
+        None => {
+            let world_options = WorldOptions {
+                day_night_cycle_enabled: config.world.day_night_cycle,
+                slow_client_policy: config.connection.slow_client_policy,
+            };
+
+            let handle = task::spawn(game_loop(world, socket_addr, world_options));
+
+            handle.await.expect("game loop task join")?;
+        }
+    }
 
-    handle.await.expect("game loop task join")?;
     Ok(())
 }
 
@@ -121,20 +165,48 @@ async fn game_loop(
 
         let _handle = task::spawn(async {
             log::info!("New connection: {}", stream.peer_addr().unwrap());
-            match Connection::handle_login(stream, sender_clone).await {
-                Ok(connection) => {
-                    if let Some(mut connection) = connection {
-                        if let Err(err) = connection.handle_connection().await {
-                            if let Err(err) = connection.send_error(err).await {
-                                log::error!("Error sending error to client: {err}");
-                            }
-                        }
-                    }
-                }
-                Err(err) => log::error!("Error on client login: {err}"),
-            }
+            Connection::run(stream, sender_clone).await;
         });
     }
 
     Ok(())
 }
+
+async fn decode(
+    recording: Option<PathBuf>,
+    protocol: Option<u16>,
+    from: Side,
+    login: bool,
+    hex: Option<String>,
+) -> Result<()> {
+    let messages = match (recording, hex) {
+        (Some(recording), _) => dissector::decode_recording(&recording).await?,
+        (None, Some(hex)) => {
+            let protocol: Protocol = protocol
+                .ok_or_else(|| anyhow!("A protocol is required to decode a hex dump"))?
+                .try_into()?;
+            let direction = match from {
+                Side::Client => RecordDirection::ClientToServer,
+                Side::Server => RecordDirection::ServerToClient,
+            };
+            let dump = if Path::new(&hex).is_file() {
+                std::fs::read_to_string(&hex)?
+            } else {
+                hex
+            };
+
+            dissector::decode_hex_dump(&dump, protocol, direction, login)
+                .await?
+                .into_iter()
+                .map(|message| (direction, message))
+                .collect()
+        }
+        (None, None) => return Err(anyhow!("Nothing to decode, use --recording or a hex dump")),
+    };
+
+    for (direction, message) in messages {
+        println!("[{direction:?}] {message}");
+    }
+
+    Ok(())
+}
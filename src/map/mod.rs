@@ -9,6 +9,7 @@ use position::Position;
 use serde_derive::Deserialize;
 use std::collections::BTreeMap;
 
+mod pathfinding;
 pub mod position;
 
 const MAP_WIDTH: u16 = 100;
@@ -17,6 +18,14 @@ const MAP_HEIGHT: u16 = 100;
 const MAP_LAYERS: u8 = 16;
 const RESPAWN_LOCATION: Position = Position::new(50, 50, 7);
 
+/// Items that can't be walked over. There is no item database yet, so these are only the
+/// blocking items placed by the maps
+const BLOCKING_ITEMS: &[u16] = &[
+    0x000e, 0x5a0e, 0x5c0e, 0x5e0e, 0x5f0e, // water
+    0xac0a, 0xb00a, // stones
+    0x00a0, 0x00a3, 0x01a3, // trees
+];
+
 pub static MAP: OnceLock<Map> = OnceLock::new();
 
 #[derive(Deserialize, Debug)]
@@ -108,7 +117,7 @@ impl Map {
         }
     }
 
-    fn checkerboard_pattern(
+    pub(crate) fn checkerboard_pattern(
         width: u16,
         height: u16,
         offset_x: u16,
@@ -380,12 +389,15 @@ impl Map {
         self.tiles.get_mut(&position).unwrap()
     }
 
-    pub fn get_tile_objects(&self, position: Position) -> Option<&[TileObject]> {
-        if position.x >= self.metadata.offset_x
+    fn contains(&self, position: Position) -> bool {
+        position.x >= self.metadata.offset_x
             && position.x < self.metadata.offset_x + self.metadata.width
             && position.y >= self.metadata.offset_y
             && position.y < self.metadata.offset_y + self.metadata.height
-        {
+    }
+
+    pub fn get_tile_objects(&self, position: Position) -> Option<&[TileObject]> {
+        if self.contains(position) {
             self.tiles.get(&position).map(|t| t.0.as_slice())
         } else if position.z == 7 {
             Some(&[TileObject::Other(0x000e)]) //water
@@ -395,6 +407,29 @@ impl Map {
     }
 }
 
+impl Map {
+    /// Whether a creature can step on the position: inside the map, on ground and with nothing
+    /// blocking the way. Players are not part of the map, the world checks them separately
+    pub fn is_walkable(&self, position: Position) -> bool {
+        self.contains(position)
+            && self.tiles.get(&position).is_some_and(|tile| {
+                !tile.0.is_empty() && !tile.0.iter().any(TileObject::is_blocking)
+            })
+    }
+}
+
+impl TileObject {
+    fn is_blocking(&self) -> bool {
+        match self {
+            Self::Other(id)
+            | Self::FluidContainer(id, _)
+            | Self::LightSource(id, _)
+            | Self::Stackable(id, _) => BLOCKING_ITEMS.contains(id),
+            Self::Creature(..) => true,
+        }
+    }
+}
+
 impl MapMetadata {
     const fn new(
         width: u16,
@@ -432,3 +467,19 @@ impl Tile {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_walkable() {
+        let map = Map::rookgaard_temple(MAP_WIDTH, MAP_HEIGHT, 0, 0, RESPAWN_LOCATION);
+
+        assert!(map.is_walkable(RESPAWN_LOCATION));
+        assert!(!map.is_walkable(Position::new(44, 54, 7)), "water");
+        assert!(!map.is_walkable(Position::new(55, 46, 7)), "tree");
+        assert!(!map.is_walkable(Position::new(50, 50, 6)), "no ground");
+        assert!(!map.is_walkable(Position::new(MAP_WIDTH, 50, 7)), "outside the map");
+    }
+}
@@ -0,0 +1,108 @@
+use super::{position::Position, Map};
+use crate::character::Direction;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap},
+};
+
+const DIRECTIONS: [Direction; 4] = [Direction::North, Direction::East, Direction::South, Direction::West];
+
+/// Tiles looked at before giving up, many times what the client shows
+const MAX_VISITED_TILES: usize = 4096;
+
+impl Map {
+    /// Shortest sequence of steps between the positions over walkable tiles that `is_free` also
+    /// accepts, found with A*. `None` when there is no such path or it is too far away
+    pub fn find_path(
+        &self,
+        from: Position,
+        to: Position,
+        is_free: impl Fn(Position) -> bool,
+    ) -> Option<Vec<Direction>> {
+        if from.z != to.z {
+            return None;
+        }
+        let distance = |position: Position| {
+            position.x.abs_diff(to.x) as u32 + position.y.abs_diff(to.y) as u32
+        };
+
+        let mut open = BinaryHeap::from([Reverse((distance(from), 0_u32, from))]);
+        let mut steps_to = BTreeMap::from([(from, 0_u32)]);
+        let mut came_from: BTreeMap<Position, (Position, Direction)> = BTreeMap::new();
+        let mut visited = 0;
+        while let Some(Reverse((_, steps, position))) = open.pop() {
+            if position == to {
+                return Some(Self::steps_to(&came_from, from, to));
+            }
+            if steps > steps_to[&position] {
+                continue;
+            }
+            visited += 1;
+            if visited > MAX_VISITED_TILES {
+                return None;
+            }
+
+            for direction in DIRECTIONS {
+                let next = position + direction;
+                if !self.is_walkable(next) || !is_free(next) {
+                    continue;
+                }
+                if steps_to.get(&next).is_none_or(|&known| steps + 1 < known) {
+                    steps_to.insert(next, steps + 1);
+                    came_from.insert(next, (position, direction));
+                    open.push(Reverse((steps + 1 + distance(next), steps + 1, next)));
+                }
+            }
+        }
+
+        None
+    }
+
+    fn steps_to(
+        came_from: &BTreeMap<Position, (Position, Direction)>,
+        from: Position,
+        to: Position,
+    ) -> Vec<Direction> {
+        let mut steps = vec![];
+        let mut position = to;
+        while position != from {
+            let (previous, direction) = came_from[&position];
+            steps.push(direction);
+            position = previous;
+        }
+        steps.reverse();
+        steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_path() {
+        let map = Map::checkerboard_pattern(100, 100, 0, 0, Position::new(50, 50, 7));
+        let from = Position::new(50, 50, 7);
+
+        assert_eq!(map.find_path(from, from, |_| true), Some(vec![]));
+        assert_eq!(
+            map.find_path(from, Position::new(52, 49, 7), |_| true).map(|steps| steps.len()),
+            Some(3)
+        );
+
+        // Around a wall from (51,48) to (51,52)
+        let path = map
+            .find_path(from, Position::new(52, 50, 7), |position| {
+                position.x != 51 || !(48..=52).contains(&position.y)
+            })
+            .unwrap();
+        assert_eq!(path.len(), 8);
+        assert_eq!(
+            path.iter().fold(from, |position, &direction| position + direction),
+            Position::new(52, 50, 7)
+        );
+
+        assert_eq!(map.find_path(from, Position::new(100, 50, 7), |_| true), None);
+        assert_eq!(map.find_path(from, Position::new(50, 50, 6), |_| true), None);
+    }
+}
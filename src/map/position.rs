@@ -1,15 +1,21 @@
 use crate::{
     character::{player::InventorySlot, Direction},
+    protocol::PositionFormat,
     Protocol,
 };
 use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
 use std::{
     convert::TryInto,
     fmt::Display,
     ops::{Add, Sub},
 };
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+/// Tiles shown by the client, around the player on the 9th column and 7th row
+pub const VIEWPORT_WIDTH: u16 = 18;
+pub const VIEWPORT_HEIGHT: u16 = 14;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 pub struct Position {
     pub(crate) x: u16,
     pub(crate) y: u16,
@@ -34,8 +40,18 @@ impl Position {
         Self { x, y, z }
     }
 
+    /// Whether a player standing on `center` sees the position, on the same floor only
+    pub fn is_in_viewport(&self, center: Position) -> bool {
+        let left = center.x as i32 - (VIEWPORT_WIDTH as i32 - 1) / 2;
+        let top = center.y as i32 - (VIEWPORT_HEIGHT as i32 - 1) / 2;
+        self.z == center.z
+            && (left..left + VIEWPORT_WIDTH as i32).contains(&(self.x as i32))
+            && (top..top + VIEWPORT_HEIGHT as i32).contains(&(self.y as i32))
+    }
+
     pub fn get_qualifier(&self, protocol: Protocol) -> Result<PositionQualifier> {
-        if protocol == Protocol::Tibia103 && self.x == 0xff {
+        let position_format = protocol.capabilities().position;
+        if position_format == PositionFormat::Short && self.x == 0xff {
             if self.y > 0 && self.y <= 8 {
                 Ok(PositionQualifier::Inventory((self.y as u8).try_into()?))
             } else {
@@ -43,7 +59,7 @@ impl Position {
                 Ok(PositionQualifier::None)
                 // Ok(PositionQualifier::Container(self.y, self.z))
             }
-        } else if protocol != Protocol::Tibia103 && self.x == 0xffff {
+        } else if position_format == PositionFormat::Long && self.x == 0xffff {
             if self.y & 0x40 == 0 {
                 Ok(PositionQualifier::Inventory((self.y as u8).try_into()?))
             } else {
@@ -60,9 +76,9 @@ impl Add<(i16, i16, i8)> for Position {
 
     fn add(self, rhs: (i16, i16, i8)) -> Self::Output {
         Self {
-            x: (self.x as i16 + rhs.0) as u16,
-            y: (self.y as i16 + rhs.1) as u16,
-            z: (self.z as i8 + rhs.2) as u8,
+            x: (self.x as i16).wrapping_add(rhs.0) as u16,
+            y: (self.y as i16).wrapping_add(rhs.1) as u16,
+            z: (self.z as i8).wrapping_add(rhs.2) as u8,
         }
     }
 }
@@ -86,9 +102,9 @@ impl Sub<(i16, i16, i8)> for Position {
 
     fn sub(self, rhs: (i16, i16, i8)) -> Self::Output {
         Self {
-            x: (self.x as i16 - rhs.0) as u16,
-            y: (self.y as i16 - rhs.1) as u16,
-            z: (self.z as i8 - rhs.2) as u8,
+            x: (self.x as i16).wrapping_sub(rhs.0) as u16,
+            y: (self.y as i16).wrapping_sub(rhs.1) as u16,
+            z: (self.z as i8).wrapping_sub(rhs.2) as u8,
         }
     }
 }
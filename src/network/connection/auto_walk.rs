@@ -0,0 +1,93 @@
+//! Old clients send the position clicked on the map and leave the walking to the server. The
+//! world finds the path, then the connection walks it one step at a time through the regular
+//! walk, so every step is checked like a manual one. A manual walk or a blocked step cancel the
+//! rest of the path, as does logging out, which drops the connection.
+use super::{transport::Transport, Connection};
+use crate::{
+    character::Direction,
+    map::position::Position,
+    network::message::server::ServerMessage,
+    world::message::PlayerToWorldMessage,
+};
+use anyhow::Result;
+use std::{collections::VecDeque, time::Duration};
+use tokio::time::Instant;
+
+/// Time between two steps of an auto walk
+const STEP_INTERVAL: Duration = Duration::from_millis(400);
+
+pub enum AutoWalk {
+    /// Waiting for the world to find the path
+    Requested,
+    Walking {
+        steps: VecDeque<Direction>,
+        next_step: Instant,
+    },
+}
+
+impl AutoWalk {
+    /// When the next step is walked, `None` until the path is known
+    pub fn next_step(&self) -> Option<Instant> {
+        match self {
+            Self::Requested => None,
+            Self::Walking { next_step, .. } => Some(*next_step),
+        }
+    }
+}
+
+impl<T: Transport> Connection<T> {
+    pub(super) async fn receive_auto_walk(&mut self, position: Position) -> Result<()> {
+        log::trace!("Auto walk to {position:?}");
+
+        self.auto_walk = Some(AutoWalk::Requested);
+        self.sender.send(PlayerToWorldMessage::AutoWalk(self.player.id, position)).await?;
+
+        Ok(())
+    }
+
+    /// Starts walking the path, unless the player walked manually since asking for it
+    pub(super) async fn auto_walk_path(&mut self, steps: Vec<Direction>) -> Result<()> {
+        if matches!(self.auto_walk, Some(AutoWalk::Requested)) {
+            log::trace!("Auto walk path: {steps:?}");
+            self.auto_walk = (!steps.is_empty()).then(|| AutoWalk::Walking {
+                steps: steps.into(),
+                next_step: Instant::now(),
+            });
+        }
+
+        Ok(())
+    }
+
+    pub(super) async fn auto_walk_unreachable(&mut self) -> Result<()> {
+        if matches!(self.auto_walk.take(), Some(AutoWalk::Requested)) {
+            self.queue_message(ServerMessage::StatusMessage("There is no way.".to_string()))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Asks the world for the next step of the path
+    pub(super) async fn advance_auto_walk(&mut self) -> Result<()> {
+        let Some(AutoWalk::Walking { steps, next_step }) = self.auto_walk.as_mut() else {
+            return Ok(());
+        };
+
+        if let Some(direction) = steps.pop_front() {
+            self.sender.send(PlayerToWorldMessage::Walk(self.player.id, direction)).await?;
+        }
+        if steps.is_empty() {
+            self.auto_walk = None;
+        } else {
+            *next_step = Instant::now() + STEP_INTERVAL;
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn cancel_auto_walk(&mut self, reason: &str) {
+        if self.auto_walk.take().is_some() {
+            log::trace!("Auto walk cancelled, {reason}");
+        }
+    }
+}
@@ -1,23 +1,20 @@
-use super::Connection;
+use super::{transport::Transport, Connection};
 use crate::{
     character::{player::InventorySlot, CharacterUpdateType, OutfitColors},
-    chat::ChatType,
-    constants::MagicEffect,
-    io::WriteExt,
-    network::header::HeaderSend,
-    Protocol,
+    chat::encoding,
+    io::hex,
+    network::{header::HeaderSend, message::server::ServerMessage},
 };
 use anyhow::{
     Result,
     anyhow
 };
 use std::{
-    io::Cursor,
     convert::TryInto,
     sync::atomic::{AtomicU16, AtomicU8, Ordering},
 };
 
-impl Connection {
+impl<T: Transport> Connection<T> {
     pub async fn send_debug_command(&mut self, command: &str) -> Result<()> {
         let mut args = command.split_ascii_whitespace();
         if let Some(command) = args.next() {
@@ -27,168 +24,133 @@ impl Connection {
         Ok(())
     }
 
-    #[allow(clippy::unit_arg)]
     async fn debug_command(&mut self, command: &str, args: Vec<&str>) -> Result<()> {
         log::debug!("Received debug command {command:?}");
         match command {
             "chars" => self.command_chars().await,
-            "char" => self.command_char(args[0]).await,
+            "char" => self.command_char(arg(&args, 0)?).await,
             "echo" => self.command_echo().await,
-            "item" => self.command_item(args[0], args[1]).await,
-            "i" => self.command_item_right_hand(args[0]).await,
-            "stats" => Ok(self.queue_message(self.prepare_stats().await?).await),
-            "skills" => Ok(self.queue_message(self.prepare_skills().await?).await),
-            "me" => self.command_magic_effect(args[0]).await,
-            "wlight" => Ok(self
-                .queue_message(self.prepare_world_light(args[0].parse::<u8>()?).await?)
-                .await),
-            "plight" => Ok(self
-                .queue_message(
-                    self.prepare_update_character(
-                        self.player.id,
-                        CharacterUpdateType::LightLevel,
-                        args[0].parse::<u8>()?,
-                    )
-                    .await?,
-                )
-                .await),
-            "userlist" => Ok(self.queue_message(self.prepare_user_list().await?).await),
-            "userinfo" => Ok(self
-                .queue_message(self.prepare_user_info(args[0]).await?)
-                .await),
-            "info" => Ok(self
-                .queue_message(self.prepare_info(&args.join(" ")).await?)
-                .await),
+            "item" => self.command_item(arg(&args, 0)?, arg(&args, 1)?).await,
+            "i" => self.command_item_right_hand(arg(&args, 0)?).await,
+            "stats" => self.queue_message(ServerMessage::Stats(self.player.stats)).await,
+            "skills" => self.queue_message(ServerMessage::Skills(self.player.skills)).await,
+            "me" => self.command_magic_effect(arg(&args, 0)?).await,
+            "wlight" => {
+                self.queue_message(ServerMessage::WorldLight(arg(&args, 0)?.parse::<u8>()?))
+                    .await
+            }
+            "plight" => {
+                self.queue_message(ServerMessage::UpdateCharacter {
+                    id: self.player.id,
+                    update_type: CharacterUpdateType::LightLevel,
+                    value: arg(&args, 0)?.parse::<u8>()?,
+                })
+                .await
+            }
+            "userlist" => {
+                self.queue_message(ServerMessage::UserList {
+                    names: vec![self.player.name.clone()],
+                })
+                .await
+            }
+            "userinfo" => {
+                self.queue_message(ServerMessage::UserInfo {
+                    name: arg(&args, 0)?.to_string(),
+                })
+                .await
+            }
+            "info" => self.queue_message(ServerMessage::Info(args.join(" "))).await,
             "error" => self.send_error(anyhow!(args.join(" "))).await,
-            "error2" => Ok(self
-                .queue_message(self.prepare_error(&args.join(" ")).await?)
-                .await),
+            "error2" => self.queue_message(ServerMessage::Error(args.join(" "))).await,
             "motd" => self.command_motd(args).await,
-            "status" => Ok(self
-                .queue_message(self.prepare_status_message(&args.join(" ")).await?)
-                .await),
+            "status" => {
+                self.queue_message(ServerMessage::StatusMessage(args.join(" ")))
+                    .await
+            }
             "panic" => panic!("{}", args.join(" ")),
             "chat" => self.command_chat().await,
-            "outfit" => self.command_outfit(args[0]).await,
-            "cd" => self.command_change_direction(args[0]).await,
+            "outfit" => self.command_outfit(arg(&args, 0)?).await,
+            "cd" => self.command_change_direction(arg(&args, 0)?).await,
             "gc" => self.command_green_chat(args).await,
-            "u0" => Ok(self
-                .queue_message(self.prepare_unknown_0x0000().await?)
-                .await),
-            "uf" => Ok(self
-                .queue_message(self.prepare_unknown_0x000f().await?)
-                .await),
-            "u33" => Ok(self
-                .queue_message(self.prepare_unknown_0x0033().await?)
-                .await),
-            "u34" => Ok(self
-                .queue_message(self.prepare_unknown_0x0034().await?)
-                .await),
-            _ => Ok(self
-                .queue_message(
-                    self.prepare_magic_effect(MagicEffect::Puff, self.player.position)
-                        .await?,
-                )
-                .await),
+            "u0" => self.queue_message(ServerMessage::Empty(HeaderSend::Unknown0x0000)).await,
+            "uf" => self.queue_message(ServerMessage::Empty(HeaderSend::Unknown0x000f)).await,
+            "u33" => self.queue_message(ServerMessage::Empty(HeaderSend::Unknown0x0033)).await,
+            "u34" => self.queue_message(ServerMessage::Empty(HeaderSend::Unknown0x0034)).await,
+            "raw" => self.command_raw(None, &args).await,
+            "hdr" => self.command_raw(Some(arg(&args, 0)?), &args[1..]).await,
+            "probe" => self.command_probe(arg(&args, 0)?).await,
+            _ => Err(anyhow!("Unknown debug command {command:?}")),
         }
     }
 
     async fn command_chars(&self) -> Result<()> {
         let chars: Vec<u8> = (0x20_u8..=0x7f).collect();
-        let chat_msg = unsafe { std::str::from_utf8_unchecked(&chars) };
-
-        let message = if self.protocol < Protocol::Tibia501 {
-            self.prepare_chat(
-                ChatType::Normal,
-                chat_msg,
-                Some(&self.player),
-                Some(self.player.position),
-            )
-            .await?
-        } else {
-            self.prepare_chat(
-                ChatType::RedConsoleWhiteScreen,
-                chat_msg,
-                None,
-                Some(self.player.position),
-            )
-            .await?
-        };
-
-        self.queue_message(message).await;
-        Ok(())
+        let chat_msg = &encoding::from_game_bytes(&chars);
+
+        let message = self.server_chat_message(chat_msg);
+
+        self.queue_message(message).await
     }
 
     async fn command_char(&self, arg: &str) -> Result<()> {
         let character = u8::from_str_radix(arg, 16)?;
         let chat_msg = format!("0x{:02x?}={}", character, character as char);
 
-        let message = if self.protocol < Protocol::Tibia501 {
-            self.prepare_chat(
-                ChatType::Normal,
-                &chat_msg,
-                Some(&self.player),
-                Some(self.player.position),
-            )
-            .await?
-        } else {
-            self.prepare_chat(
-                ChatType::RedConsoleWhiteScreen,
-                &chat_msg,
-                None,
-                Some(self.player.position),
-            )
-            .await?
-        };
-
-        self.queue_message(message).await;
-        Ok(())
+        let message = self.server_chat_message(&chat_msg);
+
+        self.queue_message(message).await
     }
 
     async fn command_echo(&self) -> Result<()> {
-        let mut buf = Cursor::new(vec![]);
-        buf.write_header(HeaderSend::Echo, self.protocol).await?;
-        self.queue_message(buf.into_inner()).await;
-        Ok(())
+        self.queue_message(ServerMessage::Empty(HeaderSend::Echo)).await
     }
 
     async fn command_item(&self, slot: &str, item: &str) -> Result<()> {
         let slot: InventorySlot = slot.parse::<u8>()?.try_into()?;
         let item = u16::from_str_radix(item, 16)?;
         log::trace!("Giving item 0x{item:04x?} on slot {slot:?}");
-        self.queue_message(self.prepare_equipped_item(slot, item, 0).await?)
-            .await;
-        Ok(())
+        self.queue_message(ServerMessage::EquippedItem {
+            slot,
+            item,
+            stack: 0,
+        })
+        .await
     }
 
     async fn command_item_right_hand(&self, item: &str) -> Result<()> {
         let slot = InventorySlot::RightHand;
         let item = u16::from_str_radix(item, 16)?;
         log::trace!("Giving item 0x{item:04x?} on slot {slot:?}");
-        self.queue_message(self.prepare_equipped_item(slot, item, 0).await?)
-            .await;
-        Ok(())
+        self.queue_message(ServerMessage::EquippedItem {
+            slot,
+            item,
+            stack: 0,
+        })
+        .await
     }
 
     async fn command_magic_effect(&self, effect: &str) -> Result<()> {
-        let effect = effect.parse::<u8>()?.try_into()?;
-        self.queue_message(
-            self.prepare_magic_effect(effect, self.player.position)
-                .await?,
-        )
-        .await;
-        Ok(())
+        let effect = effect.parse::<u8>()?;
+        let max_magic_effect = self.protocol.capabilities().max_magic_effect;
+        if effect > max_magic_effect {
+            return Err(anyhow!("Magic effect {effect} is above {max_magic_effect} for {:?}", self.protocol));
+        }
+        let effect = effect.try_into()?;
+        self.queue_message(ServerMessage::MagicEffect {
+            effect,
+            position: self.player.position,
+        })
+        .await
     }
 
     async fn command_motd(&self, args: Vec<&str>) -> Result<()> {
         static NEXT_MOTD: AtomicU16 = AtomicU16::new(0x0102);
         let message_number = NEXT_MOTD.fetch_add(1, Ordering::SeqCst);
-        self.queue_message(
-            self.prepare_message_of_the_day(message_number, &args.join(" "))
-                .await?,
-        )
-        .await;
-        Ok(())
+        self.queue_message(ServerMessage::MessageOfTheDay {
+            number: message_number,
+            message: args.join(" "),
+        })
+        .await
     }
 
     async fn command_chat(&self) -> Result<()> {
@@ -198,55 +160,59 @@ impl Connection {
         let msg = &format!("chat_type=0x{chat_type:02x?}");
         log::trace!("{msg}");
 
-        self.queue_message(
-            self.prepare_chat(
-                chat_type,
-                msg,
-                Some(&self.player),
-                Some(self.player.position),
-            )
-            .await?,
-        )
-        .await;
-        Ok(())
+        self.queue_message(self.chat_message(
+            chat_type,
+            msg,
+            Some(&self.player),
+            Some(self.player.position),
+        ))
+        .await
     }
 
     async fn command_outfit(&self, outfit: &str) -> Result<()> {
-        let outfit = outfit.parse::<u8>()?.try_into()?;
+        let outfit = outfit.parse::<u8>()?;
+        let max_outfit_type = self.protocol.capabilities().max_outfit_type;
+        if outfit > max_outfit_type {
+            return Err(anyhow!("Outfit {outfit} is above {max_outfit_type} for {:?}", self.protocol));
+        }
+        let outfit = outfit.try_into()?;
         let outfit_colors = OutfitColors::new(0, 0, 0, 0);
-        self.queue_message(
-            self.prepare_update_outfit(self.player_id, outfit, outfit_colors)
-                .await?,
-        )
-        .await;
-        Ok(())
+        self.queue_message(ServerMessage::UpdateOutfit {
+            id: self.player_id,
+            outfit_type: outfit,
+            colors: outfit_colors,
+        })
+        .await
     }
 
     async fn command_change_direction(&self, direction: &str) -> Result<()> {
         let direction = direction.parse::<u8>()?.try_into()?;
 
-        let mut msg = self
-            .prepare_update_object(
-                self.player.position,
-                crate::constants::ObjectUpdateType::Update,
-                1,
-            )
-            .await?;
-        msg.extend(
-            self.prepare_change_direction(self.player.id, direction)
-                .await?,
-        );
-        self.queue_message(msg).await;
-        Ok(())
+        self.queue_message(self.character_turned(self.player.id, self.player.position, direction))
+            .await
+    }
+
+    /// Sends the hex bytes as a message, after the header of the given hex code if any. Bytes
+    /// may be split across arguments, e.g. `\d hdr 33 0100 02`. Any code is sent, known to the
+    /// client or not
+    async fn command_raw(&self, header: Option<&str>, payload: &[&str]) -> Result<()> {
+        let header = header
+            .map(|code| u16::from_str_radix(code.trim_start_matches("0x"), 16))
+            .transpose()?;
+        let payload = hex::decode(&payload.concat())?;
+        log::trace!("Sending raw message: header={header:02x?}, payload={payload:02x?}");
+
+        self.queue_message(ServerMessage::Raw { header, payload }).await
     }
 
     async fn command_green_chat(&self, args: Vec<&str>) -> Result<()> {
-        let chat_msg = args.join(" ");
-        self.queue_message(
-            self.prepare_green_chat(ChatType::Normal, &chat_msg, None, None)
-                .await?,
-        )
-        .await;
-        Ok(())
+        self.queue_message(ServerMessage::GreenChat(args.join(" "))).await
     }
 }
+
+/// Argument of a debug command, which the client may have left out
+fn arg<'a>(args: &[&'a str], index: usize) -> Result<&'a str> {
+    args.get(index)
+        .copied()
+        .ok_or_else(|| anyhow!("Missing argument {index}"))
+}
@@ -0,0 +1,111 @@
+//! Bytes the server does not understand yet are appended to a single JSON lines file shared by
+//! every session, so they can be aggregated to work out their meaning:
+//!
+//! `{"timestamp":1697040000123,"protocol":650,"context":"unknown header 0x00c8","offset":0,"bytes":"c800..."}`
+//!
+//! * timestamp: milliseconds since the unix epoch
+//! * protocol: negotiated protocol version, as the number sent by the client (e.g. 650)
+//! * context: message the bytes were found in, and where
+//! * offset: position of the bytes inside the frame, frame length excluded
+//! * bytes: the unexplained bytes as lowercase hex. For unknown headers, the whole frame
+use super::recorder::timestamp;
+use crate::{io::hex, Protocol};
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Discovery {
+    pub timestamp: u64,
+    pub protocol: u16,
+    pub context: String,
+    pub offset: usize,
+    pub bytes: String,
+}
+
+/// Discoveries file, opened on the first discovery. `None` when disabled in the configuration
+static FILE: OnceLock<Option<Mutex<File>>> = OnceLock::new();
+
+/// Appends the bytes to the discoveries file, if enabled. Failing to write the file does not
+/// affect the session, the error is only logged
+pub fn record(protocol: Protocol, context: &str, offset: usize, bytes: &[u8]) {
+    log::trace!("Unexplained bytes in {context} at offset {offset}: {bytes:02x?}");
+
+    let Some(file) = FILE.get_or_init(open_from_config) else {
+        return;
+    };
+    let discovery = Discovery {
+        timestamp: timestamp(),
+        protocol: protocol as u16,
+        context: context.to_string(),
+        offset,
+        bytes: hex::encode(bytes),
+    };
+    if let Err(err) = write(&mut file.lock().unwrap(), &discovery) {
+        log::warn!("Error writing discovery: {err}");
+    }
+}
+
+fn open_from_config() -> Option<Mutex<File>> {
+    let discoveries = crate::config::CONFIG.get()?.discoveries.as_ref()?;
+    if !discoveries.enabled {
+        return None;
+    }
+
+    let path = Path::new(&discoveries.file);
+    if let Some(directory) = path.parent().filter(|directory| !directory.as_os_str().is_empty()) {
+        if let Err(err) = std::fs::create_dir_all(directory) {
+            log::warn!("Error creating directory {directory:?}: {err}");
+        }
+    }
+    match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => {
+            log::info!("Recording discoveries to {path:?}");
+            Some(Mutex::new(file))
+        }
+        Err(err) => {
+            log::warn!("Error opening discoveries file {path:?}: {err}");
+            None
+        }
+    }
+}
+
+/// Writes the whole line at once, so entries of concurrent sessions don't interleave
+fn write(file: &mut File, discovery: &Discovery) -> Result<()> {
+    let mut line = serde_json::to_vec(discovery)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::connection::recorder::read_json_lines;
+
+    #[test]
+    fn test_write_read_discoveries() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("legbone-discoveries-{}.jsonl", std::process::id()));
+        let discovery = Discovery {
+            timestamp: 1697040000123,
+            protocol: 650,
+            context: "unknown header 0x00c8".to_string(),
+            offset: 0,
+            bytes: "c80001".to_string(),
+        };
+
+        let mut file = OpenOptions::new().create(true).write(true).truncate(true).open(&path)?;
+        write(&mut file, &discovery)?;
+        write(&mut file, &discovery)?;
+        let entries: Result<Vec<Discovery>> = read_json_lines(&path);
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(entries?, vec![discovery.clone(), discovery]);
+        Ok(())
+    }
+}
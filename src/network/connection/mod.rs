@@ -1,74 +1,180 @@
+mod auto_walk;
 mod debug;
+pub mod discoveries;
+mod probe;
 mod receive;
+pub mod recorder;
 mod send;
+pub mod transport;
+
+pub use send::prepare_character_list;
 
 use crate::{
-    character::player::Player,
+    character::{player::Player, Direction},
+    config::{self, SlowClientPolicy},
     io::ReadExt,
+    network::message::server::ServerMessage,
     persistence,
-    world::message::{PlayerToWorldMessage, WorldToPlayerMessage},
+    protocol::{LoginFlow, MessageForms, PlayerDataLayout},
+    world::message::{CharacterView, PlayerToWorldMessage, WorldToPlayerMessage},
     Protocol,
 };
 use anyhow::{anyhow, Result};
 use crossbeam_queue::SegQueue;
+use recorder::{RecordDirection, SessionRecorder};
+use transport::Transport;
 use tokio::{
     net::TcpStream,
+    task::{self, JoinHandle},
     time::timeout,
-    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    sync::mpsc::{channel, Receiver, Sender},
     io::{
+        AsyncRead,
         AsyncReadExt,
-        AsyncWriteExt
+        AsyncWrite,
+        AsyncWriteExt,
+        ReadHalf,
     }
 };
-use std::{convert::TryInto, time::Duration};
-
-pub struct Connection {
-    stream: TcpStream,
+use std::{convert::TryInto, io::Cursor, net::SocketAddr, time::Duration};
+
+/// The connection reads and handles client messages, while a separate writer task sends the
+/// frames queued on `outbound` to the client.
+pub struct Connection<T: Transport = TcpStream> {
+    stream: ReadHalf<T>,
+    outbound: Sender<Vec<u8>>,
+    writer: Option<JoinHandle<Result<()>>>,
+    peer_address: Option<SocketAddr>,
+    slow_client_policy: SlowClientPolicy,
     player: Player, //TODO remove
     player_id: u32,
     protocol: Protocol,
-    message_queue: SegQueue<Vec<u8>>,
-    sender: UnboundedSender<PlayerToWorldMessage>,
-    receiver: UnboundedReceiver<WorldToPlayerMessage>,
+    /// Messages waiting for the next flush, with their encoding
+    message_queue: SegQueue<(ServerMessage, Vec<u8>)>,
+    sender: Sender<PlayerToWorldMessage>,
+    receiver: Receiver<WorldToPlayerMessage>,
+    recorder: Option<SessionRecorder>,
+    /// Probe script running on the connection
+    probe: Option<probe::ProbeRun>,
+    auto_walk: Option<auto_walk::AutoWalk>,
 }
 
-impl Connection {
+impl<T: Transport> Connection<T> {
     fn new(
-        stream: TcpStream,
+        stream: T,
         protocol: Protocol,
         player: Player,
-        sender: UnboundedSender<PlayerToWorldMessage>,
-        receiver: UnboundedReceiver<WorldToPlayerMessage>,
+        sender: Sender<PlayerToWorldMessage>,
+        receiver: Receiver<WorldToPlayerMessage>,
+        recorder: Option<SessionRecorder>,
     ) -> Self {
+        let connection_config = connection_config();
+        let peer_address = stream.peer_addr().ok();
+        let (reader, writer) = tokio::io::split(stream);
+        let (outbound, outbound_receiver) = channel(connection_config.outbound_queue_size);
+
         let player_id = player.id;
         Self {
-            stream,
+            stream: reader,
+            outbound,
+            writer: Some(task::spawn(write_loop(writer, outbound_receiver))),
+            peer_address,
+            slow_client_policy: connection_config.slow_client_policy,
             player,
             player_id,
             protocol,
             message_queue: SegQueue::new(),
             sender,
             receiver,
+            recorder,
+            probe: None,
+            auto_walk: None,
+        }
+    }
+
+    /// Stops accepting new frames and waits until the queued ones are written
+    pub async fn close(&mut self) -> Result<()> {
+        if let Some(writer) = self.writer.take() {
+            // Replacing the sender closes the channel, which finishes the writer task
+            let (outbound, _) = channel(1);
+            self.outbound = outbound;
+            writer.await??;
+        }
+        Ok(())
+    }
+
+    /// Handles a client from login until it disconnects
+    pub async fn run(stream: T, sender: Sender<PlayerToWorldMessage>) {
+        match Self::handle_login(stream, sender).await {
+            Ok(connection) => {
+                if let Some(mut connection) = connection {
+                    if let Err(err) = connection.handle_connection().await {
+                        if let Err(err) = connection.send_error(err).await {
+                            log::error!("Error sending error to client: {err}");
+                        }
+                    }
+                    // Whether it logged out or not, the player leaves the world
+                    if let Err(err) = connection
+                        .sender
+                        .send(PlayerToWorldMessage::UnloadPlayer(connection.player_id))
+                        .await
+                    {
+                        log::debug!("Error unloading player: {err}");
+                    }
+                    if let Err(err) = connection.close().await {
+                        log::debug!("Error writing to client: {err}");
+                    }
+                }
+            }
+            Err(err) => log::error!("Error on client login: {err}"),
         }
     }
 
     pub async fn handle_login(
-        mut stream: TcpStream,
-        sender: UnboundedSender<PlayerToWorldMessage>,
-    ) -> Result<Option<Connection>> {
+        mut stream: T,
+        sender: Sender<PlayerToWorldMessage>,
+    ) -> Result<Option<Self>> {
         let length = stream.read_u16_le().await?;
         log::trace!("handle_login: length={length}");
 
+        let max_frame_size = connection_config().max_frame_size;
+        if !matches!(length, 67 | 221 | 223 | 723) && length > max_frame_size {
+            return Err(anyhow!(
+                "Login message of {length} bytes is above the limit of {max_frame_size} bytes"
+            ));
+        }
+
+        let mut frame = vec![0_u8; length as usize];
+        stream.read_exact(&mut frame).await?;
+        let mut message = Cursor::new(frame.as_slice());
+
+        let mut recorder = SessionRecorder::from_config(stream.peer_addr().ok())?;
+
         let (player, protocol) = match length {
-            67 => player_login(&mut stream).await?,
-            221 | 223 | 723 => create_new_player(&mut stream).await?,
-            _ => account_login(&mut stream, length).await?,
+            67 => player_login(&mut message).await?,
+            221 | 223 | 723 => create_new_player(&mut message).await?,
+            _ => account_login(&mut stream, &mut message, length, &mut recorder, &frame).await?,
         };
 
-        if let Some(player) = player {
-            let (game_sender, receiver) = unbounded_channel();
+        if let Some(mut player) = player {
+            let (game_sender, mut receiver) = channel(connection_config().world_queue_size);
 
-            sender.send(PlayerToWorldMessage::LoadPlayer(player.id, game_sender))?;
+            sender.send(PlayerToWorldMessage::LoadPlayer(
+                CharacterView {
+                    id: player.id,
+                    name: player.name.clone(),
+                    position: player.position,
+                    direction: Direction::South,
+                    outfit: player.outfit,
+                },
+                game_sender,
+            ))
+            .await?;
+            // The map is sent around the tile the world found free, not the one logged in on
+            player.position = match receiver.recv().await {
+                Some(WorldToPlayerMessage::Loaded(position)) => position,
+                message => return Err(anyhow!("Player was not loaded by the world: {message:?}")),
+            };
 
             log::info!(
                 "Player logged in: protocol={:?}, id={}, name={}, ",
@@ -76,7 +182,15 @@ impl Connection {
                 player.id,
                 player.name
             );
-            let mut client = Connection::new(stream, protocol, player, sender, receiver);
+            if let Some(recorder) = recorder.as_mut() {
+                recorder.record(
+                    RecordDirection::ClientToServer,
+                    protocol,
+                    &[&length.to_le_bytes()[..], &frame].concat(),
+                )?;
+            }
+
+            let mut client = Self::new(stream, protocol, player, sender, receiver, recorder);
             client.queue_login_info().await?;
             client.flush_message_queue().await?;
 
@@ -87,13 +201,18 @@ impl Connection {
     }
 }
 
-async fn player_login(stream: &mut TcpStream) -> Result<(Option<Player>, Protocol)> {
-    //TODO validate message using initial bytes
+async fn player_login<R: AsyncRead + Unpin>(stream: &mut R) -> Result<(Option<Player>, Protocol)> {
     //103+ = 00, 00, 01, 01, 00
     //650  = N/A
-    stream.skip(5).await?;
+    let mut prefix = [0_u8; 5];
+    stream.read_exact(&mut prefix).await?;
 
     let protocol: Protocol = stream.read_u16_le().await?.try_into()?;
+    if protocol.capabilities().login == LoginFlow::Account {
+        discoveries::record(protocol, "PlayerLogin prefix", 0, &prefix);
+    } else {
+        validate_prefix(&prefix, &[0x00, 0x00, 0x01, 0x01, 0x00], "player login")?;
+    }
 
     let mut name = String::new();
     stream.read_string(&mut name, 30).await?;
@@ -108,13 +227,16 @@ async fn player_login(stream: &mut TcpStream) -> Result<(Option<Player>, Protoco
     Ok((persistence::load_player_by_name(&name), protocol))
 }
 
-async fn create_new_player(stream: &mut TcpStream) -> Result<(Option<Player>, Protocol)> {
-    //TODO validate message using initial bytes
+async fn create_new_player(stream: &mut Cursor<&[u8]>) -> Result<(Option<Player>, Protocol)> {
     //103+ = 00, 00, 00, 01, 00
     //640+ = N/A
-    stream.skip(5).await?;
+    let mut prefix = [0_u8; 5];
+    stream.read_exact(&mut prefix).await?;
 
     let protocol: Protocol = stream.read_u16_le().await?.try_into()?;
+    if protocol.capabilities().login == LoginFlow::PlayerOrNewPlayer {
+        validate_prefix(&prefix, &[0x00, 0x00, 0x00, 0x01, 0x00], "new player")?;
+    }
 
     let mut name = String::new();
     stream.read_string(&mut name, 30).await?;
@@ -126,7 +248,9 @@ async fn create_new_player(stream: &mut TcpStream) -> Result<(Option<Player>, Pr
 
     //TODO find out what those bytes mean
     //103+ = 01, 01
-    stream.skip(2).await?;
+    let offset = stream.position() as usize;
+    let unknown = stream.skip(2).await?;
+    discoveries::record(protocol, "NewPlayer after gender", offset, &unknown);
 
     let outfit_colors = stream.read_outfit_colors().await?;
 
@@ -134,10 +258,9 @@ async fn create_new_player(stream: &mut TcpStream) -> Result<(Option<Player>, Pr
     stream.read_string(&mut real_name, 50).await?;
 
     let mut location = String::new();
-    let location_size = if protocol == Protocol::Tibia103 {
-        48
-    } else {
-        50
+    let location_size = match protocol.capabilities().forms {
+        MessageForms::Short => 48,
+        MessageForms::Full => 50,
     };
     stream.read_string(&mut location, location_size).await?;
 
@@ -145,7 +268,7 @@ async fn create_new_player(stream: &mut TcpStream) -> Result<(Option<Player>, Pr
     stream.read_string(&mut email, 50).await?;
 
     let mut comment = String::new();
-    if protocol >= Protocol::Tibia400 && protocol <= Protocol::Tibia501 {
+    if protocol.capabilities().player_data == PlayerDataLayout::AccountWithComment {
         stream.read_string(&mut comment, 500).await?;
     }
 
@@ -157,32 +280,61 @@ async fn create_new_player(stream: &mut TcpStream) -> Result<(Option<Player>, Pr
     Ok((Some(player), protocol))
 }
 
-async fn account_login(
-    stream: &mut TcpStream,
+async fn account_login<T: Transport, R: AsyncRead + Unpin>(
+    stream: &mut T,
+    message: &mut R,
     message_length: u16,
+    recorder: &mut Option<SessionRecorder>,
+    frame: &[u8],
 ) -> Result<(Option<Player>, Protocol)> {
     log::trace!("Account login attempt. length={message_length}");
 
-    //TODO validate message using initial bytes
     //640- = NA
     //650  = 01, 01, 00
-    stream.skip(3).await?;
+    let mut prefix = [0_u8; 3];
+    message.read_exact(&mut prefix).await?;
 
-    let protocol: Protocol = stream.read_u16_le().await?.try_into()?;
+    let protocol: Protocol = message.read_u16_le().await?.try_into()?;
 
-    if protocol >= Protocol::Tibia650 {
-        let account_number = stream.read_u32_le().await?;
-        let password_length = stream.read_u16_le().await?;
+    if protocol.capabilities().login == LoginFlow::Account {
+        validate_prefix(&prefix, &[0x01, 0x01, 0x00], "account login")?;
+
+        let account_number = message.read_u32_le().await?;
+        let password_length = message.read_u16_le().await?;
+        // Clients may send more after the password, only the declared length has to fit
+        if 11 + password_length as usize > message_length as usize {
+            return Err(anyhow!(
+                "Account login of {message_length} bytes has a password of {password_length} bytes"
+            ));
+        }
 
         let mut password = String::new();
-        stream.read_string(&mut password, password_length).await?;
+        message.read_string(&mut password, password_length).await?;
+
+        let offset = 11 + password_length as usize;
+        if let Some(unknown) = frame.get(offset..).filter(|unknown| !unknown.is_empty()) {
+            discoveries::record(protocol, "AccountLogin after password", offset, unknown);
+        }
 
         let local_addr = stream.local_addr()?;
         log::trace!("Journey Onward! Account number={account_number}, password={password}, protocol={protocol:?}");
 
         let msg = send::prepare_character_list(local_addr).await?;
-        stream.write_u16_le(msg.len() as u16).await?;
-        stream.write_all(&msg).await?;
+        let mut character_list = Cursor::new(vec![]);
+        character_list.write_u16_le(msg.len() as u16).await?;
+        character_list.write_all(&msg).await?;
+        let character_list = character_list.into_inner();
+
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.record(
+                RecordDirection::ClientToServer,
+                protocol,
+                &[&message_length.to_le_bytes()[..], frame].concat(),
+            )?;
+            recorder.record(RecordDirection::ServerToClient, protocol, &character_list)?;
+        }
+
+        stream.write_all(&character_list).await?;
         stream.flush().await?;
 
         //Awaits connection be terminated by client, which will connect again using the chosen character
@@ -215,11 +367,39 @@ async fn account_login(
     }
 }
 
-impl Drop for Connection {
+/// Checks the bytes that start login messages, which are only known for some protocols
+fn validate_prefix(prefix: &[u8], expected: &[u8], message: &str) -> Result<()> {
+    if prefix != expected {
+        return Err(anyhow!(
+            "Invalid {message} message, starts with {prefix:02x?} instead of {expected:02x?}"
+        ));
+    }
+    Ok(())
+}
+
+fn connection_config() -> &'static config::Connection {
+    static DEFAULT: std::sync::OnceLock<config::Connection> = std::sync::OnceLock::new();
+    config::CONFIG
+        .get()
+        .map(|config| &config.connection)
+        .unwrap_or_else(|| DEFAULT.get_or_init(config::Connection::default))
+}
+
+/// Writes every frame sent by the connection, until it closes the channel
+async fn write_loop<W: AsyncWrite + Unpin>(mut writer: W, mut frames: Receiver<Vec<u8>>) -> Result<()> {
+    while let Some(frame) = frames.recv().await {
+        writer.write_all(&frame).await?;
+        writer.flush().await?;
+    }
+    writer.shutdown().await?;
+    Ok(())
+}
+
+impl<T: Transport> Drop for Connection<T> {
     fn drop(&mut self) {
-        match self.stream.peer_addr() {
-            Ok(peer_address) => log::info!("Connection with {peer_address} finished."),
-            Err(_) => log::warn!("Finishing connection"),
+        match self.peer_address {
+            Some(peer_address) => log::info!("Connection with {peer_address} finished."),
+            None => log::warn!("Finishing connection"),
         }
     }
 }
@@ -0,0 +1,362 @@
+//! Probe scripts send sequences of debug commands to the client, to experiment with the
+//! protocol without recompiling. Scripts are text files in the `probes` directory, started with
+//! `\d probe <name>` and stopped with `\d probe stop`. One statement per line, `#` starts a
+//! comment:
+//!
+//! * any debug command, e.g. `me 5` or `hdr 33 01`
+//! * `sleep <ms>`: waits before the next statement
+//! * `wait <header> <ms>`: waits until the client sends a message with the header, e.g.
+//!   `wait Echo 2000`, or until the time is up
+//! * `for <variable> in <from>..=<to>` ... `end`: repeats the statements for every value
+//!
+//! Commands may use variables between braces, either from loops or `id`, `x`, `y` and `z` of
+//! the player and `pos`, the position of the player as written by the protocol. `{name}` writes
+//! numbers in decimal, `{name:u8}`, `{name:u16}` and `{name:u32}` write them as little endian hex
+//! bytes, for `raw` and `hdr` payloads. Every message sent by the client while the script runs is
+//! logged along with the last command.
+use super::{transport::Transport, Connection};
+use crate::{
+    io::{hex, WriteExt},
+    network::{header::HeaderReceive, message::client::ClientMessage},
+};
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Cursor,
+    path::Path,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+const PROBE_DIRECTORY: &str = "probes";
+const PROBE_EXTENSION: &str = "probe";
+/// Statements after expanding every loop, so nested loops can't grow without limit
+const MAX_STEPS: usize = 100_000;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Statement {
+    Command(String),
+    Sleep(Duration),
+    Wait(HeaderReceive, Duration),
+    For {
+        variable: String,
+        from: i64,
+        to: i64,
+        body: Vec<Statement>,
+    },
+}
+
+/// Statement of a script with its loops expanded and its variables replaced
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Command(String),
+    Sleep(Duration),
+    Wait(HeaderReceive, Duration),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Number(i64),
+    Bytes(Vec<u8>),
+}
+
+pub struct ProbeRun {
+    name: String,
+    steps: VecDeque<Step>,
+    /// When the next step runs
+    resume_at: Instant,
+    waiting_for: Option<HeaderReceive>,
+    last_command: Option<String>,
+}
+
+impl ProbeRun {
+    pub fn resume_at(&self) -> Instant {
+        self.resume_at
+    }
+}
+
+fn parse(script: &str) -> Result<Vec<Statement>> {
+    let mut lines = script
+        .lines()
+        .enumerate()
+        .map(|(n, line)| (n + 1, line.split('#').next().unwrap_or_default().trim()))
+        .filter(|(_, line)| !line.is_empty());
+
+    let statements = parse_block(&mut lines, None)?;
+    Ok(statements)
+}
+
+/// Parses statements until the `end` of the loop started at line `loop_line`, or until the end
+/// of the script
+fn parse_block<'a>(
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+    loop_line: Option<usize>,
+) -> Result<Vec<Statement>> {
+    let mut statements = vec![];
+    while let Some((n, line)) = lines.next() {
+        let words: Vec<&str> = line.split_ascii_whitespace().collect();
+        let statement = match words.as_slice() {
+            ["end"] if loop_line.is_some() => return Ok(statements),
+            ["end"] => return Err(anyhow!("End without a loop on line {n}")),
+            ["sleep", ms] => Statement::Sleep(Duration::from_millis(ms.parse()?)),
+            ["wait", header, ms] => {
+                let header = HeaderReceive::deserialize(
+                    serde::de::value::StrDeserializer::<serde::de::value::Error>::new(header),
+                )
+                .map_err(|_| anyhow!("Unknown header {header:?} on line {n}"))?;
+                Statement::Wait(header, Duration::from_millis(ms.parse()?))
+            }
+            ["for", variable, "in", range] => {
+                let (from, to) = range
+                    .split_once("..=")
+                    .ok_or_else(|| anyhow!("Invalid range {range:?} on line {n}, expected <from>..=<to>"))?;
+                Statement::For {
+                    variable: variable.to_string(),
+                    from: parse_number(from)?,
+                    to: parse_number(to)?,
+                    body: parse_block(lines, Some(n))?,
+                }
+            }
+            ["probe", ..] => return Err(anyhow!("Probes can't start other probes, line {n}")),
+            _ => Statement::Command(line.to_string()),
+        };
+        statements.push(statement);
+    }
+
+    match loop_line {
+        Some(n) => Err(anyhow!("Loop on line {n} has no end")),
+        None => Ok(statements),
+    }
+}
+
+/// Decimal or, starting with `0x`, hex number
+fn parse_number(number: &str) -> Result<i64> {
+    Ok(match number.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16)?,
+        None => number.parse()?,
+    })
+}
+
+fn expand(statements: &[Statement], variables: &mut HashMap<String, Value>, steps: &mut Vec<Step>) -> Result<()> {
+    for statement in statements {
+        if steps.len() >= MAX_STEPS {
+            return Err(anyhow!("Probe is longer than {MAX_STEPS} steps"));
+        }
+        match statement {
+            Statement::Command(command) => steps.push(Step::Command(substitute(command, variables)?)),
+            Statement::Sleep(duration) => steps.push(Step::Sleep(*duration)),
+            Statement::Wait(header, timeout) => steps.push(Step::Wait(*header, *timeout)),
+            Statement::For {
+                variable,
+                from,
+                to,
+                body,
+            } => {
+                if to.saturating_sub(*from) >= MAX_STEPS as i64 {
+                    return Err(anyhow!("Loop over {variable} is longer than {MAX_STEPS} steps"));
+                }
+                let shadowed = variables.remove(variable);
+                for value in *from..=*to {
+                    variables.insert(variable.clone(), Value::Number(value));
+                    expand(body, variables, steps)?;
+                }
+                variables.remove(variable);
+                if let Some(shadowed) = shadowed {
+                    variables.insert(variable.clone(), shadowed);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replaces every `{name}` or `{name:format}` of the command with the value of the variable
+fn substitute(command: &str, variables: &HashMap<String, Value>) -> Result<String> {
+    let mut result = String::new();
+    let mut rest = command;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("Unclosed brace in {command:?}"))?;
+        let (name, format) = match rest[start + 1..start + end].split_once(':') {
+            Some((name, format)) => (name, Some(format)),
+            None => (&rest[start + 1..start + end], None),
+        };
+        let value = variables
+            .get(name)
+            .ok_or_else(|| anyhow!("Unknown variable {name:?} in {command:?}"))?;
+
+        result.push_str(&rest[..start]);
+        result.push_str(&format_value(value, format)?);
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn format_value(value: &Value, format: Option<&str>) -> Result<String> {
+    let out_of_range = |value: i64| anyhow!("Value {value} does not fit in {}", format.unwrap_or_default());
+    match (value, format) {
+        (Value::Number(value), None) => Ok(value.to_string()),
+        (Value::Number(value), Some("u8")) => {
+            Ok(hex::encode(&u8::try_from(*value).map_err(|_| out_of_range(*value))?.to_le_bytes()))
+        }
+        (Value::Number(value), Some("u16")) => {
+            Ok(hex::encode(&u16::try_from(*value).map_err(|_| out_of_range(*value))?.to_le_bytes()))
+        }
+        (Value::Number(value), Some("u32")) => {
+            Ok(hex::encode(&u32::try_from(*value).map_err(|_| out_of_range(*value))?.to_le_bytes()))
+        }
+        (Value::Bytes(bytes), None) => Ok(hex::encode(bytes)),
+        (_, Some(format)) => Err(anyhow!("Unknown format {format:?}")),
+    }
+}
+
+impl<T: Transport> Connection<T> {
+    pub(super) async fn command_probe(&mut self, name: &str) -> Result<()> {
+        if name == "stop" {
+            if let Some(run) = self.probe.take() {
+                log::info!("Probe {} stopped, {} steps left", run.name, run.steps.len());
+            }
+            return Ok(());
+        }
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            return Err(anyhow!("Invalid probe name {name:?}"));
+        }
+
+        let path = Path::new(PROBE_DIRECTORY).join(name).with_extension(PROBE_EXTENSION);
+        let script = std::fs::read_to_string(&path).map_err(|err| anyhow!("Error reading {path:?}: {err}"))?;
+
+        let mut position = Cursor::new(vec![]);
+        position.write_position(self.player.position, self.protocol).await?;
+        let mut variables = HashMap::from([
+            ("id".to_string(), Value::Number(self.player.id.into())),
+            ("x".to_string(), Value::Number(self.player.position.x.into())),
+            ("y".to_string(), Value::Number(self.player.position.y.into())),
+            ("z".to_string(), Value::Number(self.player.position.z.into())),
+            ("pos".to_string(), Value::Bytes(position.into_inner())),
+        ]);
+        let mut steps = vec![];
+        expand(&parse(&script)?, &mut variables, &mut steps)?;
+
+        log::info!("Probe {name} started, {} steps", steps.len());
+        self.probe = Some(ProbeRun {
+            name: name.to_string(),
+            steps: steps.into(),
+            resume_at: Instant::now(),
+            waiting_for: None,
+            last_command: None,
+        });
+        Ok(())
+    }
+
+    /// Runs the steps of the probe until it has to sleep or wait for the client
+    pub(super) async fn advance_probe(&mut self) -> Result<()> {
+        let Some(run) = self.probe.as_mut() else {
+            return Ok(());
+        };
+        if let Some(header) = run.waiting_for.take() {
+            log::info!("Probe {}: no {header:?} from the client after {:?}", run.name, run.last_command);
+        }
+
+        while let Some(run) = self.probe.as_mut() {
+            let Some(step) = run.steps.pop_front() else {
+                log::info!("Probe {} finished", run.name);
+                self.probe = None;
+                break;
+            };
+
+            match step {
+                Step::Command(command) => {
+                    log::info!("Probe {}: {command}", run.name);
+                    run.last_command = Some(command.clone());
+                    if let Err(err) = self.send_debug_command(&command).await {
+                        log::warn!("Probe command {command:?} failed: {err}");
+                    }
+                }
+                Step::Sleep(duration) => {
+                    run.resume_at = Instant::now() + duration;
+                    break;
+                }
+                Step::Wait(header, timeout) => {
+                    run.resume_at = Instant::now() + timeout;
+                    run.waiting_for = Some(header);
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Logs the reaction of the client to the probe and ends its wait for the message
+    pub(super) fn probe_received(&mut self, header: HeaderReceive, message: &ClientMessage) {
+        let Some(run) = self.probe.as_mut() else {
+            return;
+        };
+        log::info!("Probe {}: client sent {message:?} after {:?}", run.name, run.last_command);
+        if run.waiting_for == Some(header) {
+            run.waiting_for = None;
+            run.resume_at = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_probe() -> Result<()> {
+        let script = "
+            # sweeps a byte
+            for b in 0..=1
+                hdr 32 {id:u32} 03 02 0000 {b:u8} # outfit
+                wait Echo 500
+            end
+            me {b}
+            sleep 10
+        ";
+        let statements = parse(script)?;
+        let mut variables = HashMap::from([
+            ("id".to_string(), Value::Number(0x01020304)),
+            ("b".to_string(), Value::Number(7)),
+        ]);
+        let mut steps = vec![];
+        expand(&statements, &mut variables, &mut steps)?;
+
+        assert_eq!(
+            steps,
+            vec![
+                Step::Command("hdr 32 04030201 03 02 0000 00".to_string()),
+                Step::Wait(HeaderReceive::Echo, Duration::from_millis(500)),
+                Step::Command("hdr 32 04030201 03 02 0000 01".to_string()),
+                Step::Wait(HeaderReceive::Echo, Duration::from_millis(500)),
+                Step::Command("me 7".to_string()),
+                Step::Sleep(Duration::from_millis(10)),
+            ]
+        );
+
+        assert!(parse("for b in 0..=1\nme {b}").is_err());
+        assert!(parse("wait Nothing 10").is_err());
+        assert!(parse("end").is_err());
+        assert!(expand(&parse("for i in 0..=0xffffffffff\nend")?, &mut variables, &mut vec![]).is_err());
+        assert!(expand(&parse("me {c}")?, &mut variables, &mut vec![]).is_err());
+        assert!(expand(&parse("hdr 33 {id:u8}")?, &mut variables, &mut vec![]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_probe_directory() -> Result<()> {
+        let mut variables = HashMap::from([
+            ("id".to_string(), Value::Number(1)),
+            ("pos".to_string(), Value::Bytes(vec![0x32, 0x00, 0x32, 0x00, 0x07])),
+        ]);
+        for entry in std::fs::read_dir(PROBE_DIRECTORY)? {
+            let path = entry?.path();
+            let script = std::fs::read_to_string(&path)?;
+            expand(&parse(&script)?, &mut variables, &mut vec![]).map_err(|err| anyhow!("{path:?}: {err}"))?;
+        }
+        Ok(())
+    }
+}
@@ -1,60 +1,112 @@
-use super::Connection;
+use super::{auto_walk::AutoWalk, connection_config, discoveries, recorder::RecordDirection, transport::Transport, Connection};
 use crate::{
-    character::{Direction, FightMode, FightStance, OutfitType},
-    chat::ChatType,
-    constants::{MagicEffect, ObjectUpdateType},
-    io::ReadExt,
-    map::position::PositionQualifier,
-    network::header::HeaderReceive,
+    character::{Direction, FightMode, FightStance, OutfitColors, OutfitType},
+    chat::{self, encoding, ChatType},
+    config::{self, SlowClientPolicy},
+    constants::MagicEffect,
+    map::position::{Position, PositionQualifier},
+    network::{
+        header::{HeaderReceive, HeaderSend},
+        message::{
+            client::{ClientMessage, DecodeError, PlayerDetails},
+            server::ServerMessage,
+        },
+    },
+    protocol::WalkRefusal,
     world::message::{PlayerToWorldMessage, WorldToPlayerMessage},
-    Protocol,
-};
-use anyhow::Result;
-use std::{
-    convert::TryInto,
-    io::Cursor,
-    time::Duration
 };
+use anyhow::{anyhow, Result};
 use tokio::{
-    io::{
-        AsyncReadExt,
-        AsyncRead
-    },
-    time::timeout,
+    io::AsyncReadExt,
+    time::{sleep_until, Instant},
 };
 
-impl Connection {
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// Removes the first complete frame from the buffer, without its length. Fails as soon as the
+/// length and header show the frame is above its limit, without waiting for the rest of it
+fn take_frame(buffer: &mut Vec<u8>, limits: &config::Connection) -> Result<Option<Vec<u8>>> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let length = u16::from_le_bytes([buffer[0], buffer[1]]) as usize;
+    let header = match buffer.get(2..4) {
+        Some(header) if length >= 2 => {
+            HeaderReceive::try_from(u16::from_le_bytes([header[0], header[1]])).ok()
+        }
+        None if length >= 2 => return Ok(None),
+        _ => None,
+    };
+
+    let limit = limits.max_frame_size(header);
+    if length > limit as usize {
+        return Err(anyhow!(
+            "Frame of {length} bytes is above the limit of {limit} bytes for {}",
+            header.map_or("unknown headers".to_string(), |header| format!("{header:?}"))
+        ));
+    }
+
+    if buffer.len() < length + 2 {
+        return Ok(None);
+    }
+    let frame = buffer[2..length + 2].to_vec();
+    buffer.drain(..length + 2);
+    Ok(Some(frame))
+}
+
+impl<T: Transport> Connection<T> {
     pub async fn handle_connection(&mut self) -> Result<()> {
+        let mut buffer = vec![];
+        let mut chunk = vec![0_u8; READ_CHUNK_SIZE];
+        let limits = connection_config();
+
         loop {
-            //TODO use non blocking io instead of timeout
-            match timeout(Duration::from_millis(100), self.stream.read_u16_le()).await {
-                Ok(Ok(length)) => {
-                    let mut message = vec![0_u8; length as usize];
-                    self.stream.read_exact(&mut message).await?;
-                    log::trace!("Message received: length={length}, bytes={message:02x?}");
-
-                    self.receive_message(Cursor::new(message)).await?;
-                },
-                Ok(Err(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof || err.kind() == std::io::ErrorKind::ConnectionReset => {
-                    log::info!("Client disconnected ({:?})", err.kind());
-                    break;
-                },
-                Ok(Err(err)) if err.kind() == std::io::ErrorKind::TimedOut => { /* do nothing */ },
-                Ok(Err(err)) => {
-                    log::error!("Connection ended ({:?})", err.kind());
-                    return Err(err.into())
+            let probe_deadline = self.probe.as_ref().map(|probe| probe.resume_at());
+            let auto_walk_deadline = self.auto_walk.as_ref().and_then(AutoWalk::next_step);
+
+            // All branches are cancel safe: read() and recv() take nothing from their source
+            // unless they complete
+            tokio::select! {
+                read = self.stream.read(&mut chunk) => match read {
+                    Ok(0) => {
+                        log::info!("Client disconnected");
+                        break;
+                    }
+                    Ok(bytes) => {
+                        buffer.extend_from_slice(&chunk[..bytes]);
+                        while let Some(message) = take_frame(&mut buffer, limits).inspect_err(|err| {
+                            log::warn!("Rejecting client {:?}: {err}", self.peer_address)
+                        })? {
+                            if self.receive_frame(message).await? {
+                                log::info!("Player {} logged out", self.player_id);
+                                self.flush_message_queue().await?;
+                                return Ok(());
+                            }
+                        }
+                    }
+                    Err(err) if err.kind() == std::io::ErrorKind::ConnectionReset => {
+                        log::info!("Client disconnected ({:?})", err.kind());
+                        break;
+                    }
+                    Err(err) => {
+                        log::error!("Connection ended ({:?})", err.kind());
+                        return Err(err.into());
+                    }
                 },
-                Err(_elapsed) => { /* do nothing */ }
-            };
-
-            if self.protocol >= Protocol::Tibia300 {
-                if let Ok(msg) = self.receiver.try_recv() {
-                    match msg {
-                        WorldToPlayerMessage::WorldLight(light_level) => {
-                            self.queue_message(self.prepare_world_light(light_level).await?)
-                                .await
+                msg = self.receiver.recv() => match msg {
+                    Some(msg) => {
+                        self.receive_world_message(msg).await?;
+                        while let Ok(msg) = self.receiver.try_recv() {
+                            self.receive_world_message(msg).await?;
                         }
                     }
+                    None => return Err(anyhow!("Disconnected by the world, client is not keeping up")),
+                },
+                _ = sleep_until(probe_deadline.unwrap_or_else(Instant::now)), if probe_deadline.is_some() => {
+                    self.advance_probe().await?;
+                }
+                _ = sleep_until(auto_walk_deadline.unwrap_or_else(Instant::now)), if auto_walk_deadline.is_some() => {
+                    self.advance_auto_walk().await?;
                 }
             }
 
@@ -63,86 +115,208 @@ impl Connection {
         Ok(())
     }
 
-    async fn receive_message<R: AsyncRead + Unpin>(&mut self, mut message: R) -> Result<bool> {
-        match message.read_u16_le().await?.try_into() {
-            Ok(header) => {
-                log::trace!("Message received from client: {header:?}");
-                match header {
-                    HeaderReceive::PlayerInfo => {
-                        self.receive_player_info(&mut message).await?
-                    }
-                    HeaderReceive::UserList => {
-                        self.receive_user_list(&mut message).await?
-                    }
-                    HeaderReceive::Walk => self.receive_walk(&mut message).await?,
-                    HeaderReceive::AutoWalk => {
-                        self.receive_auto_walk(&mut message).await?
-                    }
-                    HeaderReceive::LookAt => self.receive_look_at(&mut message).await?,
-                    HeaderReceive::Chat => self.receive_chat(&mut message).await?,
-                    HeaderReceive::ChangeDirection => {
-                        self.receive_change_direction(&mut message).await?
-                    }
-                    HeaderReceive::Comment => {
-                        self.receive_comment(&mut message).await?
-                    }
-                    HeaderReceive::Push => self.receive_push(&mut message).await?,
-                    HeaderReceive::UseItem => {
-                        self.receive_use_item(&mut message).await?
-                    }
-                    HeaderReceive::CloseContainer => {
-                        self.receive_close_container(&mut message).await?
-                    }
-                    HeaderReceive::RequestChangeData => {
-                        self.receive_change_data(&mut message).await?
-                    }
-                    HeaderReceive::SetData => {
-                        self.receive_set_data(&mut message).await?
-                    }
-                    HeaderReceive::SetText => {
-                        self.receive_set_text(&mut message).await?
-                    }
-                    HeaderReceive::HouseText => {
-                        self.receive_house_text(&mut message).await?
-                    }
-                    HeaderReceive::ChangeMode => {
-                        self.receive_change_mode(&mut message).await?
-                    }
-                    HeaderReceive::ExitBattle => {
-                        self.receive_exit_battle(&mut message).await?
-                    }
-                    HeaderReceive::SetTarget => {
-                        self.receive_set_target(&mut message).await?
-                    }
-                    HeaderReceive::Echo => {}
-                    HeaderReceive::Logout => {
-                        self.sender.send(PlayerToWorldMessage::UnloadPlayer(self.player_id))?;
-                        return Ok(true);
-                    }
+    /// Returns true if the client logged out
+    async fn receive_frame(&mut self, message: Vec<u8>) -> Result<bool> {
+        log::trace!("Message received: length={}, bytes={message:02x?}", message.len());
+
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record(
+                RecordDirection::ClientToServer,
+                self.protocol,
+                &[&(message.len() as u16).to_le_bytes()[..], &message].concat(),
+            )?;
+        }
+
+        self.receive_message(&message).await
+    }
+
+    async fn receive_world_message(&mut self, msg: WorldToPlayerMessage) -> Result<()> {
+        if !msg.is_essential()
+            && self.slow_client_policy == SlowClientPolicy::DropNonEssential
+            && self.is_outbound_full()
+        {
+            log::debug!("Client is not keeping up, dropping {msg:?}");
+            return Ok(());
+        }
+
+        match msg {
+            // Only expected once, before the login, see `handle_login`
+            WorldToPlayerMessage::Loaded(position) => log::warn!("Player loaded again on {position}"),
+            WorldToPlayerMessage::WorldLight(light_level) => {
+                if self.protocol.capabilities().supports(HeaderSend::WorldLight) {
+                    self.queue_message(ServerMessage::WorldLight(light_level))
+                        .await?;
+                }
+            }
+            WorldToPlayerMessage::Walked {
+                from,
+                to,
+                direction,
+            } => self.walked(from, to, direction).await?,
+            WorldToPlayerMessage::WalkBlocked(direction) => self.walk_blocked(direction).await?,
+            WorldToPlayerMessage::AutoWalkPath(steps) => self.auto_walk_path(steps).await?,
+            WorldToPlayerMessage::AutoWalkUnreachable => self.auto_walk_unreachable().await?,
+            WorldToPlayerMessage::CharacterAppeared(character) => {
+                self.queue_message(self.character_appeared(character)).await?;
+            }
+            WorldToPlayerMessage::CharacterDisappeared { id: _, position } => {
+                self.queue_message(self.character_disappeared(position)).await?;
+            }
+            WorldToPlayerMessage::CharacterMoved {
+                id,
+                from,
+                to,
+                direction,
+            } => {
+                for message in self.character_moved(id, from, to, direction) {
+                    self.queue_message(message).await?;
+                }
+            }
+            WorldToPlayerMessage::CharacterTurned {
+                id,
+                position,
+                direction,
+            } => {
+                self.queue_message(self.character_turned(id, position, direction))
+                    .await?;
+            }
+            WorldToPlayerMessage::Chat {
+                chat_type,
+                message,
+                sender,
+                position,
+            } => {
+                self.queue_message(ServerMessage::Chat {
+                    chat_type,
+                    message,
+                    sender: Some(sender),
+                    position: Some(position),
+                })
+                .await?;
+            }
+            WorldToPlayerMessage::PrivateMessage { sender, message } => {
+                self.queue_message(ServerMessage::Chat {
+                    chat_type: self.protocol.capabilities().private_message,
+                    message,
+                    sender: Some(sender),
+                    position: None,
+                })
+                .await?;
+            }
+            WorldToPlayerMessage::PrivateMessageSent(name) => {
+                self.queue_message(ServerMessage::StatusMessage(format!("Message sent to {name}.")))
+                    .await?;
+            }
+            WorldToPlayerMessage::PlayerNotOnline(name) => {
+                log::trace!("Private message to {name} not sent, not online");
+                self.queue_message(ServerMessage::StatusMessage(
+                    "A player with this name is not online.".to_string(),
+                ))
+                .await?;
+            }
+            WorldToPlayerMessage::CharacterOutfitChanged { id, outfit } => {
+                self.queue_message(ServerMessage::UpdateOutfit {
+                    id,
+                    outfit_type: OutfitType::Human,
+                    colors: outfit,
+                })
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn receive_message(&mut self, bytes: &[u8]) -> Result<bool> {
+        // Clients send bytes past what is known of some messages, they are kept for later
+        let message = match ClientMessage::decode_with_trailing_bytes(bytes, self.protocol) {
+            Ok((message, trailing)) => {
+                if !trailing.is_empty() {
+                    let context = format!("{:?} trailing bytes", message.header());
+                    discoveries::record(self.protocol, &context, bytes.len() - trailing.len(), trailing);
                 }
+                message
             }
-            Err(err) => {
-                log::error!("Error reading header: {err:?}");
+            Err(DecodeError::UnknownHeader(header)) => {
+                log::error!("Error reading header: unknown header 0x{header:04x}");
+                discoveries::record(self.protocol, &format!("unknown header 0x{header:04x}"), 0, bytes);
+                return Ok(false);
             }
+            Err(err @ DecodeError::InvalidValue { .. }) => {
+                log::warn!("Dropping client message: {err}");
+                return Ok(false);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        log::trace!("Message received from client: {message:?}");
+        if let Ok(header) = HeaderReceive::try_from(u16::from_le_bytes([bytes[0], bytes[1]])) {
+            self.probe_received(header, &message);
+        }
+
+        match message {
+            ClientMessage::PlayerInfo { name } => self.receive_player_info(name).await?,
+            ClientMessage::UserList => self.receive_user_list().await?,
+            ClientMessage::Walk { direction } => self.receive_walk(direction).await?,
+            ClientMessage::AutoWalk { position } => self.receive_auto_walk(position).await?,
+            ClientMessage::LookAt { position } => self.receive_look_at(position).await?,
+            ClientMessage::Chat { message } => self.receive_chat(message).await?,
+            ClientMessage::ChangeDirection { direction } => {
+                self.receive_change_direction(direction).await?
+            }
+            ClientMessage::Comment { comment } => self.receive_comment(&comment).await?,
+            ClientMessage::Push {
+                from,
+                object_id,
+                stack_pos,
+                to,
+                count,
+            } => self.receive_push(from, object_id, stack_pos, to, count).await?,
+            ClientMessage::UseItem {
+                item_type,
+                position,
+                item_id,
+                stack_pos,
+                unknown,
+            } => {
+                self.receive_use_item(item_type, position, item_id, stack_pos, unknown)
+                    .await?
+            }
+            ClientMessage::CloseContainer { local_id } => {
+                self.receive_close_container(local_id).await?
+            }
+            ClientMessage::RequestChangeData => self.receive_change_data().await?,
+            ClientMessage::SetData { outfit, details } => {
+                self.receive_set_data(outfit, details).await?
+            }
+            ClientMessage::SetText { data } => self.receive_set_text(&data).await?,
+            ClientMessage::HouseText { data } => self.receive_house_text(&data).await?,
+            ClientMessage::ChangeMode {
+                fight_mode,
+                fight_stance,
+            } => self.receive_change_mode(fight_mode, fight_stance).await?,
+            ClientMessage::ExitBattle => self.receive_exit_battle().await?,
+            ClientMessage::SetTarget { id } => self.receive_set_target(id).await?,
+            ClientMessage::Echo => {}
+            ClientMessage::Logout => return Ok(true),
         }
 
         Ok(false)
     }
 
-    async fn receive_set_text<R: AsyncRead + Unpin>(&mut self, _message: &mut R) -> Result<()> {
-        log::trace!("received set_text");
+    async fn receive_set_text(&mut self, data: &[u8]) -> Result<()> {
+        discoveries::record(self.protocol, "SetText", 2, data);
         Ok(())
     }
 
-    async fn receive_house_text<R: AsyncRead + Unpin>(&mut self, _message: &mut R) -> Result<()> {
-        log::trace!("received house_text");
+    async fn receive_house_text(&mut self, data: &[u8]) -> Result<()> {
+        discoveries::record(self.protocol, "HouseText", 2, data);
         Ok(())
     }
 
-    async fn receive_change_mode<R: AsyncRead + Unpin>(&mut self, message: &mut R) -> Result<()> {
-        let fight_mode: FightMode = message.read_u8().await?.try_into()?;
-        let fight_stance: FightStance = message.read_u8().await?.try_into()?;
-
+    async fn receive_change_mode(
+        &mut self,
+        fight_mode: FightMode,
+        fight_stance: FightStance,
+    ) -> Result<()> {
         log::trace!(
             "Change mode: mode={fight_mode:?}, stance={fight_stance:?}"
         );
@@ -150,50 +324,34 @@ impl Connection {
         Ok(())
     }
 
-    async fn receive_exit_battle<R: AsyncRead + Unpin>(&mut self, _message: &mut R) -> Result<()> {
+    async fn receive_exit_battle(&mut self) -> Result<()> {
         log::trace!("Exit battle");
         Ok(())
     }
 
-    async fn receive_player_info<R: AsyncRead + Unpin>(&mut self, message: &mut R) -> Result<()> {
-        let mut player_name = String::new();
-        unsafe { message.read_string_until_end(&mut player_name).await? };
-        self.queue_message(self.prepare_user_info(&player_name).await?)
-            .await;
+    async fn receive_player_info(&mut self, player_name: String) -> Result<()> {
+        self.queue_message(ServerMessage::UserInfo { name: player_name })
+            .await?;
 
         Ok(())
     }
 
-    async fn receive_user_list<R: AsyncRead + Unpin>(&mut self, _message: &mut R) -> Result<()> {
-        self.queue_message(self.prepare_user_list().await?).await;
+    async fn receive_user_list(&mut self) -> Result<()> {
+        self.queue_message(ServerMessage::UserList {
+            names: vec![self.player.name.clone()],
+        })
+        .await?;
         Ok(())
     }
 
-    async fn receive_push<R: AsyncRead + Unpin>(&mut self, message: &mut R) -> Result<()> {
-        let (position_from, object_id, stack_pos, position_to, count) =
-            if self.protocol == Protocol::Tibia103 {
-                let position_from = message.read_position(self.protocol).await?;
-                let object_id = message.read_u16_le().await?;
-                let stack_pos = message.read_u8().await?;
-                let position_to = message.read_position(self.protocol).await?;
-
-                (position_from, object_id, stack_pos, position_to, None)
-            } else {
-                let position_from = message.read_position(self.protocol).await?;
-                let object_id = message.read_u16_le().await?;
-                let stack_pos = message.read_u8().await?;
-                let position_to = message.read_position(self.protocol).await?;
-                let count = message.read_u8().await?;
-
-                (
-                    position_from,
-                    object_id,
-                    stack_pos,
-                    position_to,
-                    Some(count),
-                )
-            };
-
+    async fn receive_push(
+        &mut self,
+        position_from: Position,
+        object_id: u16,
+        stack_pos: u8,
+        position_to: Position,
+        count: Option<u8>,
+    ) -> Result<()> {
         let msg_from = match position_from.get_qualifier(self.protocol)? {
             PositionQualifier::None => format!("{position_from}"),
             PositionQualifier::Container(container_index, item_index) => {
@@ -221,93 +379,94 @@ impl Connection {
         Ok(())
     }
 
-    async fn receive_set_data<R: AsyncRead + Unpin>(&mut self, message: &mut R) -> Result<()> {
-        let outfit = if self.protocol <= Protocol::Tibia501 {
-            let mut password = String::new();
-            message.read_string(&mut password, 30).await?;
-
-            let outfit = message.read_outfit_colors().await?;
-
-            let mut real_name = String::new();
-            message.read_string(&mut real_name, 50).await?;
-
-            let mut location = String::new();
-            message.read_string(&mut location, 50).await?;
-
-            let mut email = String::new();
-            message.read_string(&mut email, 50).await?;
-
-            if self.protocol >= Protocol::Tibia400 {
-                let mut comment = String::new();
-                message.read_string(&mut comment, 500).await?;
-
+    async fn receive_set_data(
+        &mut self,
+        outfit: OutfitColors,
+        details: Option<PlayerDetails>,
+    ) -> Result<()> {
+        match details {
+            Some(PlayerDetails {
+                password,
+                real_name,
+                location,
+                email,
+                comment: Some(comment),
+            }) => {
                 log::trace!("Change Data: password={password}, outfit={outfit:?}, real name={real_name}, location={location}, e-mail={email}, comment={comment}");
-            } else {
+            }
+            Some(PlayerDetails {
+                password,
+                real_name,
+                location,
+                email,
+                comment: None,
+            }) => {
                 log::trace!(
                     "Change Data: password={password}, outfit={outfit:?}, real name={real_name}, location={location}, e-mail={email}"
                 );
             }
+            None => log::trace!("Change Data: outfit={outfit:?}"),
+        }
 
-            outfit
-        } else {
-            let outfit = message.read_outfit_colors().await?;
-
-            log::trace!("Change Data: outfit={outfit:?}");
-
-            outfit
-        };
-
-        self.queue_message(
-            self.prepare_update_outfit(self.player.id, OutfitType::Human, outfit)
-                .await?,
-        )
-        .await;
+        self.queue_message(ServerMessage::UpdateOutfit {
+            id: self.player.id,
+            outfit_type: OutfitType::Human,
+            colors: outfit,
+        })
+        .await?;
         self.player.outfit = outfit;
+        self.sender.send(PlayerToWorldMessage::ChangeOutfit(self.player.id, outfit)).await?;
 
         Ok(())
     }
 
-    async fn receive_change_data<R: AsyncRead + Unpin>(&mut self, _message: &mut R) -> Result<()> {
-        self.queue_message(self.prepare_data_window().await?).await;
+    async fn receive_change_data(&mut self) -> Result<()> {
+        self.queue_message(ServerMessage::DataWindow {
+            name: self.player.name.clone(),
+            gender: self.player.gender,
+            outfit: self.player.outfit,
+        })
+        .await?;
         Ok(())
     }
 
-    async fn receive_set_target<R: AsyncRead + Unpin>(&mut self, message: &mut R) -> Result<()> {
-        let target_id = message.read_u32_le().await?;
+    async fn receive_set_target(&mut self, target_id: u32) -> Result<()> {
         log::trace!("Set target, id={target_id}");
 
         Ok(())
     }
 
-    async fn receive_use_item<R: AsyncRead + Unpin>(&mut self, message: &mut R) -> Result<()> {
-        let item_type = message.read_u8().await?; //1 = regular, 2 = usable with
-        let pos = message.read_position(self.protocol).await?;
-        let item_id = message.read_u16_le().await?;
-        let stack_pos = message.read_u8().await?;
-        let unknown = message.read_u8().await?;
-
+    async fn receive_use_item(
+        &mut self,
+        item_type: u8,
+        pos: Position,
+        item_id: u16,
+        stack_pos: u8,
+        unknown: u8,
+    ) -> Result<()> {
         log::trace!(
             "item_type={item_type}, pos={pos}, item_id=0x{item_id:04x?}, stack_pos={stack_pos}, unknown={unknown}"
         );
 
-        let message = self.prepare_open_container().await?;
-        self.queue_message(message).await;
+        //TODO send non hardcoded items
+        self.queue_message(ServerMessage::OpenContainer {
+            local_id: 1,
+            item_id: 0x013d,
+            items: vec![0x005a; 5],
+        })
+        .await?;
 
         Ok(())
     }
 
-    async fn receive_close_container<R: AsyncRead + Unpin>(&mut self, message: &mut R) -> Result<()> {
-        let local_id = message.read_u8().await?;
-
-        self.queue_message(self.prepare_close_container(local_id).await?)
-            .await;
+    async fn receive_close_container(&mut self, local_id: u8) -> Result<()> {
+        self.queue_message(ServerMessage::CloseContainer { local_id })
+            .await?;
 
         Ok(())
     }
 
-    async fn receive_look_at<R: AsyncRead + Unpin>(&mut self, message: &mut R) -> Result<()> {
-        let position = message.read_position(self.protocol).await?;
-
+    async fn receive_look_at(&mut self, position: Position) -> Result<()> {
         let msg = match position.get_qualifier(self.protocol)? {
             PositionQualifier::None => format!("Looking at position {position}"),
             PositionQualifier::Container(container_index, item_index) => {
@@ -321,115 +480,112 @@ impl Connection {
         };
 
         log::trace!("{msg}");
-        self.queue_message(
-            self.prepare_chat(
-                ChatType::GreenScreenOnly,
-                &msg,
-                None,
-                Some(self.player.position),
-            )
-            .await?,
-        )
-        .await;
+        self.queue_message(self.chat_message(
+            ChatType::GreenScreenOnly,
+            &msg,
+            None,
+            Some(self.player.position),
+        ))
+        .await?;
 
         Ok(())
     }
 
-    async fn receive_change_direction<R: AsyncRead + Unpin>(&mut self, message: &mut R) -> Result<()> {
-        let direction: Direction = message.read_u8().await?.try_into()?;
+    async fn receive_change_direction(&mut self, direction: Direction) -> Result<()> {
         log::trace!("Change direction to {direction:?}");
 
-        //todo use real stack pos
-        let mut msg = self
-            .prepare_update_object(self.player.position, ObjectUpdateType::Update, 1)
+        self.queue_message(self.character_turned(self.player.id, self.player.position, direction))
             .await?;
-        msg.extend(
-            self.prepare_change_direction(self.player.id, direction)
-                .await?,
-        );
-        self.queue_message(msg).await;
+        self.sender.send(PlayerToWorldMessage::Turn(self.player.id, direction)).await?;
 
         Ok(())
     }
 
-    async fn receive_auto_walk<R: AsyncRead + Unpin>(&mut self, message: &mut R) -> Result<()> {
-        let position = message.read_position(self.protocol).await?;
-        log::trace!("Auto walk to {position:?}");
+    /// The world decides whether the step is possible, see `walked` and `walk_blocked`
+    async fn receive_walk(&mut self, direction: Direction) -> Result<()> {
+        log::trace!("Walk 1 tile {direction:?}");
+
+        self.cancel_auto_walk("walking manually");
+        self.sender.send(PlayerToWorldMessage::Walk(self.player.id, direction)).await?;
 
         Ok(())
     }
 
-    async fn receive_walk<R: AsyncRead + Unpin>(&mut self, message: &mut R) -> Result<()> {
-        let direction: Direction = message.read_u8().await?.try_into()?;
-        log::trace!("Walk 1 tile {direction:?}");
-
-        self.sender.send(PlayerToWorldMessage::Walk(self.player.id))?;
+    async fn walked(&mut self, from: Position, to: Position, direction: Direction) -> Result<()> {
+        self.player.position = to;
 
-        let old_position = self.player.position;
-        let new_position = self.player.position + direction;
-        self.player.position = new_position;
+        for message in self.character_moved(self.player.id, from, to, direction) {
+            self.queue_message(message).await?;
+        }
 
-        if self.protocol == Protocol::Tibia103 {
-            // Remove character from old tile
-            // let msg = self.prepare_update_object(old_position, ObjectUpdateType::Remove, 1).await?;
-            // self.queue_message(msg).await;
+        //Move character and update map
+        self.queue_message(ServerMessage::MoveOneTile {
+            direction,
+            player: self.player_view(),
+        })
+        .await?;
 
-            // Add character to new tile
-            // let msg = self.prepare_update_object(new_position, ObjectUpdateType::Add, 1).await?;
-            // self.queue_message(msg).await;
-        } else {
-            // Remove character from old tile
-            let msg = self
-                .prepare_update_object(old_position, ObjectUpdateType::Remove, 1)
-                .await?;
-            self.queue_message(msg).await;
+        Ok(())
+    }
 
-            // Add character to new tile
-            let mut msg = self
-                .prepare_update_object(new_position, ObjectUpdateType::Add, 1)
+    /// The client already made the step it asked for, so it is put back on its real position
+    /// and told why
+    async fn walk_blocked(&mut self, direction: Direction) -> Result<()> {
+        self.cancel_auto_walk("step blocked");
+        self.queue_message(ServerMessage::StatusMessage("Sorry, not possible.".to_string()))
+            .await?;
+        self.queue_message(ServerMessage::Map {
+            player: self.player_view(),
+        })
+        .await?;
+        if self.protocol.capabilities().walk_refusal == WalkRefusal::MapAndTurn {
+            self.queue_message(self.character_turned(self.player.id, self.player.position, direction))
                 .await?;
-            msg.extend(
-                self.prepare_change_direction(self.player.id, direction)
-                    .await?,
-            );
-            self.queue_message(msg).await;
         }
 
-        //Move character and update map
-        self.queue_message(
-            self.prepare_move_character(direction, old_position, new_position)
-                .await?,
-        )
-        .await;
-
         Ok(())
     }
 
-    async fn receive_chat<R: AsyncRead + Unpin>(&mut self, message: &mut R) -> Result<()> {
-        let length = message.read_u16_le().await?;
+    async fn receive_chat(&mut self, raw_msg: Vec<u8>) -> Result<()> {
         let config = crate::config::CONFIG.get().unwrap();
 
-        let mut raw_msg = vec![0_u8; length as usize];
-        message.read_exact(&mut raw_msg).await?;
         log::trace!("raw message = {raw_msg:02x?}");
-        let msg = unsafe { String::from_utf8_unchecked(raw_msg) };
+        let msg = encoding::from_game_bytes(&raw_msg);
         log::trace!("message = '{msg}'");
 
         if config.server.debug_commands && msg.starts_with("\\d ") {
             self.receive_debug_command(&msg[2..]).await?;
+        } else if let Some((receiver, text)) = chat::parse_private_message(&msg) {
+            self.sender.send(PlayerToWorldMessage::PrivateMessage(
+                self.player.id,
+                receiver.to_string(),
+                text.to_string(),
+            ))
+            .await?;
         } else if msg.starts_with('#') {
             self.receive_qualified_chat(&msg).await?;
         } else {
-            self.queue_message(
-                self.prepare_chat(
-                    ChatType::Normal,
-                    &msg,
-                    Some(&self.player),
-                    Some(self.player.position),
-                )
-                .await?,
-            )
-            .await;
+            self.say(ChatType::Normal, &msg).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Messages heard by other players go through the world, which sends them back to the
+    /// player as well. The others are only shown to the player
+    async fn say(&mut self, chat_type: ChatType, msg: &str) -> Result<()> {
+        if chat_type.is_heard_by_others() {
+            self.sender
+                .send(PlayerToWorldMessage::Chat(self.player.id, chat_type, msg.to_string()))
+                .await?;
+        } else {
+            self.queue_message(self.chat_message(
+                chat_type,
+                msg,
+                Some(&self.player),
+                Some(self.player.position),
+            ))
+            .await?;
         }
 
         Ok(())
@@ -437,11 +593,13 @@ impl Connection {
 
     async fn receive_debug_command(&mut self, msg: &str) -> Result<()> {
         if let Err(err) = self.send_debug_command(msg).await {
-            self.queue_message(
-                self.prepare_magic_effect(MagicEffect::Puff, self.player.position)
-                    .await?,
-            )
-            .await;
+            if self.protocol.capabilities().supports(HeaderSend::MagicEffect) {
+                self.queue_message(ServerMessage::MagicEffect {
+                    effect: MagicEffect::Puff,
+                    position: self.player.position,
+                })
+                .await?;
+            }
             log::trace!("Error on debug command: {err:?}");
         }
 
@@ -451,34 +609,62 @@ impl Connection {
     async fn receive_qualified_chat(&mut self, msg: &str) -> Result<()> {
         match TryInto::<ChatType>::try_into(msg.chars().nth(1)) {
             Ok(chat_type) => {
-                self.queue_message(
-                    self.prepare_chat(
-                        chat_type,
-                        &msg[3..],
-                        Some(&self.player),
-                        Some(self.player.position),
-                    )
-                    .await?,
-                )
-                .await;
+                // "#x message", the qualifier may be all there is
+                let text: String = msg.chars().skip(3).collect();
+                self.say(chat_type, &text).await?;
             }
             Err(_err) => {
-                self.queue_message(
-                    self.prepare_magic_effect(MagicEffect::Puff, self.player.position)
-                        .await?,
-                )
-                .await;
+                self.queue_message(ServerMessage::MagicEffect {
+                    effect: MagicEffect::Puff,
+                    position: self.player.position,
+                })
+                .await?;
             }
         }
         Ok(())
     }
 
-    async fn receive_comment<R: AsyncRead + Unpin>(&self, message: &mut R) -> Result<()> {
-        let mut msg = String::new();
-        unsafe { message.read_string_until_end(&mut msg).await? };
-
+    async fn receive_comment(&self, msg: &str) -> Result<()> {
         log::info!("Received comment from client: {msg}");
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_frame() -> Result<()> {
+        let limits = config::Connection::default();
+        let mut buffer = vec![0x02, 0x00, 0x14, 0x00, 0x03, 0x00, 0x65];
+
+        assert_eq!(take_frame(&mut buffer, &limits)?, Some(vec![0x14, 0x00]));
+        assert_eq!(take_frame(&mut buffer, &limits)?, None);
+        assert_eq!(buffer, vec![0x03, 0x00, 0x65]);
+
+        buffer.extend_from_slice(&[0x01, 0x02]);
+        assert_eq!(take_frame(&mut buffer, &limits)?, Some(vec![0x65, 0x01, 0x02]));
+        assert!(buffer.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_take_frame_above_limit() {
+        let mut limits = config::Connection::default();
+        limits.max_frame_sizes.insert(HeaderReceive::Chat, 8);
+
+        let mut buffer = vec![0x00, 0x01, 0x09, 0x00];
+        assert!(take_frame(&mut buffer, &limits).is_err());
+
+        let mut buffer = vec![0x00, 0x01, 0x05, 0x00];
+        assert_eq!(take_frame(&mut buffer, &limits).ok(), Some(None));
+
+        let mut buffer = vec![0xff, 0xff, 0x99];
+        assert_eq!(take_frame(&mut buffer, &limits).ok(), Some(None));
+        buffer.push(0x00);
+        assert!(take_frame(&mut buffer, &limits).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+//! Session recordings are JSON lines files, one entry per line, in the order the bytes went
+//! through the socket:
+//!
+//! `{"timestamp":1697040000123,"direction":"ClientToServer","protocol":650,"bytes":"0900..."}`
+//!
+//! * timestamp: milliseconds since the unix epoch
+//! * direction: `ClientToServer` or `ServerToClient`
+//! * protocol: negotiated protocol version, as the number sent by the client (e.g. 650)
+//! * bytes: exact bytes as lowercase hex, including the u16 length of every frame. Inbound
+//!   entries hold one frame each, outbound entries hold everything written by one flush, which
+//!   is several frames for clients that receive messages individually
+//! * messages: outbound entries only, the messages of the flush before being encoded
+use crate::{io::hex, network::message::server::ServerMessage, Protocol};
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    net::SocketAddr,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum RecordDirection {
+    ClientToServer,
+    ServerToClient,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordEntry {
+    pub timestamp: u64,
+    pub direction: RecordDirection,
+    pub protocol: u16,
+    pub bytes: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<ServerMessage>,
+}
+
+impl RecordEntry {
+    pub fn protocol(&self) -> Result<Protocol> {
+        Ok(self.protocol.try_into()?)
+    }
+
+    pub fn bytes(&self) -> Result<Vec<u8>> {
+        hex::decode(&self.bytes)
+    }
+}
+
+pub struct SessionRecorder {
+    file: BufWriter<File>,
+}
+
+impl SessionRecorder {
+    /// Creates a new recording file inside `directory`, named after the current time and the
+    /// address of the client
+    pub fn start(directory: &Path, peer_address: Option<SocketAddr>) -> Result<Self> {
+        std::fs::create_dir_all(directory)?;
+
+        let peer = peer_address
+            .map(|address| address.to_string().replace([':', '.', '[', ']'], "_"))
+            .unwrap_or_else(|| "unknown".to_string());
+        let path = directory.join(format!("session-{}-{peer}.jsonl", timestamp()));
+        log::info!("Recording session to {path:?}");
+
+        Ok(Self {
+            file: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    /// Starts a recorder if recording is enabled in the configuration
+    pub fn from_config(peer_address: Option<SocketAddr>) -> Result<Option<Self>> {
+        match crate::config::CONFIG.get().and_then(|config| config.recording.as_ref()) {
+            Some(recording) if recording.enabled => {
+                Ok(Some(Self::start(Path::new(&recording.directory), peer_address)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        direction: RecordDirection,
+        protocol: Protocol,
+        bytes: &[u8],
+    ) -> Result<()> {
+        self.record_messages(direction, protocol, bytes, vec![])
+    }
+
+    /// Records the bytes along with the messages they encode
+    pub fn record_messages(
+        &mut self,
+        direction: RecordDirection,
+        protocol: Protocol,
+        bytes: &[u8],
+        messages: Vec<ServerMessage>,
+    ) -> Result<()> {
+        let entry = RecordEntry {
+            timestamp: timestamp(),
+            direction,
+            protocol: protocol as u16,
+            bytes: hex::encode(bytes),
+            messages,
+        };
+
+        serde_json::to_writer(&mut self.file, &entry)?;
+        self.file.write_all(b"\n")?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads every entry of a JSON lines file, like recordings and discoveries
+pub fn read_json_lines<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            entries.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(entries)
+}
+
+pub(super) fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -1,804 +1,306 @@
-use super::Connection;
+use super::{recorder::RecordDirection, transport::Transport, Connection};
 use crate::{
     character::{
         player::{InventorySlot, Player},
-        CharacterUpdateType, Direction, HealthStatus, Outfit, OutfitColors, OutfitType,
+        CharacterUpdateType, Direction, Outfit,
     },
-    chat::{encoding, ChatType},
-    constants::*,
+    chat::ChatType,
+    config::SlowClientPolicy,
+    constants::{MagicEffect, ObjectUpdateType},
     io::WriteExt,
-    map::{position::Position, TileObject, MAP},
-    network::header::{AuxiliaryHeaderSend, HeaderSend},
-    Protocol,
+    map::position::Position,
+    network::message::server::{player_stack_pos, PlayerView, ServerMessage},
+    protocol::{Framing, MessageForms, SkillLayout},
+    world::message::CharacterView,
 };
 use anyhow::{anyhow, Error, Result};
 use std::{
     io::Cursor,
     net::SocketAddr
 };
-use tokio::io::AsyncWriteExt;
+use tokio::{io::AsyncWriteExt, sync::mpsc::error::TrySendError};
 
-impl Connection {
+impl<T: Transport> Connection<T> {
     pub async fn send_error(&mut self, err: Error) -> Result<()> {
         log::warn!("Sending error {err:?} to client");
-        self.queue_message(self.prepare_error(&err.to_string()).await?).await;
+        self.queue_message(ServerMessage::Error(err.to_string())).await?;
         self.flush_message_queue().await?;
         Ok(())
     }
 
-    async fn assemble_message(&self) -> Result<Vec<u8>> {
-        if !self.message_queue.is_empty() {
-            if self.protocol > Protocol::Tibia501 {
-                self.assemble_big_message().await
-            } else {
-                self.assemble_individual_messages().await
-            }
-        } else {
-            Ok(vec![])
+    async fn assemble_message(&self, messages: &[Vec<u8>]) -> Result<Vec<u8>> {
+        match self.protocol.capabilities().framing {
+            Framing::Bundled => assemble_big_message(messages).await,
+            Framing::Individual => assemble_individual_messages(messages).await,
         }
     }
 
+    /// Hands the queued messages to the writer task. If its queue is full, the frame is dropped
+    /// when the slow client policy allows it and nothing in it is essential, otherwise the
+    /// client is disconnected
     pub async fn flush_message_queue(&mut self) -> Result<()> {
-        let message = self.assemble_message().await?;
-        self.stream.write_all(&message).await?;
-        self.stream.flush().await?;
-        Ok(())
-    }
-
-    /// For older clients, sends each message individually. Sends length + actual message
-    /// for every queued message.
-    async fn assemble_individual_messages(&self) -> Result<Vec<u8>> {
-        let mut big_message = Cursor::new(vec![]);
-        while let Some(message) = self.message_queue.pop() {
-            big_message.write_u16_le(message.len() as u16 + 2).await?;
-            big_message.write_all(&message).await?;
-        }
-        Ok(big_message.into_inner())
-    }
-
-    /// For newer clients, send all queued messages as one, concatenating every message,
-    /// with just one length
-    async fn assemble_big_message(&self) -> Result<Vec<u8>> {
-        let mut big_message = Cursor::new(vec![]);
-        while let Some(message) = self.message_queue.pop() {
-            big_message.write_all(&message).await?;
-        }
-
-        let big_message = big_message.into_inner();
-        let mut final_message = Cursor::new(vec![]);
-        final_message.write_u16_le(big_message.len() as u16 + 2).await?;
-        final_message.write_all(&big_message).await?;
-        Ok(final_message.into_inner())
-    }
-
-    pub async fn queue_message(&self, message: Vec<u8>) {
-        if !message.is_empty() {
-            self.message_queue.push(message);
-        }
-    }
-
-    pub async fn prepare_info(&self, message: &str) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        buf.write_header(HeaderSend::Info, self.protocol).await?;
-        buf.write_null_terminated_string(message).await?;
-
-        Ok(buf.into_inner())
-    }
-
-    pub async fn prepare_error(&self, message: &str) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        buf.write_header(HeaderSend::Error, self.protocol).await?;
-        buf.write_null_terminated_string(message).await?;
-
-        Ok(buf.into_inner())
-    }
-
-    async fn prepare_login(&self) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        buf.write_header(HeaderSend::Login, self.protocol).await?;
-        if self.protocol >= Protocol::Tibia300 {
-            buf.write_u32_le(self.player.id).await?;
-        }
-
-        Ok(buf.into_inner())
-    }
-
-    pub async fn queue_login_info(&mut self) -> Result<()> {
-        let player_id = self.player.id;
-        let position = self.player.position;
-
-        if self.protocol == Protocol::Tibia103 {
-            self.queue_message(self.prepare_login().await?).await;
-
-            self.queue_message(
-                self.prepare_equipped_item(InventorySlot::Bag, 0x013d, 0)
-                    .await?,
-            )
-            .await;
-            self.queue_message(
-                self.prepare_equipped_item(InventorySlot::RightHand, 0x015a, 0)
-                    .await?,
-            )
-            .await;
-            self.queue_message(
-                self.prepare_equipped_item(InventorySlot::LeftHand, 0x025a, 0)
-                    .await?,
-            )
-            .await;
-
-            self.queue_message(self.prepare_map(self.player.position, 18, 14, 1).await?)
-                .await;
-            self.queue_message(self.prepare_status_message("Hello, World!").await?)
-                .await;
-            // self.queue_message(self.prepare_message_of_the_day(0x0101, "Hello, World!").await?).await;
-        } else {
-            self.queue_message(self.prepare_login().await?).await;
-            self.queue_message(self.prepare_stats().await?).await;
-            if self.protocol >= Protocol::Tibia400 {
-                self.queue_message(self.prepare_skills().await?).await;
-            }
-            self.queue_message(
-                self.prepare_equipped_item(InventorySlot::Helmet, 0x005c, 0)
-                    .await?,
-            )
-            .await;
-            self.queue_message(
-                self.prepare_equipped_item(InventorySlot::Necklace, 0x007b, 0)
-                    .await?,
-            )
-            .await;
-            self.queue_message(
-                self.prepare_equipped_item(InventorySlot::Bag, 0x013d, 0)
-                    .await?,
-            )
-            .await;
-            self.queue_message(
-                self.prepare_equipped_item(InventorySlot::Armor, 0x007a, 0)
-                    .await?,
-            )
-            .await;
-            self.queue_message(
-                self.prepare_equipped_item(InventorySlot::LeftHand, 0x085d, 0)
-                    .await?,
-            )
-            .await;
-            self.queue_message(
-                self.prepare_equipped_item(InventorySlot::RightHand, 0x065a, 0)
-                    .await?,
-            )
-            .await;
-            self.queue_message(
-                self.prepare_equipped_item(InventorySlot::Legs, 0x0079, 0)
-                    .await?,
-            )
-            .await;
-            self.queue_message(
-                self.prepare_equipped_item(InventorySlot::Boots, 0x0378, 0)
-                    .await?,
-            )
-            .await;
-
-            self.queue_message(self.prepare_map(self.player.position, 18, 14, 3).await?)
-                .await;
-            self.queue_message(
-                self.prepare_update_character(player_id, CharacterUpdateType::LightLevel, 0)
-                    .await?,
-            )
-            .await;
-            self.queue_message(
-                self.prepare_magic_effect(MagicEffect::Teleport, position)
-                    .await?,
-            )
-            .await;
-            self.queue_message(self.prepare_world_light(6).await?).await;
-            self.queue_message(self.prepare_status_message("Hello, World!").await?)
-                .await;
-            // self.queue_message(self.prepare_message_of_the_day(0x0101, "Hello, World!").await?).await;
-        }
-
-        Ok(())
-    }
-
-    pub async fn prepare_world_light(&self, light_level: u8) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        buf.write_header(HeaderSend::WorldLight, self.protocol)
-            .await?;
-        buf.write_u8(light_level).await?;
-
-        Ok(buf.into_inner())
-    }
-
-    pub async fn prepare_update_outfit(
-        &self,
-        id: u32,
-        outfit: OutfitType,
-        outfit_colors: OutfitColors,
-    ) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        if self.protocol == Protocol::Tibia103 {
-            // buf.write_header(HeaderSend::UpdateObject, self.protocol).await?;
-            // buf.write_position(self.player.position, self.protocol).await?;
-            // buf.write_u8(ObjectUpdateType::Update as u8).await?;
-        } else {
-            buf.write_header(HeaderSend::UpdateCharacter, self.protocol)
-                .await?;
-            buf.write_u32_le(id).await?;
-            buf.write_u8(CharacterUpdateType::Outfit as u8).await?;
-            buf.write_u8(outfit as u8).await?;
-            buf.write_outfit_colors(outfit_colors).await?;
-        }
-
-        Ok(buf.into_inner())
-    }
-
-    pub async fn prepare_update_character(
-        &self,
-        id: u32,
-        update_type: CharacterUpdateType,
-        value: u8,
-    ) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        buf.write_header(HeaderSend::UpdateCharacter, self.protocol)
-            .await?;
-        buf.write_u32_le(id).await?;
-        buf.write_u8(update_type as u8).await?;
-        buf.write_u8(value).await?;
-
-        Ok(buf.into_inner())
-    }
-
-    pub async fn prepare_update_object(
-        &self,
-        position: Position,
-        update_type: ObjectUpdateType,
-        stack_pos: u8,
-    ) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        if self.protocol == Protocol::Tibia103 {
-            // buf.write_header(HeaderSend::UpdateObject, self.protocol).await?;
-            // buf.write_position(position, self.protocol).await?;
-            // buf.write_u8(update_type.to_protocol_103_type() as u8).await?;
-            // buf.write_u8(stack_pos).await?;
-        } else {
-            buf.write_header(HeaderSend::UpdateObject, self.protocol)
-                .await?;
-            buf.write_position(position, self.protocol).await?;
-            buf.write_u8(update_type as u8).await?;
-            buf.write_u8(stack_pos).await?;
-
-            //remove light?
-            if update_type == ObjectUpdateType::Remove {
-                buf.write_zeroes(6).await?;
-            }
+        let mut messages = vec![];
+        let mut encoded = vec![];
+        while let Some((message, bytes)) = self.message_queue.pop() {
+            messages.push(message);
+            encoded.push(bytes);
         }
-
-        Ok(buf.into_inner())
-    }
-
-    pub async fn prepare_magic_effect(
-        &self,
-        effect: MagicEffect,
-        position: Position,
-    ) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        buf.write_header(HeaderSend::MagicEffect, self.protocol)
-            .await?;
-        buf.write_position(position, self.protocol).await?;
-        buf.write_u8(effect as u8).await?;
-
-        Ok(buf.into_inner())
-    }
-
-    pub async fn prepare_skills(&self) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        let skills = self.player.skills;
-
-        buf.write_header(HeaderSend::Skills, self.protocol).await?;
-        if self.protocol == Protocol::Tibia400 {
-            buf.write_u8(skills.sword).await?;
-            buf.write_u8(skills.club).await?;
-            buf.write_u8(skills.gauche).await?;
-            buf.write_u8(skills.fist).await?;
-            buf.write_u8(skills.missile).await?;
-            buf.write_u8(skills.shield).await?;
-            buf.write_u8(skills.distance).await?; //throwing on v4
-            buf.write_u8(skills.fishing).await?;
-        } else {
-            buf.write_u8(skills.sword).await?;
-            buf.write_u8(skills.club).await?;
-            buf.write_u8(skills.axe).await?;
-            buf.write_u8(skills.distance).await?;
-            buf.write_u8(skills.shield).await?;
-            buf.write_u8(skills.fist).await?;
-            buf.write_u8(skills.fishing).await?;
+        if messages.is_empty() {
+            return Ok(());
         }
 
-        Ok(buf.into_inner())
-    }
-
-    async fn prepare_map(
-        &self,
-        position: Position,
-        width: u16,
-        height: u16,
-        layers: u8,
-    ) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        buf.write_header(HeaderSend::Map, self.protocol).await?;
-        buf.write_position(self.player.position, self.protocol)
-            .await?;
-        buf.write_all(
-            self.prepare_map_internal(position, width, height, layers)
-                .await?
-                .as_slice(),
-        )
-        .await?;
-
-        Ok(buf.into_inner())
-    }
-
-    async fn prepare_map_internal(
-        &self,
-        position: Position,
-        width: u16,
-        height: u16,
-        layers: u8,
-    ) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        let corner = position - (((width as i16 - 1) / 2), ((height as i16 - 1) / 2), 0);
-        let corner_2 = corner + (width as i16 - 1, height as i16 - 1, layers as i8 - 1);
-
-        log::trace!(
-            "center = {position:?}, corner_1 = {corner:?}, corner_2 = {corner_2:?}"
-        );
-        log::trace!(
-            "width = {width:?}, height={height:?}, layers={layers:?}"
-        );
-
-        for z in 0..layers {
-            let position = Position::new(position.x, position.y, z);
-            buf.write_all(&self.prepare_layer(position, corner, width, height).await?)
-                .await?;
-        }
-        buf.set_position(buf.position() - 1);
-        buf.write_u8(0xfe).await?;
-        buf.write_u8(0x00).await?;
-
-        // log::trace!("MAP = {:02x?}", buf);
-
-        Ok(buf.into_inner())
-    }
-
-    async fn prepare_layer(
-        &self,
-        position: Position,
-        corner: Position,
-        width: u16,
-        height: u16,
-    ) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        for x in 0..width {
-            for y in 0..height {
-                let position = corner + (x as i16, y as i16, position.z as i8);
-                buf.write_all(&self.prepare_tile(position).await?).await?;
+        let message = self.assemble_message(&encoded).await?;
+        let permit = match self.outbound.try_reserve() {
+            Ok(permit) => permit,
+            Err(TrySendError::Full(()))
+                if self.slow_client_policy == SlowClientPolicy::DropNonEssential
+                    && !messages.iter().any(ServerMessage::is_essential) =>
+            {
+                log::debug!("Client is not keeping up, dropping {messages:?}");
+                return Ok(());
             }
-        }
-
-        Ok(buf.into_inner())
-    }
-
-    async fn prepare_tile(&self, position: Position) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-        if let Some(tile) = MAP.get().unwrap().get_tile_objects(position) {
-            for tile_object in tile {
-                match tile_object {
-                    TileObject::Other(tile_id) => buf.write_u16_le(*tile_id).await?,
-                    TileObject::FluidContainer(tile_id, fluid) => {
-                        buf.write_u16_le(*tile_id).await?;
-                        if self.protocol >= Protocol::Tibia300 {
-                            buf.write_u8(*fluid as u8).await?;
-                        }
-                    }
-                    TileObject::LightSource(tile_id, light_level) => {
-                        buf.write_u16_le(*tile_id).await?;
-                        if self.protocol >= Protocol::Tibia300 {
-                            buf.write_u8(*light_level).await?;
-                        }
-                    }
-                    TileObject::Stackable(tile_id, count) => {
-                        buf.write_u16_le(*tile_id).await?;
-                        if self.protocol >= Protocol::Tibia300 {
-                            buf.write_u8(*count).await?;
-                        }
-                    }
-                    TileObject::Creature(id, name, outfit) => {
-                        if self.protocol >= Protocol::Tibia300 {
-                            buf.write_all(&self.prepare_character(*id, name, *outfit).await?)
-                                .await?;
-                        }
-                    }
-                }
+            Err(TrySendError::Full(())) => {
+                return Err(anyhow!("Client is not keeping up, outbound queue is full"))
             }
+            Err(TrySendError::Closed(())) => return Err(anyhow!("Connection writer finished")),
+        };
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record_messages(
+                RecordDirection::ServerToClient,
+                self.protocol,
+                &message,
+                messages,
+            )?;
         }
 
-        if position == self.player.position {
-            buf.write_all(&self.prepare_player_character().await?)
-                .await?;
-        }
-
-        if self.protocol == Protocol::Tibia103 {
-            buf.write_u8(0xff).await?;
-        }
-
-        buf.write_u8(0xff).await?;
-        Ok(buf.into_inner())
-    }
-
-    async fn prepare_player_character(&self) -> Result<Vec<u8>> {
-        if self.protocol == Protocol::Tibia103 {
-            let mut buf = Cursor::new(vec![]);
-            buf.write_u8(AuxiliaryHeaderSend::Character as u8).await?;
-            buf.write_outfit_colors(self.player.outfit).await?;
-            Ok(buf.into_inner())
-        } else {
-            self.prepare_character(
-                self.player.id,
-                &self.player.name,
-                Outfit::human(self.player.outfit),
-            )
-            .await
-        }
-    }
-
-    async fn prepare_character(&self, id: u32, name: &str, outfit: Outfit) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        buf.write_u8(AuxiliaryHeaderSend::Character as u8).await?;
-        buf.write_u32_le(0).await?; //knows creature
-        buf.write_u32_le(id).await?;
-        buf.write_string_with_fixed_length(name, 30).await?;
-        buf.write_u8(HealthStatus::Healthy as u8).await?;
-        buf.write_u8(Direction::South as u8).await?;
+        permit.send(message);
 
-        buf.write_u8(outfit.outfit_type as u8).await?;
-        buf.write_outfit_colors(outfit.colors).await?;
-
-        //light level=0
-        buf.write_u8(0).await?;
-
-        Ok(buf.into_inner())
+        Ok(())
     }
 
-    pub async fn prepare_status_message(&self, status: &str) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        buf.write_header(HeaderSend::StatusMessage, self.protocol)
-            .await?;
-        buf.write_null_terminated_string(status).await?;
-
-        Ok(buf.into_inner())
+    /// Whether there is no room for another frame in the outbound queue
+    pub(super) fn is_outbound_full(&self) -> bool {
+        self.outbound.capacity() == 0
     }
 
-    pub async fn prepare_message_of_the_day(
-        &self,
-        message_number: u16,
-        message: &str,
-    ) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        buf.write_header(HeaderSend::MessageOfTheDay, self.protocol)
-            .await?;
-        if self.protocol > Protocol::Tibia400 {
-            buf.write_u16_le(message_number).await?;
-            buf.write_u8(0x0a).await?;
+    /// Encodes the message for the client and queues it until the next flush. Messages not
+    /// supported by the protocol fail here, messages that encode to nothing are skipped.
+    pub async fn queue_message(&self, message: ServerMessage) -> Result<()> {
+        let bytes = message.encode(self.protocol).await?;
+        if !bytes.is_empty() {
+            self.message_queue.push((message, bytes));
         }
-
-        buf.write_null_terminated_string(message).await?;
-
-        Ok(buf.into_inner())
+        Ok(())
     }
 
-    pub async fn prepare_equipped_item(
-        &self,
-        slot: InventorySlot,
-        item: u16,
-        stack: u8,
-    ) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        buf.write_header(HeaderSend::EquippedItem, self.protocol)
-            .await?;
-        if self.protocol == Protocol::Tibia103 {
-            buf.write_u16_le(item).await?;
-            buf.write_u8(slot as u8).await?;
-        } else {
-            buf.write_u8(slot as u8).await?;
-            buf.write_u16_le(item).await?;
-            buf.write_u8(stack).await?;
+    /// The connected player, as drawn in map descriptions
+    pub(super) fn player_view(&self) -> PlayerView {
+        PlayerView {
+            id: self.player.id,
+            name: self.player.name.clone(),
+            position: self.player.position,
+            outfit: self.player.outfit,
         }
-
-        Ok(buf.into_inner())
     }
 
-    pub async fn prepare_stats(&self) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        let stats = self.player.stats;
-        buf.write_header(HeaderSend::Stats, self.protocol).await?;
-
-        buf.write_u16_le(stats.health_points).await?;
-        buf.write_u16_le(stats.capacity).await?;
-        if self.protocol >= Protocol::Tibia400 {
-            buf.write_u32_le(stats.experience_points).await?;
-            buf.write_u8(stats.experience_level).await?;
-            buf.write_u16_le(stats.mana_points).await?;
-            buf.write_u8(stats.magic_level).await?;
-            buf.write_u16_le(stats.ammunition).await?;
-        } else if self.protocol >= Protocol::Tibia300 {
-            buf.write_u8(stats.intelligence).await?;
-            buf.write_u8(stats.strength).await?;
-            buf.write_u8(stats.dexterity).await?;
-            buf.write_u16_le(stats.experience_points as u16).await?;
-            buf.write_u8(stats.experience_level).await?;
+    /// A character drawn on the tile, as another player sees it appear
+    pub(super) fn character_appeared(&self, character: CharacterView) -> ServerMessage {
+        ServerMessage::Compound(vec![
+            ServerMessage::UpdateObject {
+                position: character.position,
+                update_type: ObjectUpdateType::Add,
+                stack_pos: player_stack_pos(self.protocol, character.position),
+            },
+            ServerMessage::Character {
+                id: character.id,
+                name: character.name,
+                direction: character.direction,
+                outfit: Outfit::human(character.outfit),
+            },
+        ])
+    }
+
+    /// A character removed from the tile, as another player sees it disappear
+    pub(super) fn character_disappeared(&self, position: Position) -> ServerMessage {
+        ServerMessage::UpdateObject {
+            position,
+            update_type: ObjectUpdateType::Remove,
+            stack_pos: player_stack_pos(self.protocol, position),
         }
-
-        Ok(buf.into_inner())
     }
 
-    pub async fn prepare_chat(
+    /// The character leaving its tile and added to the next one, facing where it walked to
+    pub(super) fn character_moved(&self, id: u32, from: Position, to: Position, direction: Direction) -> [ServerMessage; 2] {
+        [
+            self.character_disappeared(from),
+            ServerMessage::Compound(vec![
+                ServerMessage::UpdateObject {
+                    position: to,
+                    update_type: ObjectUpdateType::Add,
+                    stack_pos: player_stack_pos(self.protocol, to),
+                },
+                ServerMessage::ChangeDirection { id, direction },
+            ]),
+        ]
+    }
+
+    /// The character updated on its tile, facing the direction
+    pub(super) fn character_turned(&self, id: u32, position: Position, direction: Direction) -> ServerMessage {
+        ServerMessage::Compound(vec![
+            ServerMessage::UpdateObject {
+                position,
+                update_type: ObjectUpdateType::Update,
+                stack_pos: player_stack_pos(self.protocol, position),
+            },
+            ServerMessage::ChangeDirection { id, direction },
+        ])
+    }
+
+    pub(super) fn chat_message(
         &self,
         chat_type: ChatType,
-        msg: &str,
-        sender: Option<&Player>,
-        position: Option<Position>,
-    ) -> Result<Vec<u8>> {
-        let msg = match chat_type {
-            ChatType::Yell => encoding::translate_upper(&msg.to_uppercase()),
-            _ => encoding::translate(msg),
-        };
-        self.prepare_raw_chat(chat_type, &msg, sender, position)
-            .await
-    }
-
-    async fn prepare_raw_chat(
-        &self,
-        chat_type: ChatType,
-        msg: &[u8],
+        message: &str,
         sender: Option<&Player>,
         position: Option<Position>,
-    ) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        let position = match self.protocol {
-            Protocol::Tibia300 => position.map(|p| p + (1, 1, 0)),
-            _ => position,
-        };
-
-        buf.write_header(HeaderSend::Chat, self.protocol).await?;
-        buf.write_position(position.unwrap_or(Position::new(0, 0, 0)), self.protocol)
-            .await?;
-        buf.write_u8(chat_type as u8).await?;
-        if let Some(player) = sender {
-            buf.write_all(player.name.as_bytes()).await?;
-            buf.write_u8(0x09).await?; //TAB
+    ) -> ServerMessage {
+        ServerMessage::Chat {
+            chat_type,
+            message: message.to_string(),
+            sender: sender.map(|player| player.name.clone()),
+            position,
         }
-        buf.write_all(msg).await?;
-        buf.write_u8(0x00).await?;
-
-        Ok(buf.into_inner())
     }
 
-    pub async fn prepare_user_info(&self, player_name: &str) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        buf.write_header(HeaderSend::UserInfo, self.protocol)
-            .await?;
-        buf.write_u16_le(0x1010).await?; //# of bytes to allocate for text
-        let info = &format!("INFO: name={player_name}");
-        buf.write_null_terminated_string(info).await?;
-
-        Ok(buf.into_inner())
-    }
-
-    pub async fn prepare_user_list(&self) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        buf.write_header(HeaderSend::UserList, self.protocol)
-            .await?;
-        buf.write_u16_le(0x1010).await?; //# of bytes to allocate for text
-
-        //for each player online
-        {
-            buf.write_all(self.player.name.as_bytes()).await?;
-            buf.write_u8(b'\n').await?;
+    /// Message from the server itself, said by the player on clients without a chat type for it
+    pub(super) fn server_chat_message(&self, message: &str) -> ServerMessage {
+        match self.protocol.capabilities().server_chat {
+            Some(chat_type) => self.chat_message(chat_type, message, None, Some(self.player.position)),
+            None => self.chat_message(
+                ChatType::Normal,
+                message,
+                Some(&self.player),
+                Some(self.player.position),
+            ),
         }
-
-        buf.write_u8(0).await?;
-
-        Ok(buf.into_inner())
     }
 
-    pub async fn prepare_data_window(&self) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        buf.write_header(HeaderSend::DataWindow, self.protocol)
-            .await?;
-        buf.write_string_with_fixed_length(&self.player.name, 30)
-            .await?;
+    pub async fn queue_login_info(&mut self) -> Result<()> {
+        let player_id = self.player.id;
+        let position = self.player.position;
 
-        if self.protocol <= Protocol::Tibia501 {
-            buf.write_string_with_fixed_length("password", 30).await?;
-            buf.write_gender(self.player.gender, self.protocol).await?;
-            buf.write_outfit_colors(self.player.outfit).await?;
-            buf.write_string_with_fixed_length("realname", 50).await?;
-            buf.write_string_with_fixed_length("location", 50).await?;
-            buf.write_string_with_fixed_length("email", 50).await?;
-            if self.protocol >= Protocol::Tibia400 {
-                buf.write_string_with_fixed_length("comment", 500).await?;
+        if self.protocol.capabilities().forms == MessageForms::Short {
+            self.queue_message(ServerMessage::Login { player_id }).await?;
+
+            for (slot, item) in [
+                (InventorySlot::Bag, 0x013d),
+                (InventorySlot::RightHand, 0x015a),
+                (InventorySlot::LeftHand, 0x025a),
+            ] {
+                self.queue_message(ServerMessage::EquippedItem {
+                    slot,
+                    item,
+                    stack: 0,
+                })
+                .await?;
             }
-        } else {
-            buf.write_gender(self.player.gender, self.protocol).await?;
-            buf.write_outfit_colors(self.player.outfit).await?;
-        }
-
-        Ok(buf.into_inner())
-    }
 
-    pub async fn prepare_open_container(&self) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        buf.write_header(HeaderSend::OpenContainer, self.protocol)
+            self.queue_message(ServerMessage::Map {
+                player: self.player_view(),
+            })
             .await?;
-        buf.write_u8(1).await?; //local_id
-        buf.write_u16_le(0x013d).await?; //item_id
-
-        //Add each item inside container
-        //TODO send non hardcoded items
-        for _ in 0..5 {
-            buf.write_u16_le(0x005a).await?; //item_id
-        }
-
-        buf.write_u16_le(0xffff).await?;
-
-        Ok(buf.into_inner())
-    }
-
-    pub async fn prepare_close_container(&self, local_id: u8) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        buf.write_header(HeaderSend::CloseContainer, self.protocol)
-            .await?;
-        buf.write_u8(local_id).await?;
-
-        Ok(buf.into_inner())
-    }
-
-    pub async fn prepare_change_direction(&self, id: u32, direction: Direction) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        if self.protocol == Protocol::Tibia103 {
-        } else {
-            buf.write_u8(AuxiliaryHeaderSend::ChangeDirection as u8)
+            self.queue_message(ServerMessage::StatusMessage("Hello, World!".to_string()))
                 .await?;
-            buf.write_u8(direction as u8).await?;
-            buf.write_u32_le(id).await?;
-        }
-
-        Ok(buf.into_inner())
-    }
-
-    pub async fn prepare_move_character(
-        &self,
-        direction: Direction,
-        from: Position,
-        to: Position,
-    ) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        log::trace!(
-            "move character from {from:?} to {to:?}, direction={direction:?}"
-        );
-
-        let (width, height) = match direction {
-            Direction::North | Direction::South => (18, 1),
-            Direction::East | Direction::West => (1, 14),
-        };
-        let center = to
-            + match direction {
-                Direction::North => (0, -6, 0),
-                Direction::East => (9, 0, 0),
-                Direction::South => (0, 7, 0),
-                Direction::West => (-8, 0, 0),
-            };
-        let layers = if self.protocol == Protocol::Tibia103 {
-            1
+            // self.queue_message(ServerMessage::MessageOfTheDay { number: 0x0101, message: "Hello, World!".to_string() }).await?;
         } else {
-            3
-        };
-
-        log::trace!("center = {center:?}");
-
-        buf.write_header(direction.into(), self.protocol).await?;
-        buf.write_all(
-            self.prepare_map_internal(center, width, height, layers)
-                .await?
-                .as_slice(),
-        )
-        .await?;
-
-        Ok(buf.into_inner())
-    }
+            self.queue_message(ServerMessage::Login { player_id }).await?;
+            self.queue_message(ServerMessage::Stats(self.player.stats)).await?;
+            if self.protocol.capabilities().skills != SkillLayout::None {
+                self.queue_message(ServerMessage::Skills(self.player.skills))
+                    .await?;
+            }
+            for (slot, item) in [
+                (InventorySlot::Helmet, 0x005c),
+                (InventorySlot::Necklace, 0x007b),
+                (InventorySlot::Bag, 0x013d),
+                (InventorySlot::Armor, 0x007a),
+                (InventorySlot::LeftHand, 0x085d),
+                (InventorySlot::RightHand, 0x065a),
+                (InventorySlot::Legs, 0x0079),
+                (InventorySlot::Boots, 0x0378),
+            ] {
+                self.queue_message(ServerMessage::EquippedItem {
+                    slot,
+                    item,
+                    stack: 0,
+                })
+                .await?;
+            }
 
-    pub async fn prepare_green_chat(
-        &self,
-        _chat_type: ChatType,
-        msg: &str,
-        _sender: Option<&Player>,
-        _position: Option<Position>,
-    ) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-
-        // let position = match self.protocol {
-        //     Protocol::Tibia300 => position.map(|p| p + (1,1,0)),
-        //     _ => position
-        // };
-
-        buf.write_header(HeaderSend::GreenChat, self.protocol)
+            self.queue_message(ServerMessage::Map {
+                player: self.player_view(),
+            })
             .await?;
-        // buf.write_position(position.unwrap_or(Position::new(0, 0, 0)), self.protocol).await?;
-        // buf.write_u8(chat_type as u8).await?;
-        // if let Some(player) = sender {
-        //     buf.write_all(player.name.as_bytes()).await?;
-        //     buf.write_u8(0x09).await?;//TAB
-        // }
-        buf.write_null_terminated_string(msg).await?;
-
-        Ok(buf.into_inner())
-    }
-
-    pub async fn prepare_unknown_0x0000(&self) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-        buf.write_header(HeaderSend::Unknown0x0000, self.protocol)
+            self.queue_message(ServerMessage::UpdateCharacter {
+                id: player_id,
+                update_type: CharacterUpdateType::LightLevel,
+                value: 0,
+            })
             .await?;
-
-        Ok(buf.into_inner())
-    }
-
-    pub async fn prepare_unknown_0x000f(&self) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-        buf.write_header(HeaderSend::Unknown0x000f, self.protocol)
+            self.queue_message(ServerMessage::MagicEffect {
+                effect: MagicEffect::Teleport,
+                position,
+            })
             .await?;
+            self.queue_message(ServerMessage::WorldLight(6)).await?;
+            self.queue_message(ServerMessage::StatusMessage("Hello, World!".to_string()))
+                .await?;
+            // self.queue_message(ServerMessage::MessageOfTheDay { number: 0x0101, message: "Hello, World!".to_string() }).await?;
+        }
 
-        Ok(buf.into_inner())
+        Ok(())
     }
+}
 
-    pub async fn prepare_unknown_0x0033(&self) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-        buf.write_header(HeaderSend::Unknown0x0033, self.protocol)
-            .await?;
+/// Largest message a frame can hold, as its length counts itself
+const MAX_FRAME_CONTENT: usize = u16::MAX as usize - 2;
 
-        Ok(buf.into_inner())
+/// For older clients, sends each message individually. Sends length + actual message
+/// for every queued message.
+async fn assemble_individual_messages(messages: &[Vec<u8>]) -> Result<Vec<u8>> {
+    let mut big_message = Cursor::new(vec![]);
+    for message in messages {
+        write_frame(&mut big_message, message).await?;
     }
+    Ok(big_message.into_inner())
+}
 
-    pub async fn prepare_unknown_0x0034(&self) -> Result<Vec<u8>> {
-        let mut buf = Cursor::new(vec![]);
-        buf.write_header(HeaderSend::Unknown0x0034, self.protocol)
-            .await?;
-
-        Ok(buf.into_inner())
+/// For newer clients, send all queued messages as one, concatenating every message,
+/// with just one length. Messages that don't fit continue in a new frame.
+async fn assemble_big_message(messages: &[Vec<u8>]) -> Result<Vec<u8>> {
+    let mut final_message = Cursor::new(vec![]);
+    let mut big_message = vec![];
+    for message in messages {
+        if !big_message.is_empty() && big_message.len() + message.len() > MAX_FRAME_CONTENT {
+            write_frame(&mut final_message, &big_message).await?;
+            big_message.clear();
+        }
+        big_message.extend_from_slice(message);
     }
+    write_frame(&mut final_message, &big_message).await?;
+    Ok(final_message.into_inner())
+}
+
+/// Writes the length and the message, which must fit in one frame
+async fn write_frame(buf: &mut Cursor<Vec<u8>>, message: &[u8]) -> Result<()> {
+    if message.len() > MAX_FRAME_CONTENT {
+        return Err(anyhow!(
+            "Message of {} bytes does not fit in a frame of up to {MAX_FRAME_CONTENT} bytes",
+            message.len()
+        ));
+    }
+    buf.write_u16_le(message.len() as u16 + 2).await?;
+    buf.write_all(message).await?;
+    Ok(())
 }
 
 pub async fn prepare_character_list(server_address: SocketAddr) -> Result<Vec<u8>> {
@@ -829,3 +331,22 @@ pub async fn prepare_character_list(server_address: SocketAddr) -> Result<Vec<u8
         SocketAddr::V6(_) => Err(anyhow!("Game does not support ipv6")),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_assemble_big_message_splits_frames() -> Result<()> {
+        let messages = vec![vec![1; 40_000], vec![2; 20_000], vec![3; 10_000]];
+
+        let bytes = assemble_big_message(&messages).await?;
+        assert_eq!(bytes.len(), 40_000 + 20_000 + 10_000 + 4);
+        assert_eq!(&bytes[..2], &(60_000_u16 + 2).to_le_bytes());
+        assert_eq!(&bytes[60_002..60_004], &(10_000_u16 + 2).to_le_bytes());
+
+        assert!(assemble_big_message(&[vec![0; MAX_FRAME_CONTENT + 1]]).await.is_err());
+        assert!(assemble_individual_messages(&[vec![0; MAX_FRAME_CONTENT + 1]]).await.is_err());
+        Ok(())
+    }
+}
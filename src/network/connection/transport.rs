@@ -0,0 +1,90 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf},
+    net::TcpStream,
+};
+
+/// Byte stream a `Connection` talks to the client through
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    /// Address of the client
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Address the client connected to, sent in the character list so the client can connect
+    /// again with the chosen character
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Transport for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+}
+
+/// In-memory transport, with fixed addresses, for running sessions without sockets
+pub struct MemoryTransport {
+    stream: DuplexStream,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+}
+
+impl MemoryTransport {
+    /// Creates the server side transport and the client side stream connected to it
+    pub fn pair(local_addr: SocketAddr, peer_addr: SocketAddr) -> (Self, DuplexStream) {
+        let (server, client) = tokio::io::duplex(64 * 1024);
+        (
+            Self {
+                stream: server,
+                local_addr,
+                peer_addr,
+            },
+            client,
+        )
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl AsyncRead for MemoryTransport {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MemoryTransport {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,541 @@
+use crate::{
+    character::{Gender, OutfitColors},
+    io::{hex, ReadExt},
+    map::position::Position,
+    network::{
+        connection::recorder::{self, RecordDirection, RecordEntry},
+        header::{AuxiliaryHeaderSend, HeaderReceive, HeaderSend},
+        layout::{self, Condition, Field, FieldType, LoginMessage},
+    },
+    protocol::HeaderFormat,
+    Protocol,
+};
+use anyhow::{anyhow, Result};
+use std::{
+    fmt::Display,
+    io::Cursor,
+    net::Ipv4Addr,
+    path::Path,
+};
+use tokio::io::AsyncReadExt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Number(u32),
+    Position(Position),
+    Outfit(OutfitColors),
+    Gender(Gender),
+    Text(String),
+    Ipv4(Ipv4Addr),
+    Items(Vec<u16>),
+    Map(Vec<u8>),
+    List(Vec<Vec<DecodedField>>),
+    Bytes(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedField {
+    pub name: &'static str,
+    pub value: FieldValue,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MessageKind {
+    Server(HeaderSend),
+    Auxiliary(AuxiliaryHeaderSend),
+    Client(HeaderReceive),
+    Login(LoginMessage),
+    CharacterList,
+    Unknown(u16),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedMessage {
+    pub kind: MessageKind,
+    pub fields: Vec<DecodedField>,
+    /// Bytes left undecoded, because the header or the layout of the message is unknown
+    pub remainder: Vec<u8>,
+}
+
+impl DecodedMessage {
+    pub fn field(&self, name: &str) -> Option<&FieldValue> {
+        self.fields
+            .iter()
+            .find(|field| field.name == name)
+            .map(|field| &field.value)
+    }
+
+    pub fn number(&self, name: &str) -> Option<u32> {
+        match self.field(name) {
+            Some(FieldValue::Number(number)) => Some(*number),
+            _ => None,
+        }
+    }
+
+    pub fn text(&self, name: &str) -> Option<&str> {
+        match self.field(name) {
+            Some(FieldValue::Text(text)) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+/// Splits a stream of server bytes into frames. Unlike the ones sent by the client, the u16
+/// length of the frames sent by the server counts its own 2 bytes
+pub fn split_server_frames(mut bytes: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut frames = vec![];
+    while !bytes.is_empty() {
+        if bytes.len() < 2 {
+            return Err(anyhow!("Incomplete frame length: {bytes:02x?}"));
+        }
+        let length = u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+        if length < 2 || bytes.len() < length {
+            return Err(anyhow!(
+                "Incomplete frame: expected {length} bytes, found {}",
+                bytes.len()
+            ));
+        }
+        frames.push(&bytes[..length]);
+        bytes = &bytes[length..];
+    }
+    Ok(frames)
+}
+
+/// Splits a stream of client bytes into the frames without their u16 lengths
+pub fn split_client_frames(mut bytes: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut frames = vec![];
+    while !bytes.is_empty() {
+        if bytes.len() < 2 {
+            return Err(anyhow!("Incomplete frame length: {bytes:02x?}"));
+        }
+        let length = u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+        if bytes.len() < 2 + length {
+            return Err(anyhow!(
+                "Incomplete frame: expected {length} bytes, found {}",
+                bytes.len() - 2
+            ));
+        }
+        frames.push(&bytes[2..2 + length]);
+        bytes = &bytes[2 + length..];
+    }
+    Ok(frames)
+}
+
+/// Decodes every message of a stream of frames sent by the server
+pub async fn decode_server_stream(bytes: &[u8], protocol: Protocol) -> Result<Vec<DecodedMessage>> {
+    let mut messages = vec![];
+    for frame in split_server_frames(bytes)? {
+        messages.extend(decode_server_frame(&frame[2..], protocol).await?);
+    }
+    Ok(messages)
+}
+
+/// Decodes the messages of a frame sent by the server, without its length. Clients up to 5.01
+/// receive one message per frame (plus its auxiliary messages), newer clients receive all
+/// messages of a flush in the same frame.
+pub async fn decode_server_frame(frame: &[u8], protocol: Protocol) -> Result<Vec<DecodedMessage>> {
+    let mut messages = vec![];
+    let mut cursor = Cursor::new(frame);
+    while (cursor.position() as usize) < frame.len() {
+        let code = match protocol.capabilities().header {
+            HeaderFormat::U16 => match frame[cursor.position() as usize] {
+                byte @ (0xfa | 0xfb) => {
+                    cursor.read_u8().await?;
+                    byte as u16
+                }
+                _ => cursor.read_u16_le().await?,
+            },
+            HeaderFormat::U8 => cursor.read_u8().await? as u16,
+            HeaderFormat::U8WithPrefix => {
+                cursor.skip(4).await?;
+                cursor.read_u8().await? as u16
+            }
+        };
+
+        let auxiliary = u8::try_from(code)
+            .ok()
+            .and_then(|code| AuxiliaryHeaderSend::try_from(code).ok());
+
+        let (kind, layout) = if let Some(header) = auxiliary {
+            (
+                MessageKind::Auxiliary(header),
+                layout::auxiliary_message_layout(header, protocol),
+            )
+        } else if let Ok(header) = HeaderSend::try_from(code) {
+            (
+                MessageKind::Server(header),
+                layout::server_message_layout(header, protocol),
+            )
+        } else {
+            (MessageKind::Unknown(code), None)
+        };
+
+        match layout {
+            Some(layout) => {
+                let fields = decode_fields(&mut cursor, &layout, protocol).await?;
+                messages.push(DecodedMessage {
+                    kind,
+                    fields,
+                    remainder: vec![],
+                });
+            }
+            None => {
+                messages.push(DecodedMessage {
+                    kind,
+                    fields: vec![],
+                    remainder: frame[cursor.position() as usize..].to_vec(),
+                });
+                break;
+            }
+        }
+    }
+
+    Ok(messages)
+}
+
+/// Decodes a frame sent by the client after the login, without its length
+pub async fn decode_client_frame(frame: &[u8], protocol: Protocol) -> Result<DecodedMessage> {
+    let mut cursor = Cursor::new(frame);
+    let code = cursor.read_u16_le().await?;
+    let (kind, layout) = match HeaderReceive::try_from(code) {
+        Ok(header) => (
+            MessageKind::Client(header),
+            layout::client_message_layout(header, protocol),
+        ),
+        Err(_) => (MessageKind::Unknown(code), None),
+    };
+
+    decode_with_layout(cursor, kind, layout, protocol).await
+}
+
+/// Decodes the first frame sent by the client, without its length. The protocol is read from
+/// the message itself.
+pub async fn decode_login_frame(frame: &[u8]) -> Result<DecodedMessage> {
+    let login = LoginMessage::from_length(frame.len() as u16);
+    let protocol_offset = match login {
+        LoginMessage::PlayerLogin | LoginMessage::NewPlayer => 5,
+        LoginMessage::AccountLogin => 3,
+    };
+    let protocol = frame
+        .get(protocol_offset..protocol_offset + 2)
+        .ok_or_else(|| anyhow!("Login message too short: {frame:02x?}"))?;
+    let protocol: Protocol = u16::from_le_bytes([protocol[0], protocol[1]]).try_into()?;
+
+    let layout = layout::login_message_layout(login, protocol);
+    decode_with_layout(Cursor::new(frame), MessageKind::Login(login), Some(layout), protocol).await
+}
+
+/// Decodes the character list sent as the answer to an account login, without its length
+pub async fn decode_character_list(frame: &[u8], protocol: Protocol) -> Result<DecodedMessage> {
+    let layout = layout::character_list_layout();
+    decode_with_layout(Cursor::new(frame), MessageKind::CharacterList, Some(layout), protocol).await
+}
+
+async fn decode_with_layout(
+    mut cursor: Cursor<&[u8]>,
+    kind: MessageKind,
+    layout: Option<Vec<Field>>,
+    protocol: Protocol,
+) -> Result<DecodedMessage> {
+    let fields = match layout {
+        Some(layout) => decode_fields(&mut cursor, &layout, protocol).await?,
+        None => vec![],
+    };
+    let frame = *cursor.get_ref();
+    Ok(DecodedMessage {
+        kind,
+        fields,
+        remainder: frame[cursor.position() as usize..].to_vec(),
+    })
+}
+
+async fn decode_fields(
+    cursor: &mut Cursor<&[u8]>,
+    layout: &[Field],
+    protocol: Protocol,
+) -> Result<Vec<DecodedField>> {
+    let mut fields: Vec<DecodedField> = vec![];
+    for field in layout {
+        let present = match field.condition {
+            None => true,
+            Some(Condition::Equals(name, value)) => number(&fields, name) == Some(value),
+            Some(Condition::NotEquals(name, value)) => number(&fields, name) != Some(value),
+        };
+        if !present {
+            continue;
+        }
+
+        let value = match field.field_type {
+            FieldType::List(entry_layout) => {
+                let count = fields.last().and_then(|last| match last.value {
+                    FieldValue::Number(count) => Some(count),
+                    _ => None,
+                });
+                let count = count.ok_or_else(|| anyhow!("List {} without count", field.name))?;
+
+                let mut entries = vec![];
+                for _ in 0..count {
+                    let mut entry = vec![];
+                    for entry_field in entry_layout {
+                        entry.push(DecodedField {
+                            name: entry_field.name,
+                            value: decode_value(cursor, entry_field.field_type, protocol).await?,
+                        });
+                    }
+                    entries.push(entry);
+                }
+                FieldValue::List(entries)
+            }
+            field_type => decode_value(cursor, field_type, protocol).await?,
+        };
+
+        fields.push(DecodedField {
+            name: field.name,
+            value,
+        });
+    }
+
+    Ok(fields)
+}
+
+fn number(fields: &[DecodedField], name: &str) -> Option<u32> {
+    fields
+        .iter()
+        .find(|field| field.name == name)
+        .and_then(|field| match field.value {
+            FieldValue::Number(number) => Some(number),
+            _ => None,
+        })
+}
+
+async fn decode_value(
+    cursor: &mut Cursor<&[u8]>,
+    field_type: FieldType,
+    protocol: Protocol,
+) -> Result<FieldValue> {
+    let value = match field_type {
+        FieldType::U8 => FieldValue::Number(cursor.read_u8().await? as u32),
+        FieldType::U16 => FieldValue::Number(cursor.read_u16_le().await? as u32),
+        FieldType::U32 => FieldValue::Number(cursor.read_u32_le().await?),
+        FieldType::Position => FieldValue::Position(cursor.read_position(protocol).await?),
+        FieldType::OutfitColors => FieldValue::Outfit(cursor.read_outfit_colors().await?),
+        FieldType::Gender => FieldValue::Gender(cursor.read_gender(protocol).await?),
+        FieldType::FixedString(length) => {
+            let mut text = String::new();
+            cursor.read_string(&mut text, length).await?;
+            FieldValue::Text(text)
+        }
+        FieldType::NullTerminatedString => {
+            let mut text = String::new();
+            loop {
+                match cursor.read_u8().await? {
+                    b'\0' => break,
+                    c => text.push(c as char),
+                }
+            }
+            FieldValue::Text(text)
+        }
+        FieldType::LengthString => {
+            let length = cursor.read_u16_le().await?;
+            let mut text = String::new();
+            for _ in 0..length {
+                text.push(cursor.read_u8().await? as char);
+            }
+            FieldValue::Text(text)
+        }
+        FieldType::Ipv4 => {
+            let mut octets = [0_u8; 4];
+            cursor.read_exact(&mut octets).await?;
+            FieldValue::Ipv4(Ipv4Addr::from(octets))
+        }
+        FieldType::ItemList => {
+            let mut items = vec![];
+            loop {
+                match cursor.read_u16_le().await? {
+                    0xffff => break,
+                    item => items.push(item),
+                }
+            }
+            FieldValue::Items(items)
+        }
+        FieldType::MapDescription => {
+            let start = cursor.position() as usize;
+            let bytes = &cursor.get_ref()[start..];
+            let length = bytes
+                .windows(2)
+                .position(|window| window == [0xfe, 0x00])
+                .map(|end| end + 2)
+                .unwrap_or(bytes.len());
+            let map = bytes[..length].to_vec();
+            cursor.set_position((start + length) as u64);
+            FieldValue::Map(map)
+        }
+        FieldType::Unknown(length) => {
+            let mut bytes = vec![0_u8; length as usize];
+            cursor.read_exact(&mut bytes).await?;
+            FieldValue::Bytes(bytes)
+        }
+        FieldType::List(_) => return Err(anyhow!("Nested lists are not supported")),
+    };
+
+    Ok(value)
+}
+
+/// Decodes a recording made by the session recorder, using the protocol stored in it
+pub async fn decode_recording(path: &Path) -> Result<Vec<(RecordDirection, DecodedMessage)>> {
+    let mut messages = vec![];
+    let mut login = None;
+    for entry in recorder::read_json_lines::<RecordEntry>(path)? {
+        let protocol = entry.protocol()?;
+        let bytes = entry.bytes()?;
+        match entry.direction {
+            RecordDirection::ClientToServer => {
+                for frame in split_client_frames(&bytes)? {
+                    let message = if login.is_none() {
+                        let message = decode_login_frame(frame).await?;
+                        if let MessageKind::Login(kind) = message.kind {
+                            login = Some(kind);
+                        }
+                        message
+                    } else {
+                        decode_client_frame(frame, protocol).await?
+                    };
+                    messages.push((entry.direction, message));
+                }
+            }
+            RecordDirection::ServerToClient => {
+                if login == Some(LoginMessage::AccountLogin) {
+                    for frame in split_client_frames(&bytes)? {
+                        messages.push((entry.direction, decode_character_list(frame, protocol).await?));
+                    }
+                } else {
+                    for message in decode_server_stream(&bytes, protocol).await? {
+                        messages.push((entry.direction, message));
+                    }
+                }
+            }
+        }
+    }
+    Ok(messages)
+}
+
+/// Decodes a hex dump of frames, including their lengths
+pub async fn decode_hex_dump(
+    dump: &str,
+    protocol: Protocol,
+    direction: RecordDirection,
+    login: bool,
+) -> Result<Vec<DecodedMessage>> {
+    let bytes = hex::decode(dump)?;
+    match direction {
+        RecordDirection::ServerToClient => decode_server_stream(&bytes, protocol).await,
+        RecordDirection::ClientToServer => {
+            let mut messages = vec![];
+            for (index, frame) in split_client_frames(&bytes)?.into_iter().enumerate() {
+                if login && index == 0 {
+                    messages.push(decode_login_frame(frame).await?);
+                } else {
+                    messages.push(decode_client_frame(frame, protocol).await?);
+                }
+            }
+            Ok(messages)
+        }
+    }
+}
+
+impl Display for MessageKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Server(header) => write!(f, "{header:?} (0x{:04x})", *header as u16),
+            Self::Auxiliary(header) => write!(f, "{header:?} (0x{:02x})", *header as u8),
+            Self::Client(header) => write!(f, "{header:?} (0x{:04x})", *header as u16),
+            Self::Login(login) => write!(f, "{login:?}"),
+            Self::CharacterList => write!(f, "CharacterList"),
+            Self::Unknown(code) => write!(f, "Unknown header (0x{code:04x})"),
+        }
+    }
+}
+
+impl Display for FieldValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Number(number) => write!(f, "{number} (0x{number:02x})"),
+            Self::Position(position) => write!(f, "{position}"),
+            Self::Outfit(outfit) => write!(
+                f,
+                "head={}, body={}, legs={}, shoes={}, unknown_byte={}",
+                outfit.head, outfit.body, outfit.legs, outfit.shoes, outfit.unknown_byte
+            ),
+            Self::Gender(gender) => write!(f, "{gender:?}"),
+            Self::Text(text) => write!(f, "{text:?}"),
+            Self::Ipv4(ip) => write!(f, "{ip}"),
+            Self::Items(items) => write!(f, "{items:04x?}"),
+            Self::Map(bytes) => write!(f, "{} bytes", bytes.len()),
+            Self::List(entries) => {
+                for (index, entry) in entries.iter().enumerate() {
+                    write!(f, "\n        [{index}]")?;
+                    for field in entry {
+                        write!(f, " {}={}", field.name, field.value)?;
+                    }
+                }
+                Ok(())
+            }
+            Self::Bytes(bytes) => write!(f, "{bytes:02x?}"),
+        }
+    }
+}
+
+impl Display for DecodedMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind)?;
+        for field in self.fields.iter() {
+            write!(f, "\n    {}: {}", field.name, field.value)?;
+        }
+        if !self.remainder.is_empty() {
+            write!(f, "\n    undecoded: {:02x?}", self.remainder)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_decode_bundled_server_frame() -> Result<()> {
+        // UpdateObject + ChangeDirection + WorldLight, as sent to a 6.5 client
+        let frame = hex::decode("1400 1900 3200 3200 07 01 01 fa 02 00010000 2800 06")?;
+
+        let messages = decode_server_stream(&frame, Protocol::Tibia650).await?;
+
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].kind, MessageKind::Server(HeaderSend::UpdateObject));
+        assert_eq!(
+            messages[0].field("position"),
+            Some(&FieldValue::Position(Position::new(50, 50, 7)))
+        );
+        assert_eq!(messages[0].field("light"), None);
+        assert_eq!(
+            messages[1].kind,
+            MessageKind::Auxiliary(AuxiliaryHeaderSend::ChangeDirection)
+        );
+        assert_eq!(messages[1].number("id"), Some(256));
+        assert_eq!(messages[2].number("light_level"), Some(6));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_decode_client_frame() -> Result<()> {
+        let frame = hex::decode("0900 0500 68656c6c6f")?;
+
+        let message = decode_client_frame(&frame, Protocol::Tibia300).await?;
+
+        assert_eq!(message.kind, MessageKind::Client(HeaderReceive::Chat));
+        assert_eq!(message.text("message"), Some("hello"));
+        assert!(message.remainder.is_empty());
+
+        Ok(())
+    }
+}
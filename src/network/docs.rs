@@ -0,0 +1,450 @@
+//! Generates the packet layout documentation of every protocol, from the layouts the dissector
+//! decodes with and the capabilities the codecs ask. `legbone docs` writes it to
+//! `docs/protocol.md`, and a test fails when the checked in file is out of date.
+use crate::{
+    network::{
+        header::{AuxiliaryHeaderSend, HeaderReceive, HeaderSend},
+        layout::{self, Condition, Field, FieldType, LoginMessage},
+    },
+    protocol::{Framing, GenderEncoding, HeaderFormat, LoginFlow, PositionFormat, WalkRefusal},
+    Protocol,
+};
+use std::fmt::Write;
+
+/// Markdown documentation of every protocol
+pub fn protocol_documentation() -> String {
+    let mut doc = String::new();
+    doc.push_str("# Protocol\n\n");
+    doc.push_str("Generated by `legbone docs` from `src/network/layout.rs` and `src/protocol/mod.rs`, do not edit.\n\n");
+    doc.push_str("Every frame starts with its u16 length. The length of frames sent by the server counts its own 2 bytes, the length of frames sent by the client does not. Numbers are little endian. Offsets start after the header, and are left out after fields of variable size.\n\n");
+
+    let versions: Vec<String> = Protocol::ALL
+        .iter()
+        .map(|&protocol| format!("[{}](#{})", version(protocol), anchor(protocol)))
+        .collect();
+    let _ = writeln!(doc, "Versions: {}", versions.join(", "));
+
+    for protocol in Protocol::ALL {
+        document_protocol(&mut doc, protocol);
+    }
+    doc
+}
+
+fn document_protocol(doc: &mut String, protocol: Protocol) {
+    let capabilities = protocol.capabilities();
+    let _ = writeln!(doc, "\n## {}\n", version(protocol));
+
+    let _ = writeln!(doc, "* Server headers: {}", header_format(protocol));
+    let _ = writeln!(doc, "* Client headers: u16");
+    let _ = writeln!(doc, "* Server frames: {}", framing(protocol));
+    let _ = writeln!(doc, "* Positions: {}", position(protocol));
+    let _ = writeln!(doc, "* Gender: {}", gender(protocol));
+    let _ = writeln!(doc, "* Chat positions: {}", chat_position(protocol));
+    let _ = writeln!(doc, "* Private messages: {}", private_message(protocol));
+    let _ = writeln!(doc, "* Refused steps: {}", walk_refusal(protocol));
+
+    let _ = writeln!(doc, "\n### Login messages");
+    for login in login_messages(protocol) {
+        let layout = layout::login_message_layout(login, protocol);
+        let size = match layout_size(&layout, protocol) {
+            Some(size) => format!("{size} bytes"),
+            None => "variable size".to_string(),
+        };
+        let _ = writeln!(doc, "\n{login:?}, {size}:\n");
+        table(doc, &[(None, format!("{login:?}"), Some(layout))], protocol);
+    }
+
+    if capabilities.login == LoginFlow::Account {
+        let _ = writeln!(doc, "\nCharacter list, answer to the account login:\n");
+        table(doc, &[(None, "CharacterList".to_string(), Some(layout::character_list_layout()))], protocol);
+    }
+
+    let mut headers = capabilities.headers.to_vec();
+    headers.sort_by_key(|&header| header as u16);
+    let messages: Vec<_> = headers
+        .into_iter()
+        .map(|header| {
+            let code = match capabilities.header {
+                HeaderFormat::U16 => format!("0x{:04x}", header as u16),
+                HeaderFormat::U8 | HeaderFormat::U8WithPrefix => format!("0x{:02x}", header as u16),
+            };
+            (Some(code), format!("{header:?}"), layout::server_message_layout(header, protocol))
+        })
+        .collect();
+    let _ = writeln!(doc, "\n### Server messages\n");
+    table(doc, &messages, protocol);
+
+    let messages: Vec<_> = (0..=u8::MAX)
+        .filter_map(|code| AuxiliaryHeaderSend::try_from(code).ok())
+        .filter_map(|header| {
+            let layout = layout::auxiliary_message_layout(header, protocol)?;
+            Some((Some(format!("0x{:02x}", header as u8)), format!("{header:?}"), Some(layout)))
+        })
+        .collect();
+    let _ = writeln!(doc, "\n### Auxiliary messages\n");
+    let _ = writeln!(doc, "Sent right after other messages, with a u8 header.\n");
+    table(doc, &messages, protocol);
+
+    let messages: Vec<_> = (0..=u8::MAX as u16)
+        .filter_map(|code| HeaderReceive::try_from(code).ok())
+        .map(|header| {
+            let code = format!("0x{:04x}", header as u16);
+            (Some(code), format!("{header:?}"), layout::client_message_layout(header, protocol))
+        })
+        .collect();
+    let _ = writeln!(doc, "\n### Client messages\n");
+    table(doc, &messages, protocol);
+}
+
+/// Differences between two protocols: capabilities, headers added or removed and messages
+/// whose layouts differ
+pub fn protocol_diff(from: Protocol, to: Protocol) -> String {
+    let mut diff = String::new();
+    let _ = writeln!(diff, "Differences from {} to {}", version(from), version(to));
+
+    let (old, new) = (from.capabilities(), to.capabilities());
+    let capabilities = [
+        ("Server headers", header_format(from), header_format(to)),
+        ("Server frames", framing(from), framing(to)),
+        ("Positions", position(from).to_string(), position(to).to_string()),
+        ("Gender", gender(from).to_string(), gender(to).to_string()),
+        ("Max outfit type", old.max_outfit_type.to_string(), new.max_outfit_type.to_string()),
+        ("Max magic effect", old.max_magic_effect.to_string(), new.max_magic_effect.to_string()),
+        ("Chat positions", chat_position(from), chat_position(to)),
+        ("Private messages", private_message(from), private_message(to)),
+        ("Refused steps", walk_refusal(from).to_string(), walk_refusal(to).to_string()),
+    ];
+    section(
+        &mut diff,
+        "Capabilities",
+        capabilities
+            .into_iter()
+            .filter(|(_, old, new)| old != new)
+            .map(|(name, old, new)| format!("{name}: {old} -> {new}")),
+    );
+
+    let added = new.headers.iter().filter(|header| !old.headers.contains(header));
+    let removed = old.headers.iter().filter(|header| !new.headers.contains(header));
+    section(&mut diff, "Server headers added", added.map(|header| format!("0x{:04x} {header:?}", *header as u16)));
+    section(&mut diff, "Server headers removed", removed.map(|header| format!("0x{:04x} {header:?}", *header as u16)));
+
+    let logins = [LoginMessage::PlayerLogin, LoginMessage::NewPlayer, LoginMessage::AccountLogin];
+    let added = logins.iter().filter(|login| !login_messages(from).contains(login) && login_messages(to).contains(login));
+    let removed = logins.iter().filter(|login| login_messages(from).contains(login) && !login_messages(to).contains(login));
+    section(&mut diff, "Login messages added", added.map(|login| format!("{login:?}")));
+    section(&mut diff, "Login messages removed", removed.map(|login| format!("{login:?}")));
+
+    let mut changes = vec![];
+    for login in logins {
+        if login_messages(from).contains(&login) && login_messages(to).contains(&login) {
+            let layout = |protocol| Some(layout::login_message_layout(login, protocol));
+            changes.extend(layout_change(&format!("{login:?}"), from, to, layout));
+        }
+    }
+    section(&mut diff, "Login messages with different layouts", changes);
+
+    let mut headers: Vec<HeaderSend> = old.headers.iter().filter(|header| new.headers.contains(header)).copied().collect();
+    headers.sort_by_key(|&header| header as u16);
+    let changes = headers.into_iter().filter_map(|header| {
+        layout_change(&format!("{header:?}"), from, to, |protocol| layout::server_message_layout(header, protocol))
+    });
+    section(&mut diff, "Server messages with different layouts", changes);
+
+    let changes = (0..=u8::MAX)
+        .filter_map(|code| AuxiliaryHeaderSend::try_from(code).ok())
+        .filter_map(|header| {
+            layout_change(&format!("{header:?}"), from, to, |protocol| layout::auxiliary_message_layout(header, protocol))
+        });
+    section(&mut diff, "Auxiliary messages with different layouts", changes);
+
+    let changes = (0..=u8::MAX as u16)
+        .filter_map(|code| HeaderReceive::try_from(code).ok())
+        .filter_map(|header| {
+            layout_change(&format!("{header:?}"), from, to, |protocol| layout::client_message_layout(header, protocol))
+        });
+    section(&mut diff, "Client messages with different layouts", changes);
+
+    if diff.lines().count() == 1 {
+        diff.push_str("\nNo differences\n");
+    }
+    diff
+}
+
+/// Writes the section only if it has items
+fn section(diff: &mut String, title: &str, items: impl IntoIterator<Item = String>) {
+    let items: Vec<String> = items.into_iter().collect();
+    if !items.is_empty() {
+        let _ = writeln!(diff, "\n{title}:");
+        for item in items {
+            let _ = writeln!(diff, "* {item}");
+        }
+    }
+}
+
+/// Fields removed and added between the protocols, if the layouts differ
+fn layout_change(name: &str, from: Protocol, to: Protocol, layout: impl Fn(Protocol) -> Option<Vec<Field>>) -> Option<String> {
+    let fields = |protocol| match layout(protocol) {
+        None => vec!["layout unknown".to_string()],
+        Some(layout) => {
+            let mut rows = vec![];
+            field_rows(&mut rows, &layout, "", None, protocol);
+            rows.into_iter()
+                .map(|(_, name, field_type)| format!("{name} ({field_type})"))
+                .collect()
+        }
+    };
+
+    let (old, new) = (fields(from), fields(to));
+    if old == new {
+        return None;
+    }
+    let lines: Vec<String> = field_diff(&old, &new).into_iter().map(|line| format!("\n  {line}")).collect();
+    Some(format!("{name}{}", lines.concat()))
+}
+
+/// Lines of a diff between the fields, based on their longest common subsequence
+fn field_diff(old: &[String], new: &[String]) -> Vec<String> {
+    // common[i][j] = length of the longest common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut lines = vec![];
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(format!("- {}", old[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+ {}", new[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+/// Header as written by the protocol, name and layout of a message
+type MessageRow = (Option<String>, String, Option<Vec<Field>>);
+
+/// One row per field of each message
+fn table(doc: &mut String, messages: &[MessageRow], protocol: Protocol) {
+    let _ = writeln!(doc, "| Header | Message | Offset | Field | Type |");
+    let _ = writeln!(doc, "|---|---|---|---|---|");
+    for (code, name, layout) in messages {
+        let code = code.as_ref().map_or(" ".to_string(), |code| format!(" `{code}` "));
+        match layout {
+            None => {
+                let _ = writeln!(doc, "|{code}| {name} | | | layout unknown |");
+            }
+            Some(layout) if layout.is_empty() => {
+                let _ = writeln!(doc, "|{code}| {name} | | | no fields |");
+            }
+            Some(layout) => {
+                let mut rows = vec![];
+                field_rows(&mut rows, layout, "", Some(0), protocol);
+                for (i, (offset, field, field_type)) in rows.into_iter().enumerate() {
+                    let offset = offset.map_or(" ".to_string(), |offset| format!(" {offset} "));
+                    if i == 0 {
+                        let _ = writeln!(doc, "|{code}| {name} |{offset}| {field} | {field_type} |");
+                    } else {
+                        let _ = writeln!(doc, "| | |{offset}| {field} | {field_type} |");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Offset, name and type of every field, including the fields of lists. Returns the offset after
+/// the fields, if known
+fn field_rows(
+    rows: &mut Vec<(Option<u16>, String, String)>,
+    layout: &[Field],
+    prefix: &str,
+    mut offset: Option<u16>,
+    protocol: Protocol,
+) -> Option<u16> {
+    for field in layout {
+        let name = match field.condition {
+            None => format!("{prefix}{}", field.name),
+            Some(Condition::Equals(other, value)) => format!("{prefix}{} (if {other} = {value})", field.name),
+            Some(Condition::NotEquals(other, value)) => format!("{prefix}{} (if {other} ≠ {value})", field.name),
+        };
+        rows.push((offset, name, field_type(field.field_type, protocol)));
+
+        if let FieldType::List(entry) = field.field_type {
+            field_rows(rows, entry, &format!("{prefix}{}[].", field.name), None, protocol);
+        }
+        offset = match (offset, width(field.field_type, protocol), field.condition) {
+            (Some(offset), Some(width), None) => Some(offset + width),
+            _ => None,
+        };
+    }
+    offset
+}
+
+fn layout_size(layout: &[Field], protocol: Protocol) -> Option<u16> {
+    field_rows(&mut vec![], layout, "", Some(0), protocol)
+}
+
+/// Bytes taken by the field, `None` when it depends on its value
+fn width(field_type: FieldType, protocol: Protocol) -> Option<u16> {
+    match field_type {
+        FieldType::U8 | FieldType::Gender => Some(1),
+        FieldType::U16 => Some(2),
+        FieldType::U32 | FieldType::Ipv4 => Some(4),
+        FieldType::Position => match protocol.capabilities().position {
+            PositionFormat::Short => Some(2),
+            PositionFormat::Long => Some(5),
+        },
+        FieldType::OutfitColors => Some(3),
+        FieldType::FixedString(length) | FieldType::Unknown(length) => Some(length),
+        FieldType::NullTerminatedString
+        | FieldType::LengthString
+        | FieldType::ItemList
+        | FieldType::MapDescription
+        | FieldType::List(_) => None,
+    }
+}
+
+fn field_type(field_type: FieldType, protocol: Protocol) -> String {
+    match field_type {
+        FieldType::U8 => "u8".to_string(),
+        FieldType::U16 => "u16".to_string(),
+        FieldType::U32 => "u32".to_string(),
+        FieldType::Position => position(protocol).to_string(),
+        FieldType::OutfitColors => "u4 legs and u4 shoes, u4 head and u4 body, u8 unknown".to_string(),
+        FieldType::Gender => format!("u8, {}", gender(protocol)),
+        FieldType::FixedString(length) => format!("string of {length} bytes, padded with zeroes"),
+        FieldType::NullTerminatedString => "string ending with 0x00".to_string(),
+        FieldType::LengthString => "u16 length, string".to_string(),
+        FieldType::Ipv4 => "4 bytes ipv4 address".to_string(),
+        FieldType::ItemList => "u16 item ids, ending with 0xffff".to_string(),
+        FieldType::MapDescription => "tiles separated by 0xff, ending with 0xfe 0x00".to_string(),
+        FieldType::List(_) => "repeated as many times as the previous field".to_string(),
+        FieldType::Unknown(1) => "1 byte of unknown meaning".to_string(),
+        FieldType::Unknown(length) => format!("{length} bytes of unknown meaning"),
+    }
+}
+
+fn header_format(protocol: Protocol) -> String {
+    match protocol.capabilities().header {
+        HeaderFormat::U16 => "u16",
+        HeaderFormat::U8 => "u8",
+        HeaderFormat::U8WithPrefix => "u8, after 4 zero bytes",
+    }
+    .to_string()
+}
+
+fn framing(protocol: Protocol) -> String {
+    match protocol.capabilities().framing {
+        Framing::Individual => "one message per frame, along with its auxiliary messages",
+        Framing::Bundled => "every message of a flush in the same frame",
+    }
+    .to_string()
+}
+
+fn position(protocol: Protocol) -> &'static str {
+    match protocol.capabilities().position {
+        PositionFormat::Short => "u8 x, u8 y",
+        PositionFormat::Long => "u16 x, u16 y, u8 z",
+    }
+}
+
+fn gender(protocol: Protocol) -> &'static str {
+    match protocol.capabilities().gender {
+        GenderEncoding::FemaleZero => "female = 0, male = 1",
+        GenderEncoding::FemaleTwo => "female = 2, male = 1",
+    }
+}
+
+fn chat_position(protocol: Protocol) -> String {
+    match protocol.capabilities().chat_position_offset {
+        (0, 0, 0) => "position of the speaker".to_string(),
+        (x, y, z) => format!("position of the speaker plus ({x}, {y}, {z})"),
+    }
+}
+
+fn private_message(protocol: Protocol) -> String {
+    let chat_type = protocol.capabilities().private_message;
+    format!("chat type {:#04x} ({chat_type:?})", chat_type as u8)
+}
+
+fn walk_refusal(protocol: Protocol) -> &'static str {
+    match protocol.capabilities().walk_refusal {
+        WalkRefusal::Map => "map sent again",
+        WalkRefusal::MapAndTurn => "map sent again, then the character turned to the step",
+    }
+}
+
+/// Login messages sent by clients of the protocol, as validated in `connection::handle_login`
+fn login_messages(protocol: Protocol) -> Vec<LoginMessage> {
+    let mut logins = vec![LoginMessage::PlayerLogin];
+    match protocol.capabilities().login {
+        LoginFlow::PlayerOrNewPlayer => logins.push(LoginMessage::NewPlayer),
+        LoginFlow::Player => {}
+        LoginFlow::Account => logins.push(LoginMessage::AccountLogin),
+    }
+    logins
+}
+
+/// e.g. 6.50 for Tibia650
+fn version(protocol: Protocol) -> String {
+    let number = protocol as u16;
+    format!("{}.{:02}", number / 100, number % 100)
+}
+
+/// Anchor of the heading of the protocol, as generated by GitHub
+fn anchor(protocol: Protocol) -> String {
+    version(protocol).replace('.', "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protocol_documentation_is_up_to_date() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/docs/protocol.md");
+        let checked_in = std::fs::read_to_string(path).unwrap_or_default();
+        assert!(
+            checked_in == protocol_documentation(),
+            "docs/protocol.md is out of date, run `legbone docs --output docs/protocol.md`"
+        );
+    }
+
+    #[test]
+    fn test_protocol_diff() {
+        let diff = protocol_diff(Protocol::Tibia400, Protocol::Tibia412);
+        assert!(diff.contains("Server headers: u8 -> u16"), "{diff}");
+        assert!(diff.contains("Skills\n  - gauche (u8)\n  - fist (u8)\n  - missile (u8)\n  + axe (u8)"), "{diff}");
+        assert!(!diff.contains("Server headers added"), "{diff}");
+
+        let diff = protocol_diff(Protocol::Tibia412, Protocol::Tibia501);
+        assert!(diff.contains("Gender: female = 0, male = 1 -> female = 2, male = 1"), "{diff}");
+        assert!(!diff.contains("Server frames"), "{diff}");
+
+        let diff = protocol_diff(Protocol::Tibia650, Protocol::Tibia661);
+        assert!(diff.ends_with("No differences\n"), "{diff}");
+    }
+
+    #[test]
+    fn test_login_sizes() {
+        for protocol in Protocol::ALL {
+            for login in [LoginMessage::PlayerLogin, LoginMessage::NewPlayer] {
+                let size = layout_size(&layout::login_message_layout(login, protocol), protocol);
+                assert_eq!(size.map(LoginMessage::from_length), Some(login), "{login:?} {protocol:?}");
+            }
+        }
+    }
+}
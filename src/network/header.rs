@@ -1,8 +1,9 @@
 use crate::character::Direction;
 use num_enum::TryFromPrimitive;
+use serde_derive::{Deserialize, Serialize};
 
 #[repr(u16)]
-#[derive(Debug, TryFromPrimitive)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive, Serialize, Deserialize)]
 pub enum HeaderSend {
     //103 = 00 00 01 00 02 00 03 00 04 00 05 00 0A 00 0B 00 0C 00 0D 00 0E       00 12 00 13 00 14 00 15       00 19                                                             00 64 00 65 00 66 00 67 00 68 00 C8 00
     //300 =       01 00 02 00 03 00 04 00 05 00 0A 00 0B 00 0C 00 0D 00 0E 00 0F 00 12 00 13 00 14 00 15 00 16 00 19 00 1A       00 23       00 28 00 32 00 33       00 3C       00 64 00 65 00 66 00 67 00 68 00 C8 00
@@ -79,14 +80,14 @@ impl From<Direction> for HeaderSend {
 }
 
 #[repr(u8)]
-#[derive(Debug, TryFromPrimitive)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive)]
 pub enum AuxiliaryHeaderSend {
     ChangeDirection = 0xfa,
     Character = 0xfb,
 }
 
 #[repr(u16)]
-#[derive(Debug, TryFromPrimitive)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, TryFromPrimitive, Deserialize)]
 pub enum HeaderReceive {
     UserList = 0x0003,
     PlayerInfo = 0x0004,
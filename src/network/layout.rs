@@ -0,0 +1,413 @@
+//! Field by field layout of the messages, as encoded in `connection::send` and decoded in
+//! `connection::receive`. Headers and lengths are not part of the layouts.
+use crate::{
+    network::header::{AuxiliaryHeaderSend, HeaderReceive, HeaderSend},
+    protocol::{MessageForms, PlayerDataLayout, SkillLayout, StatsLayout},
+    Protocol,
+};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FieldType {
+    U8,
+    U16,
+    U32,
+    /// x and y as u8 on 1.03, x and y as u16 and z as u8 later
+    Position,
+    /// 4 colors stored as u4's in 2 bytes, followed by an unknown byte
+    OutfitColors,
+    Gender,
+    /// String padded with zeroes to the given length
+    FixedString(u16),
+    NullTerminatedString,
+    /// u16 length followed by the string
+    LengthString,
+    Ipv4,
+    /// u16 item ids until 0xffff
+    ItemList,
+    /// Tiles separated by 0xff, ending with 0xfe 0x00
+    MapDescription,
+    /// Repeats the given fields as many times as the value of the previous field
+    List(&'static [Field]),
+    /// Bytes with unknown meaning
+    Unknown(u16),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Condition {
+    Equals(&'static str, u32),
+    NotEquals(&'static str, u32),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Field {
+    pub name: &'static str,
+    pub field_type: FieldType,
+    /// Field is only present when the condition on a previous field of the message holds
+    pub condition: Option<Condition>,
+}
+
+impl Field {
+    pub const fn new(name: &'static str, field_type: FieldType) -> Self {
+        Self {
+            name,
+            field_type,
+            condition: None,
+        }
+    }
+
+    pub const fn with_condition(self, condition: Condition) -> Self {
+        Self {
+            condition: Some(condition),
+            ..self
+        }
+    }
+}
+
+const CHARACTER_LIST_ENTRY: &[Field] = &[
+    Field::new("name", FieldType::LengthString),
+    Field::new("world", FieldType::LengthString),
+    Field::new("ip", FieldType::Ipv4),
+    Field::new("port", FieldType::U16),
+];
+
+/// Layout of a message sent by the server, `None` when the layout is not known
+pub fn server_message_layout(header: HeaderSend, protocol: Protocol) -> Option<Vec<Field>> {
+    use FieldType::*;
+    let capabilities = protocol.capabilities();
+    let full_forms = capabilities.forms == MessageForms::Full;
+
+    let layout = match header {
+        HeaderSend::Login => {
+            if full_forms {
+                vec![Field::new("id", U32)]
+            } else {
+                vec![]
+            }
+        }
+        HeaderSend::Error
+        | HeaderSend::Info
+        | HeaderSend::StatusMessage
+        | HeaderSend::GreenChat => vec![Field::new("message", NullTerminatedString)],
+        HeaderSend::DataWindow => {
+            let mut layout = vec![Field::new("name", FixedString(30))];
+            match capabilities.player_data {
+                PlayerDataLayout::Account | PlayerDataLayout::AccountWithComment => {
+                    layout.push(Field::new("password", FixedString(30)));
+                    layout.push(Field::new("gender", Gender));
+                    layout.push(Field::new("outfit", OutfitColors));
+                    layout.push(Field::new("real_name", FixedString(50)));
+                    layout.push(Field::new("location", FixedString(50)));
+                    layout.push(Field::new("email", FixedString(50)));
+                    if capabilities.player_data == PlayerDataLayout::AccountWithComment {
+                        layout.push(Field::new("comment", FixedString(500)));
+                    }
+                }
+                PlayerDataLayout::Character => {
+                    layout.push(Field::new("gender", Gender));
+                    layout.push(Field::new("outfit", OutfitColors));
+                }
+            }
+            layout
+        }
+        HeaderSend::MessageOfTheDay => {
+            if capabilities.numbered_message_of_the_day {
+                vec![
+                    Field::new("message_number", U16),
+                    Field::new("separator", U8),
+                    Field::new("message", NullTerminatedString),
+                ]
+            } else {
+                vec![Field::new("message", NullTerminatedString)]
+            }
+        }
+        HeaderSend::Map => vec![
+            Field::new("position", Position),
+            Field::new("map", MapDescription),
+        ],
+        HeaderSend::MoveOneTileNorth
+        | HeaderSend::MoveOneTileEast
+        | HeaderSend::MoveOneTileSouth
+        | HeaderSend::MoveOneTileWest => vec![Field::new("map", MapDescription)],
+        HeaderSend::OpenContainer => vec![
+            Field::new("local_id", U8),
+            Field::new("item_id", U16),
+            Field::new("items", ItemList),
+        ],
+        HeaderSend::CloseContainer => vec![Field::new("local_id", U8)],
+        HeaderSend::EquippedItem => {
+            if full_forms {
+                vec![
+                    Field::new("slot", U8),
+                    Field::new("item_id", U16),
+                    Field::new("stack", U8),
+                ]
+            } else {
+                vec![Field::new("item_id", U16), Field::new("slot", U8)]
+            }
+        }
+        HeaderSend::UpdateObject => {
+            if !full_forms {
+                return None;
+            }
+            vec![
+                Field::new("position", Position),
+                Field::new("update_type", U8),
+                Field::new("stack_pos", U8),
+                Field::new("light", Unknown(6)).with_condition(Condition::Equals("update_type", 0)),
+            ]
+        }
+        HeaderSend::MagicEffect => vec![
+            Field::new("position", Position),
+            Field::new("effect", U8),
+        ],
+        HeaderSend::WorldLight => vec![Field::new("light_level", U8)],
+        HeaderSend::UpdateCharacter => vec![
+            Field::new("id", U32),
+            Field::new("update_type", U8),
+            Field::new("value", U8).with_condition(Condition::NotEquals("update_type", 3)),
+            Field::new("outfit_type", U8).with_condition(Condition::Equals("update_type", 3)),
+            Field::new("outfit", OutfitColors).with_condition(Condition::Equals("update_type", 3)),
+        ],
+        HeaderSend::Stats => {
+            let mut layout = vec![
+                Field::new("health_points", U16),
+                Field::new("capacity", U16),
+            ];
+            match capabilities.stats {
+                StatsLayout::Basic => {}
+                StatsLayout::Tibia300 => {
+                    layout.push(Field::new("intelligence", U8));
+                    layout.push(Field::new("strength", U8));
+                    layout.push(Field::new("dexterity", U8));
+                    layout.push(Field::new("experience_points", U16));
+                    layout.push(Field::new("experience_level", U8));
+                }
+                StatsLayout::Standard => {
+                    layout.push(Field::new("experience_points", U32));
+                    layout.push(Field::new("experience_level", U8));
+                    layout.push(Field::new("mana_points", U16));
+                    layout.push(Field::new("magic_level", U8));
+                    layout.push(Field::new("ammunition", U16));
+                }
+            }
+            layout
+        }
+        HeaderSend::Skills => {
+            if capabilities.skills == SkillLayout::Tibia400 {
+                vec![
+                    Field::new("sword", U8),
+                    Field::new("club", U8),
+                    Field::new("gauche", U8),
+                    Field::new("fist", U8),
+                    Field::new("missile", U8),
+                    Field::new("shield", U8),
+                    Field::new("throwing", U8),
+                    Field::new("fishing", U8),
+                ]
+            } else {
+                vec![
+                    Field::new("sword", U8),
+                    Field::new("club", U8),
+                    Field::new("axe", U8),
+                    Field::new("distance", U8),
+                    Field::new("shield", U8),
+                    Field::new("fist", U8),
+                    Field::new("fishing", U8),
+                ]
+            }
+        }
+        HeaderSend::Chat => vec![
+            Field::new("position", Position),
+            Field::new("chat_type", U8),
+            Field::new("message", NullTerminatedString),
+        ],
+        HeaderSend::UserList | HeaderSend::UserInfo => vec![
+            Field::new("buffer_size", U16),
+            Field::new("text", NullTerminatedString),
+        ],
+        HeaderSend::Echo
+        | HeaderSend::Unknown0x0000
+        | HeaderSend::Unknown0x000f
+        | HeaderSend::Unknown0x0033
+        | HeaderSend::Unknown0x0034 => vec![],
+        HeaderSend::RemoveEquippedItem
+        | HeaderSend::UpdateInventoryItem
+        | HeaderSend::Text
+        | HeaderSend::ProjectileEffect
+        | HeaderSend::HouseText => return None,
+    };
+
+    Some(layout)
+}
+
+/// Layout of the auxiliary messages, which follow other messages without a regular header.
+/// `None` when the protocol has no such message
+pub fn auxiliary_message_layout(header: AuxiliaryHeaderSend, protocol: Protocol) -> Option<Vec<Field>> {
+    use FieldType::*;
+    let full_forms = protocol.capabilities().forms == MessageForms::Full;
+
+    let layout = match header {
+        AuxiliaryHeaderSend::ChangeDirection if !full_forms => return None,
+        AuxiliaryHeaderSend::ChangeDirection => vec![
+            Field::new("direction", U8),
+            Field::new("id", U32),
+        ],
+        AuxiliaryHeaderSend::Character => {
+            if full_forms {
+                vec![
+                    Field::new("known_creature", U32),
+                    Field::new("id", U32),
+                    Field::new("name", FixedString(30)),
+                    Field::new("health_status", U8),
+                    Field::new("direction", U8),
+                    Field::new("outfit_type", U8),
+                    Field::new("outfit", OutfitColors),
+                    Field::new("light_level", U8),
+                ]
+            } else {
+                vec![Field::new("outfit", OutfitColors)]
+            }
+        }
+    };
+
+    Some(layout)
+}
+
+/// Layout of a message sent by the client, `None` when the layout is not known
+pub fn client_message_layout(header: HeaderReceive, protocol: Protocol) -> Option<Vec<Field>> {
+    use FieldType::*;
+
+    let layout = match header {
+        HeaderReceive::UserList
+        | HeaderReceive::RequestChangeData
+        | HeaderReceive::ExitBattle
+        | HeaderReceive::Echo
+        | HeaderReceive::Logout => vec![],
+        HeaderReceive::PlayerInfo => vec![Field::new("name", NullTerminatedString)],
+        HeaderReceive::Walk | HeaderReceive::ChangeDirection => {
+            vec![Field::new("direction", U8)]
+        }
+        HeaderReceive::AutoWalk | HeaderReceive::LookAt => {
+            vec![Field::new("position", Position)]
+        }
+        HeaderReceive::Chat => vec![Field::new("message", LengthString)],
+        HeaderReceive::Comment => vec![Field::new("comment", NullTerminatedString)],
+        HeaderReceive::Push => {
+            let mut layout = vec![
+                Field::new("from", Position),
+                Field::new("object_id", U16),
+                Field::new("stack_pos", U8),
+                Field::new("to", Position),
+            ];
+            if protocol.capabilities().forms == MessageForms::Full {
+                layout.push(Field::new("count", U8));
+            }
+            layout
+        }
+        HeaderReceive::UseItem => vec![
+            Field::new("item_type", U8),
+            Field::new("position", Position),
+            Field::new("item_id", U16),
+            Field::new("stack_pos", U8),
+            Field::new("unknown", Unknown(1)),
+        ],
+        HeaderReceive::CloseContainer => vec![Field::new("local_id", U8)],
+        HeaderReceive::SetData => {
+            let player_data = protocol.capabilities().player_data;
+            if player_data != PlayerDataLayout::Character {
+                let mut layout = vec![
+                    Field::new("password", FixedString(30)),
+                    Field::new("outfit", OutfitColors),
+                    Field::new("real_name", FixedString(50)),
+                    Field::new("location", FixedString(50)),
+                    Field::new("email", FixedString(50)),
+                ];
+                if player_data == PlayerDataLayout::AccountWithComment {
+                    layout.push(Field::new("comment", FixedString(500)));
+                }
+                layout
+            } else {
+                vec![Field::new("outfit", OutfitColors)]
+            }
+        }
+        HeaderReceive::ChangeMode => vec![
+            Field::new("fight_mode", U8),
+            Field::new("fight_stance", U8),
+        ],
+        HeaderReceive::SetTarget => vec![Field::new("id", U32)],
+        HeaderReceive::SetText | HeaderReceive::HouseText => return None,
+    };
+
+    Some(layout)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LoginMessage {
+    /// Login with an existing character, 67 bytes
+    PlayerLogin,
+    /// Character creation, 221 bytes on 1.03, 223 on 3.x and 723 on 4.x and 5.01
+    NewPlayer,
+    /// Account login of 6.5+, answered with the character list
+    AccountLogin,
+}
+
+impl LoginMessage {
+    pub const fn from_length(length: u16) -> Self {
+        match length {
+            67 => Self::PlayerLogin,
+            221 | 223 | 723 => Self::NewPlayer,
+            _ => Self::AccountLogin,
+        }
+    }
+}
+
+/// Layout of the first message sent by the client
+pub fn login_message_layout(login: LoginMessage, protocol: Protocol) -> Vec<Field> {
+    use FieldType::*;
+
+    match login {
+        LoginMessage::PlayerLogin => vec![
+            Field::new("prefix", Unknown(5)),
+            Field::new("protocol", U16),
+            Field::new("name", FixedString(30)),
+            Field::new("password", FixedString(30)),
+        ],
+        LoginMessage::NewPlayer => {
+            let mut layout = vec![
+                Field::new("prefix", Unknown(5)),
+                Field::new("protocol", U16),
+                Field::new("name", FixedString(30)),
+                Field::new("password", FixedString(30)),
+                Field::new("gender", Gender),
+                Field::new("unknown", Unknown(2)),
+                Field::new("outfit", OutfitColors),
+                Field::new("real_name", FixedString(50)),
+            ];
+            if protocol.capabilities().forms == MessageForms::Short {
+                layout.push(Field::new("location", FixedString(48)));
+            } else {
+                layout.push(Field::new("location", FixedString(50)));
+            }
+            layout.push(Field::new("email", FixedString(50)));
+            if protocol.capabilities().player_data == PlayerDataLayout::AccountWithComment {
+                layout.push(Field::new("comment", FixedString(500)));
+            }
+            layout
+        }
+        LoginMessage::AccountLogin => vec![
+            Field::new("prefix", Unknown(3)),
+            Field::new("protocol", U16),
+            Field::new("account_number", U32),
+            Field::new("password", LengthString),
+        ],
+    }
+}
+
+/// Layout of the character list, sent by the server as the answer to an account login
+pub fn character_list_layout() -> Vec<Field> {
+    vec![
+        Field::new("header", FieldType::U8),
+        Field::new("character_count", FieldType::U8),
+        Field::new("characters", FieldType::List(CHARACTER_LIST_ENTRY)),
+    ]
+}
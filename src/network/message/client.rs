@@ -0,0 +1,622 @@
+use crate::{
+    character::{Direction, FightMode, FightStance, OutfitColors},
+    io::WriteExt,
+    map::position::Position,
+    network::header::HeaderReceive,
+    protocol::{MessageForms, PlayerDataLayout, PositionFormat},
+    Protocol,
+};
+use anyhow::{anyhow, Result};
+use std::{fmt, io::Cursor};
+use tokio::io::AsyncWriteExt;
+
+/// Longest null-terminated or length-prefixed string accepted from the client
+pub const MAX_STRING_LENGTH: usize = 1024;
+
+/// Message sent by the client after login, fully parsed. Strings are read byte by byte, as the
+/// client does not use UTF-8.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ClientMessage {
+    UserList,
+    PlayerInfo {
+        name: String,
+    },
+    Walk {
+        direction: Direction,
+    },
+    AutoWalk {
+        position: Position,
+    },
+    LookAt {
+        position: Position,
+    },
+    /// Raw bytes of the message, translated to a string by the chat system
+    Chat {
+        message: Vec<u8>,
+    },
+    ChangeDirection {
+        direction: Direction,
+    },
+    Comment {
+        comment: String,
+    },
+    Push {
+        from: Position,
+        object_id: u16,
+        stack_pos: u8,
+        to: Position,
+        /// Not sent by 1.03
+        count: Option<u8>,
+    },
+    UseItem {
+        /// 1 = regular, 2 = usable with
+        item_type: u8,
+        position: Position,
+        item_id: u16,
+        stack_pos: u8,
+        unknown: u8,
+    },
+    CloseContainer {
+        local_id: u8,
+    },
+    RequestChangeData,
+    SetData {
+        outfit: OutfitColors,
+        /// Sent up to 5.01 only
+        details: Option<PlayerDetails>,
+    },
+    /// Layout unknown, kept as is
+    SetText {
+        data: Vec<u8>,
+    },
+    /// Layout unknown, kept as is
+    HouseText {
+        data: Vec<u8>,
+    },
+    ChangeMode {
+        fight_mode: FightMode,
+        fight_stance: FightStance,
+    },
+    ExitBattle,
+    SetTarget {
+        id: u32,
+    },
+    Echo,
+    Logout,
+}
+
+/// Fields of the character data window that were dropped in 6.x
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PlayerDetails {
+    pub password: String,
+    pub real_name: String,
+    pub location: String,
+    pub email: String,
+    /// Sent since 4.0
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DecodeError {
+    /// Message too short to hold a header
+    MissingHeader,
+    UnknownHeader(u16),
+    /// Message ended while reading `field`, which starts at `offset`
+    Truncated {
+        header: HeaderReceive,
+        field: &'static str,
+        offset: usize,
+    },
+    /// Message was fully parsed and `count` bytes were left
+    TrailingBytes {
+        header: HeaderReceive,
+        count: usize,
+    },
+    InvalidValue {
+        header: HeaderReceive,
+        field: &'static str,
+        value: u32,
+    },
+    /// String longer than `MAX_STRING_LENGTH`
+    StringTooLong {
+        header: HeaderReceive,
+        field: &'static str,
+        length: usize,
+    },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingHeader => write!(f, "message is too short to hold a header"),
+            Self::UnknownHeader(header) => write!(f, "unknown header 0x{header:04x}"),
+            Self::Truncated {
+                header,
+                field,
+                offset,
+            } => write!(f, "{header:?} ended while reading {field} at offset {offset}"),
+            Self::TrailingBytes { header, count } => {
+                write!(f, "{header:?} has {count} trailing bytes")
+            }
+            Self::InvalidValue {
+                header,
+                field,
+                value,
+            } => write!(f, "{header:?} has invalid {field} {value}"),
+            Self::StringTooLong {
+                header,
+                field,
+                length,
+            } => write!(
+                f,
+                "{header:?} has {field} of at least {length} bytes, above {MAX_STRING_LENGTH}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+type DecodeResult<T> = std::result::Result<T, DecodeError>;
+
+/// Reads fields from a message, keeping track of the offset for errors
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    header: HeaderReceive,
+    protocol: Protocol,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize, field: &'static str) -> DecodeResult<&'a [u8]> {
+        match self.bytes.get(self.offset..self.offset + count) {
+            Some(bytes) => {
+                self.offset += count;
+                Ok(bytes)
+            }
+            None => Err(DecodeError::Truncated {
+                header: self.header,
+                field,
+                offset: self.offset,
+            }),
+        }
+    }
+
+    fn u8(&mut self, field: &'static str) -> DecodeResult<u8> {
+        Ok(self.take(1, field)?[0])
+    }
+
+    fn u16(&mut self, field: &'static str) -> DecodeResult<u16> {
+        let bytes = self.take(2, field)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self, field: &'static str) -> DecodeResult<u32> {
+        let bytes = self.take(4, field)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn position(&mut self, field: &'static str) -> DecodeResult<Position> {
+        let position = match self.protocol.capabilities().position {
+            PositionFormat::Short => {
+                let bytes = self.take(2, field)?;
+                Position::new(bytes[0] as u16, bytes[1] as u16, 7)
+            }
+            PositionFormat::Long => {
+                let bytes = self.take(5, field)?;
+                Position::new(
+                    u16::from_le_bytes([bytes[0], bytes[1]]),
+                    u16::from_le_bytes([bytes[2], bytes[3]]),
+                    bytes[4],
+                )
+            }
+        };
+        Ok(position)
+    }
+
+    fn outfit_colors(&mut self, field: &'static str) -> DecodeResult<OutfitColors> {
+        let bytes = self.take(3, field)?;
+        Ok(OutfitColors::new_with_unknown_byte(
+            bytes[1] / 16,
+            bytes[1] % 16,
+            bytes[0] / 16,
+            bytes[0] % 16,
+            bytes[2],
+        ))
+    }
+
+    /// String until a null byte, which is consumed. Only the first `MAX_STRING_LENGTH` bytes
+    /// are searched for it
+    fn null_terminated_string(&mut self, field: &'static str) -> DecodeResult<String> {
+        let rest = &self.bytes[self.offset..];
+        let searched = &rest[..rest.len().min(MAX_STRING_LENGTH + 1)];
+        match searched.iter().position(|&c| c == b'\0') {
+            Some(end) => {
+                let string = rest[..end].iter().map(|&c| c as char).collect();
+                self.offset += end + 1;
+                Ok(string)
+            }
+            None if searched.len() > MAX_STRING_LENGTH => Err(DecodeError::StringTooLong {
+                header: self.header,
+                field,
+                length: searched.len(),
+            }),
+            None => Err(DecodeError::Truncated {
+                header: self.header,
+                field,
+                offset: self.offset,
+            }),
+        }
+    }
+
+    /// String of `length` bytes, ending early on a null byte
+    fn fixed_string(&mut self, length: usize, field: &'static str) -> DecodeResult<String> {
+        Ok(self
+            .take(length, field)?
+            .iter()
+            .take_while(|&&c| c != b'\0')
+            .map(|&c| c as char)
+            .collect())
+    }
+
+    fn length_bytes(&mut self, field: &'static str) -> DecodeResult<Vec<u8>> {
+        let length = self.u16(field)? as usize;
+        if length > MAX_STRING_LENGTH {
+            return Err(DecodeError::StringTooLong {
+                header: self.header,
+                field,
+                length,
+            });
+        }
+        Ok(self.take(length, field)?.to_vec())
+    }
+
+    fn rest(&mut self) -> Vec<u8> {
+        let rest = self.bytes[self.offset..].to_vec();
+        self.offset = self.bytes.len();
+        rest
+    }
+
+    fn enumeration<E: TryFrom<u8>>(&mut self, field: &'static str) -> DecodeResult<E> {
+        let value = self.u8(field)?;
+        E::try_from(value).map_err(|_| DecodeError::InvalidValue {
+            header: self.header,
+            field,
+            value: value as u32,
+        })
+    }
+}
+
+impl ClientMessage {
+    pub const fn header(&self) -> HeaderReceive {
+        match self {
+            Self::UserList => HeaderReceive::UserList,
+            Self::PlayerInfo { .. } => HeaderReceive::PlayerInfo,
+            Self::Walk { .. } => HeaderReceive::Walk,
+            Self::AutoWalk { .. } => HeaderReceive::AutoWalk,
+            Self::LookAt { .. } => HeaderReceive::LookAt,
+            Self::Chat { .. } => HeaderReceive::Chat,
+            Self::ChangeDirection { .. } => HeaderReceive::ChangeDirection,
+            Self::Comment { .. } => HeaderReceive::Comment,
+            Self::Push { .. } => HeaderReceive::Push,
+            Self::UseItem { .. } => HeaderReceive::UseItem,
+            Self::CloseContainer { .. } => HeaderReceive::CloseContainer,
+            Self::RequestChangeData => HeaderReceive::RequestChangeData,
+            Self::SetData { .. } => HeaderReceive::SetData,
+            Self::SetText { .. } => HeaderReceive::SetText,
+            Self::HouseText { .. } => HeaderReceive::HouseText,
+            Self::ChangeMode { .. } => HeaderReceive::ChangeMode,
+            Self::ExitBattle => HeaderReceive::ExitBattle,
+            Self::SetTarget { .. } => HeaderReceive::SetTarget,
+            Self::Echo => HeaderReceive::Echo,
+            Self::Logout => HeaderReceive::Logout,
+        }
+    }
+
+    /// Encodes the message with its header, as the client of the protocol sends it. Fails
+    /// when the optional fields don't match what the protocol sends
+    pub async fn encode(&self, protocol: Protocol) -> Result<Vec<u8>> {
+        let capabilities = protocol.capabilities();
+        let mut buf = Cursor::new(vec![]);
+        buf.write_u16_le(self.header() as u16).await?;
+
+        match self {
+            Self::UserList
+            | Self::RequestChangeData
+            | Self::ExitBattle
+            | Self::Echo
+            | Self::Logout => {}
+            Self::PlayerInfo { name } => buf.write_null_terminated_string(name).await?,
+            Self::Walk { direction } | Self::ChangeDirection { direction } => {
+                buf.write_u8(*direction as u8).await?;
+            }
+            Self::AutoWalk { position } | Self::LookAt { position } => {
+                buf.write_position(*position, protocol).await?;
+            }
+            Self::Chat { message } => {
+                buf.write_u16_le(message.len() as u16).await?;
+                buf.write_all(message).await?;
+            }
+            Self::Comment { comment } => buf.write_null_terminated_string(comment).await?,
+            Self::Push {
+                from,
+                object_id,
+                stack_pos,
+                to,
+                count,
+            } => {
+                buf.write_position(*from, protocol).await?;
+                buf.write_u16_le(*object_id).await?;
+                buf.write_u8(*stack_pos).await?;
+                buf.write_position(*to, protocol).await?;
+                match (capabilities.forms, count) {
+                    (MessageForms::Short, None) => {}
+                    (MessageForms::Full, Some(count)) => buf.write_u8(*count).await?,
+                    _ => return Err(anyhow!("Push count does not match {protocol:?}")),
+                }
+            }
+            Self::UseItem {
+                item_type,
+                position,
+                item_id,
+                stack_pos,
+                unknown,
+            } => {
+                buf.write_u8(*item_type).await?;
+                buf.write_position(*position, protocol).await?;
+                buf.write_u16_le(*item_id).await?;
+                buf.write_u8(*stack_pos).await?;
+                buf.write_u8(*unknown).await?;
+            }
+            Self::CloseContainer { local_id } => buf.write_u8(*local_id).await?,
+            Self::SetData { outfit, details } => {
+                match (capabilities.player_data, details) {
+                    (PlayerDataLayout::Character, None) => buf.write_outfit_colors(*outfit).await?,
+                    (PlayerDataLayout::Account | PlayerDataLayout::AccountWithComment, Some(details))
+                        if details.comment.is_some()
+                            == (capabilities.player_data == PlayerDataLayout::AccountWithComment) =>
+                    {
+                        buf.write_string_with_fixed_length(&details.password, 30).await?;
+                        buf.write_outfit_colors(*outfit).await?;
+                        buf.write_string_with_fixed_length(&details.real_name, 50).await?;
+                        buf.write_string_with_fixed_length(&details.location, 50).await?;
+                        buf.write_string_with_fixed_length(&details.email, 50).await?;
+                        if let Some(comment) = &details.comment {
+                            buf.write_string_with_fixed_length(comment, 500).await?;
+                        }
+                    }
+                    _ => return Err(anyhow!("Player details do not match {protocol:?}")),
+                }
+            }
+            Self::SetText { data } | Self::HouseText { data } => buf.write_all(data).await?,
+            Self::ChangeMode {
+                fight_mode,
+                fight_stance,
+            } => {
+                buf.write_u8(*fight_mode as u8).await?;
+                buf.write_u8(*fight_stance as u8).await?;
+            }
+            Self::SetTarget { id } => buf.write_u32_le(*id).await?,
+        }
+
+        Ok(buf.into_inner())
+    }
+
+    /// Decodes a frame sent by the client, header included and length excluded. Every byte of
+    /// the frame must be part of the message.
+    pub fn decode(bytes: &[u8], protocol: Protocol) -> DecodeResult<Self> {
+        let (message, trailing) = Self::decode_with_trailing_bytes(bytes, protocol)?;
+        if !trailing.is_empty() {
+            return Err(DecodeError::TrailingBytes {
+                header: message.header(),
+                count: trailing.len(),
+            });
+        }
+        Ok(message)
+    }
+
+    /// Like `decode`, returning the bytes left after the message instead of failing on them
+    pub fn decode_with_trailing_bytes(bytes: &[u8], protocol: Protocol) -> DecodeResult<(Self, &[u8])> {
+        if bytes.len() < 2 {
+            return Err(DecodeError::MissingHeader);
+        }
+        let raw_header = u16::from_le_bytes([bytes[0], bytes[1]]);
+        let header = HeaderReceive::try_from(raw_header)
+            .map_err(|_| DecodeError::UnknownHeader(raw_header))?;
+
+        let mut reader = Reader {
+            bytes,
+            offset: 2,
+            header,
+            protocol,
+        };
+        let message = Self::decode_body(&mut reader)?;
+        Ok((message, &bytes[reader.offset..]))
+    }
+
+    fn decode_body(reader: &mut Reader) -> DecodeResult<Self> {
+        let protocol = reader.protocol;
+        let message = match reader.header {
+            HeaderReceive::UserList => Self::UserList,
+            HeaderReceive::PlayerInfo => Self::PlayerInfo {
+                name: reader.null_terminated_string("name")?,
+            },
+            HeaderReceive::Walk => Self::Walk {
+                direction: reader.enumeration("direction")?,
+            },
+            HeaderReceive::AutoWalk => Self::AutoWalk {
+                position: reader.position("position")?,
+            },
+            HeaderReceive::LookAt => Self::LookAt {
+                position: reader.position("position")?,
+            },
+            HeaderReceive::Chat => Self::Chat {
+                message: reader.length_bytes("message")?,
+            },
+            HeaderReceive::ChangeDirection => Self::ChangeDirection {
+                direction: reader.enumeration("direction")?,
+            },
+            HeaderReceive::Comment => Self::Comment {
+                comment: reader.null_terminated_string("comment")?,
+            },
+            HeaderReceive::Push => Self::Push {
+                from: reader.position("from")?,
+                object_id: reader.u16("object_id")?,
+                stack_pos: reader.u8("stack_pos")?,
+                to: reader.position("to")?,
+                count: match protocol.capabilities().forms {
+                    MessageForms::Short => None,
+                    MessageForms::Full => Some(reader.u8("count")?),
+                },
+            },
+            HeaderReceive::UseItem => Self::UseItem {
+                item_type: reader.u8("item_type")?,
+                position: reader.position("position")?,
+                item_id: reader.u16("item_id")?,
+                stack_pos: reader.u8("stack_pos")?,
+                unknown: reader.u8("unknown")?,
+            },
+            HeaderReceive::CloseContainer => Self::CloseContainer {
+                local_id: reader.u8("local_id")?,
+            },
+            HeaderReceive::RequestChangeData => Self::RequestChangeData,
+            HeaderReceive::SetData => {
+                let player_data = protocol.capabilities().player_data;
+                if player_data != PlayerDataLayout::Character {
+                    let password = reader.fixed_string(30, "password")?;
+                    let outfit = reader.outfit_colors("outfit")?;
+                    let details = PlayerDetails {
+                        password,
+                        real_name: reader.fixed_string(50, "real_name")?,
+                        location: reader.fixed_string(50, "location")?,
+                        email: reader.fixed_string(50, "email")?,
+                        comment: if player_data == PlayerDataLayout::AccountWithComment {
+                            Some(reader.fixed_string(500, "comment")?)
+                        } else {
+                            None
+                        },
+                    };
+                    Self::SetData {
+                        outfit,
+                        details: Some(details),
+                    }
+                } else {
+                    Self::SetData {
+                        outfit: reader.outfit_colors("outfit")?,
+                        details: None,
+                    }
+                }
+            }
+            HeaderReceive::SetText => Self::SetText {
+                data: reader.rest(),
+            },
+            HeaderReceive::HouseText => Self::HouseText {
+                data: reader.rest(),
+            },
+            HeaderReceive::ChangeMode => Self::ChangeMode {
+                fight_mode: reader.enumeration("fight_mode")?,
+                fight_stance: reader.enumeration("fight_stance")?,
+            },
+            HeaderReceive::ExitBattle => Self::ExitBattle,
+            HeaderReceive::SetTarget => Self::SetTarget {
+                id: reader.u32("id")?,
+            },
+            HeaderReceive::Echo => Self::Echo,
+            HeaderReceive::Logout => Self::Logout,
+        };
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_push() {
+        let bytes = [0x14, 0x00, 0x01, 0x02, 0x34, 0x12, 0x01, 0x03, 0x04];
+        assert_eq!(
+            ClientMessage::decode(&bytes, Protocol::Tibia103),
+            Ok(ClientMessage::Push {
+                from: Position::new(1, 2, 7),
+                object_id: 0x1234,
+                stack_pos: 1,
+                to: Position::new(3, 4, 7),
+                count: None,
+            })
+        );
+        assert_eq!(
+            ClientMessage::decode(&bytes, Protocol::Tibia650),
+            Err(DecodeError::Truncated {
+                header: HeaderReceive::Push,
+                field: "stack_pos",
+                offset: 9,
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_set_data() {
+        let mut bytes = vec![0x21, 0x00];
+        bytes.extend(b"pass");
+        bytes.extend([0; 26]);
+        bytes.extend([0x34, 0x12, 0x00]);
+        bytes.extend([0; 150]);
+
+        let Ok(ClientMessage::SetData { outfit, details }) =
+            ClientMessage::decode(&bytes, Protocol::Tibia300)
+        else {
+            panic!("SetData not decoded");
+        };
+        assert_eq!(outfit, OutfitColors::new(1, 2, 3, 4));
+        let details = details.unwrap();
+        assert_eq!(details.password, "pass");
+        assert_eq!(details.comment, None);
+
+        assert!(matches!(
+            ClientMessage::decode(&bytes, Protocol::Tibia400),
+            Err(DecodeError::Truncated { field: "comment", .. })
+        ));
+        assert_eq!(
+            ClientMessage::decode(&bytes, Protocol::Tibia650),
+            Err(DecodeError::TrailingBytes {
+                header: HeaderReceive::SetData,
+                count: 180,
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(
+            ClientMessage::decode(&[0x05], Protocol::Tibia650),
+            Err(DecodeError::MissingHeader)
+        );
+        assert_eq!(
+            ClientMessage::decode(&[0x99, 0x00], Protocol::Tibia650),
+            Err(DecodeError::UnknownHeader(0x0099))
+        );
+        assert_eq!(
+            ClientMessage::decode(&[0x05, 0x00, 0x04], Protocol::Tibia650),
+            Err(DecodeError::InvalidValue {
+                header: HeaderReceive::Walk,
+                field: "direction",
+                value: 4,
+            })
+        );
+        let mut bytes = vec![0x0b, 0x00];
+        bytes.resize(2 + MAX_STRING_LENGTH + 1, b'A');
+        assert!(matches!(
+            ClientMessage::decode(&bytes, Protocol::Tibia650),
+            Err(DecodeError::StringTooLong { field: "comment", .. })
+        ));
+        assert_eq!(
+            ClientMessage::decode(&[0x04, 0x00, b'A', b'B'], Protocol::Tibia650),
+            Err(DecodeError::Truncated {
+                header: HeaderReceive::PlayerInfo,
+                field: "name",
+                offset: 2,
+            })
+        );
+    }
+}
@@ -0,0 +1,635 @@
+use crate::{
+    character::{
+        player::{InventorySlot, Skills, Stats},
+        CharacterUpdateType, Direction, Gender, HealthStatus, Outfit, OutfitColors, OutfitType,
+    },
+    chat::{encoding, ChatType},
+    constants::{MagicEffect, ObjectUpdateType},
+    io::WriteExt,
+    map::{
+        position::{Position, VIEWPORT_HEIGHT, VIEWPORT_WIDTH},
+        TileObject, MAP,
+    },
+    network::header::{AuxiliaryHeaderSend, HeaderSend},
+    protocol::{MessageForms, PlayerDataLayout, PositionFormat, SkillLayout, StatsLayout},
+    Protocol,
+};
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+use std::io::Cursor;
+use tokio::io::AsyncWriteExt;
+
+/// The player a message is built for, drawn on its own tile in map descriptions
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PlayerView {
+    pub id: u32,
+    pub name: String,
+    pub position: Position,
+    pub outfit: OutfitColors,
+}
+
+/// Message sent to the client, independent of the protocol until encoded
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ServerMessage {
+    Login {
+        player_id: u32,
+    },
+    Error(String),
+    Info(String),
+    StatusMessage(String),
+    MessageOfTheDay {
+        number: u16,
+        message: String,
+    },
+    DataWindow {
+        name: String,
+        gender: Gender,
+        outfit: OutfitColors,
+    },
+    /// Map around the player, used on login
+    Map {
+        player: PlayerView,
+    },
+    /// New row or column of the map after the player moved to `player.position`
+    MoveOneTile {
+        direction: Direction,
+        player: PlayerView,
+    },
+    EquippedItem {
+        slot: InventorySlot,
+        item: u16,
+        stack: u8,
+    },
+    UpdateObject {
+        position: Position,
+        update_type: ObjectUpdateType,
+        stack_pos: u8,
+    },
+    UpdateCharacter {
+        id: u32,
+        update_type: CharacterUpdateType,
+        value: u8,
+    },
+    UpdateOutfit {
+        id: u32,
+        outfit_type: OutfitType,
+        colors: OutfitColors,
+    },
+    ChangeDirection {
+        id: u32,
+        direction: Direction,
+    },
+    /// Character drawn on the tile of the preceding update that added an object
+    Character {
+        id: u32,
+        name: String,
+        direction: Direction,
+        outfit: Outfit,
+    },
+    MagicEffect {
+        effect: MagicEffect,
+        position: Position,
+    },
+    WorldLight(u8),
+    Stats(Stats),
+    Skills(Skills),
+    Chat {
+        chat_type: ChatType,
+        message: String,
+        sender: Option<String>,
+        position: Option<Position>,
+    },
+    GreenChat(String),
+    UserInfo {
+        name: String,
+    },
+    UserList {
+        names: Vec<String>,
+    },
+    OpenContainer {
+        local_id: u8,
+        item_id: u16,
+        items: Vec<u16>,
+    },
+    CloseContainer {
+        local_id: u8,
+    },
+    /// Messages of unknown meaning, made of the header only
+    Empty(HeaderSend),
+    /// Arbitrary bytes after an optional header code, to experiment with messages of unknown
+    /// layout. The code is sent even if the client is not known to handle it
+    Raw {
+        header: Option<u16>,
+        payload: Vec<u8>,
+    },
+    /// Messages sent together, e.g. a message followed by its auxiliary messages
+    Compound(Vec<ServerMessage>),
+}
+
+impl ServerMessage {
+    /// Non-essential messages may be dropped when the client can't keep up
+    pub fn is_essential(&self) -> bool {
+        match self {
+            Self::WorldLight(_) => false,
+            Self::Compound(messages) => messages.iter().any(Self::is_essential),
+            _ => true,
+        }
+    }
+
+    /// Encodes the message with its header. Some messages are not supported by every
+    /// protocol and encode to nothing.
+    pub async fn encode(&self, protocol: Protocol) -> Result<Vec<u8>> {
+        let capabilities = protocol.capabilities();
+        let full_forms = capabilities.forms == MessageForms::Full;
+        let mut buf = Cursor::new(vec![]);
+
+        match self {
+            Self::Login { player_id } => {
+                buf.write_header(HeaderSend::Login, protocol).await?;
+                if full_forms {
+                    buf.write_u32_le(*player_id).await?;
+                }
+            }
+            Self::Error(message) => {
+                buf.write_header(HeaderSend::Error, protocol).await?;
+                buf.write_null_terminated_string(message).await?;
+            }
+            Self::Info(message) => {
+                buf.write_header(HeaderSend::Info, protocol).await?;
+                buf.write_null_terminated_string(message).await?;
+            }
+            Self::StatusMessage(message) => {
+                buf.write_header(HeaderSend::StatusMessage, protocol).await?;
+                buf.write_null_terminated_string(message).await?;
+            }
+            Self::MessageOfTheDay { number, message } => {
+                buf.write_header(HeaderSend::MessageOfTheDay, protocol).await?;
+                if capabilities.numbered_message_of_the_day {
+                    buf.write_u16_le(*number).await?;
+                    buf.write_u8(0x0a).await?;
+                }
+                buf.write_null_terminated_string(message).await?;
+            }
+            Self::DataWindow {
+                name,
+                gender,
+                outfit,
+            } => {
+                buf.write_header(HeaderSend::DataWindow, protocol).await?;
+                buf.write_string_with_fixed_length(name, 30).await?;
+
+                match capabilities.player_data {
+                    PlayerDataLayout::Account | PlayerDataLayout::AccountWithComment => {
+                        buf.write_string_with_fixed_length("password", 30).await?;
+                        buf.write_gender(*gender, protocol).await?;
+                        buf.write_outfit_colors(*outfit).await?;
+                        buf.write_string_with_fixed_length("realname", 50).await?;
+                        buf.write_string_with_fixed_length("location", 50).await?;
+                        buf.write_string_with_fixed_length("email", 50).await?;
+                        if capabilities.player_data == PlayerDataLayout::AccountWithComment {
+                            buf.write_string_with_fixed_length("comment", 500).await?;
+                        }
+                    }
+                    PlayerDataLayout::Character => {
+                        buf.write_gender(*gender, protocol).await?;
+                        buf.write_outfit_colors(*outfit).await?;
+                    }
+                }
+            }
+            Self::Map { player } => {
+                buf.write_header(HeaderSend::Map, protocol).await?;
+                buf.write_position(player.position, protocol).await?;
+                buf.write_all(
+                    &encode_map(
+                        protocol,
+                        player,
+                        player.position,
+                        VIEWPORT_WIDTH,
+                        VIEWPORT_HEIGHT,
+                        map_layers(protocol),
+                    )
+                    .await?,
+                )
+                .await?;
+            }
+            Self::MoveOneTile { direction, player } => {
+                let (width, height) = match direction {
+                    Direction::North | Direction::South => (VIEWPORT_WIDTH, 1),
+                    Direction::East | Direction::West => (1, VIEWPORT_HEIGHT),
+                };
+                let center = player.position
+                    + match direction {
+                        Direction::North => (0, -6, 0),
+                        Direction::East => (9, 0, 0),
+                        Direction::South => (0, 7, 0),
+                        Direction::West => (-8, 0, 0),
+                    };
+                log::trace!("center = {center:?}");
+
+                buf.write_header((*direction).into(), protocol).await?;
+                buf.write_all(
+                    &encode_map(protocol, player, center, width, height, map_layers(protocol))
+                        .await?,
+                )
+                .await?;
+            }
+            Self::EquippedItem { slot, item, stack } => {
+                buf.write_header(HeaderSend::EquippedItem, protocol).await?;
+                if full_forms {
+                    buf.write_u8(*slot as u8).await?;
+                    buf.write_u16_le(*item).await?;
+                    buf.write_u8(*stack).await?;
+                } else {
+                    buf.write_u16_le(*item).await?;
+                    buf.write_u8(*slot as u8).await?;
+                }
+            }
+            Self::UpdateObject {
+                position,
+                update_type,
+                stack_pos,
+            } => {
+                if !full_forms {
+                    // buf.write_header(HeaderSend::UpdateObject, protocol).await?;
+                    // buf.write_position(*position, protocol).await?;
+                    // buf.write_u8(update_type.to_protocol_103_type() as u8).await?;
+                    // buf.write_u8(*stack_pos).await?;
+                } else {
+                    buf.write_header(HeaderSend::UpdateObject, protocol).await?;
+                    buf.write_position(*position, protocol).await?;
+                    buf.write_u8(*update_type as u8).await?;
+                    buf.write_u8(*stack_pos).await?;
+
+                    //remove light?
+                    if *update_type == ObjectUpdateType::Remove {
+                        buf.write_zeroes(6).await?;
+                    }
+                }
+            }
+            Self::UpdateCharacter {
+                id,
+                update_type,
+                value,
+            } => {
+                buf.write_header(HeaderSend::UpdateCharacter, protocol).await?;
+                buf.write_u32_le(*id).await?;
+                buf.write_u8(*update_type as u8).await?;
+                buf.write_u8(*value).await?;
+            }
+            Self::UpdateOutfit {
+                id,
+                outfit_type,
+                colors,
+            } => {
+                if !full_forms {
+                    // buf.write_header(HeaderSend::UpdateObject, protocol).await?;
+                    // buf.write_position(position, protocol).await?;
+                    // buf.write_u8(ObjectUpdateType::Update as u8).await?;
+                } else {
+                    buf.write_header(HeaderSend::UpdateCharacter, protocol).await?;
+                    buf.write_u32_le(*id).await?;
+                    buf.write_u8(CharacterUpdateType::Outfit as u8).await?;
+                    buf.write_u8(*outfit_type as u8).await?;
+                    buf.write_outfit_colors(*colors).await?;
+                }
+            }
+            Self::ChangeDirection { id, direction } => {
+                if full_forms {
+                    buf.write_u8(AuxiliaryHeaderSend::ChangeDirection as u8).await?;
+                    buf.write_u8(*direction as u8).await?;
+                    buf.write_u32_le(*id).await?;
+                }
+            }
+            Self::Character {
+                id,
+                name,
+                direction,
+                outfit,
+            } => {
+                if full_forms {
+                    buf.write_all(&encode_character(*id, name, *outfit, *direction).await?)
+                        .await?;
+                }
+            }
+            Self::MagicEffect { effect, position } => {
+                buf.write_header(HeaderSend::MagicEffect, protocol).await?;
+                buf.write_position(*position, protocol).await?;
+                buf.write_u8(*effect as u8).await?;
+            }
+            Self::WorldLight(light_level) => {
+                buf.write_header(HeaderSend::WorldLight, protocol).await?;
+                buf.write_u8(*light_level).await?;
+            }
+            Self::Stats(stats) => {
+                buf.write_header(HeaderSend::Stats, protocol).await?;
+                buf.write_u16_le(stats.health_points).await?;
+                buf.write_u16_le(stats.capacity).await?;
+                match capabilities.stats {
+                    StatsLayout::Basic => {}
+                    StatsLayout::Tibia300 => {
+                        buf.write_u8(stats.intelligence).await?;
+                        buf.write_u8(stats.strength).await?;
+                        buf.write_u8(stats.dexterity).await?;
+                        buf.write_u16_le(stats.experience_points as u16).await?;
+                        buf.write_u8(stats.experience_level).await?;
+                    }
+                    StatsLayout::Standard => {
+                        buf.write_u32_le(stats.experience_points).await?;
+                        buf.write_u8(stats.experience_level).await?;
+                        buf.write_u16_le(stats.mana_points).await?;
+                        buf.write_u8(stats.magic_level).await?;
+                        buf.write_u16_le(stats.ammunition).await?;
+                    }
+                }
+            }
+            Self::Skills(skills) => {
+                buf.write_header(HeaderSend::Skills, protocol).await?;
+                if capabilities.skills == SkillLayout::Tibia400 {
+                    buf.write_u8(skills.sword).await?;
+                    buf.write_u8(skills.club).await?;
+                    buf.write_u8(skills.gauche).await?;
+                    buf.write_u8(skills.fist).await?;
+                    buf.write_u8(skills.missile).await?;
+                    buf.write_u8(skills.shield).await?;
+                    buf.write_u8(skills.distance).await?; //throwing on v4
+                    buf.write_u8(skills.fishing).await?;
+                } else {
+                    buf.write_u8(skills.sword).await?;
+                    buf.write_u8(skills.club).await?;
+                    buf.write_u8(skills.axe).await?;
+                    buf.write_u8(skills.distance).await?;
+                    buf.write_u8(skills.shield).await?;
+                    buf.write_u8(skills.fist).await?;
+                    buf.write_u8(skills.fishing).await?;
+                }
+            }
+            Self::Chat {
+                chat_type,
+                message,
+                sender,
+                position,
+            } => {
+                let message = match chat_type {
+                    ChatType::Yell => encoding::translate_upper(&message.to_uppercase()),
+                    _ => encoding::translate(message),
+                };
+                let position = position.map(|p| p + capabilities.chat_position_offset);
+
+                buf.write_header(HeaderSend::Chat, protocol).await?;
+                buf.write_position(position.unwrap_or(Position::new(0, 0, 0)), protocol)
+                    .await?;
+                buf.write_u8(*chat_type as u8).await?;
+                if let Some(sender) = sender {
+                    buf.write_all(sender.as_bytes()).await?;
+                    buf.write_u8(0x09).await?; //TAB
+                }
+                buf.write_all(&message).await?;
+                buf.write_u8(0x00).await?;
+            }
+            Self::GreenChat(message) => {
+                buf.write_header(HeaderSend::GreenChat, protocol).await?;
+                buf.write_null_terminated_string(message).await?;
+            }
+            Self::UserInfo { name } => {
+                buf.write_header(HeaderSend::UserInfo, protocol).await?;
+                buf.write_u16_le(0x1010).await?; //# of bytes to allocate for text
+                buf.write_null_terminated_string(&format!("INFO: name={name}"))
+                    .await?;
+            }
+            Self::UserList { names } => {
+                buf.write_header(HeaderSend::UserList, protocol).await?;
+                buf.write_u16_le(0x1010).await?; //# of bytes to allocate for text
+                for name in names {
+                    buf.write_all(name.as_bytes()).await?;
+                    buf.write_u8(b'\n').await?;
+                }
+                buf.write_u8(0).await?;
+            }
+            Self::OpenContainer {
+                local_id,
+                item_id,
+                items,
+            } => {
+                buf.write_header(HeaderSend::OpenContainer, protocol).await?;
+                buf.write_u8(*local_id).await?;
+                buf.write_u16_le(*item_id).await?;
+                for item in items {
+                    buf.write_u16_le(*item).await?;
+                }
+                buf.write_u16_le(0xffff).await?;
+            }
+            Self::CloseContainer { local_id } => {
+                buf.write_header(HeaderSend::CloseContainer, protocol).await?;
+                buf.write_u8(*local_id).await?;
+            }
+            Self::Empty(header) => {
+                buf.write_header(*header, protocol).await?;
+            }
+            Self::Raw { header, payload } => {
+                if let Some(header) = header {
+                    buf.write_header_code(*header, protocol).await?;
+                }
+                buf.write_all(payload).await?;
+            }
+            Self::Compound(messages) => {
+                for message in messages {
+                    buf.write_all(&Box::pin(message.encode(protocol)).await?).await?;
+                }
+            }
+        }
+
+        Ok(buf.into_inner())
+    }
+}
+
+const fn map_layers(protocol: Protocol) -> u8 {
+    match protocol.capabilities().position {
+        PositionFormat::Short => 1,
+        PositionFormat::Long => 3,
+    }
+}
+
+async fn encode_map(
+    protocol: Protocol,
+    player: &PlayerView,
+    position: Position,
+    width: u16,
+    height: u16,
+    layers: u8,
+) -> Result<Vec<u8>> {
+    let mut buf = Cursor::new(vec![]);
+
+    let corner = position - (((width as i16 - 1) / 2), ((height as i16 - 1) / 2), 0);
+    let corner_2 = corner + (width as i16 - 1, height as i16 - 1, layers as i8 - 1);
+
+    log::trace!("center = {position:?}, corner_1 = {corner:?}, corner_2 = {corner_2:?}");
+    log::trace!("width = {width:?}, height={height:?}, layers={layers:?}");
+
+    for z in 0..layers {
+        for x in 0..width {
+            for y in 0..height {
+                let position = corner + (x as i16, y as i16, z as i8);
+                buf.write_all(&encode_tile(protocol, player, position).await?)
+                    .await?;
+            }
+        }
+    }
+    buf.set_position(buf.position() - 1);
+    buf.write_u8(0xfe).await?;
+    buf.write_u8(0x00).await?;
+
+    Ok(buf.into_inner())
+}
+
+async fn encode_tile(protocol: Protocol, player: &PlayerView, position: Position) -> Result<Vec<u8>> {
+    let full_forms = protocol.capabilities().forms == MessageForms::Full;
+    let mut buf = Cursor::new(vec![]);
+    if let Some(tile) = MAP.get().unwrap().get_tile_objects(position) {
+        for tile_object in tile {
+            match tile_object {
+                TileObject::Other(tile_id) => buf.write_u16_le(*tile_id).await?,
+                TileObject::FluidContainer(tile_id, fluid) => {
+                    buf.write_u16_le(*tile_id).await?;
+                    if full_forms {
+                        buf.write_u8(*fluid as u8).await?;
+                    }
+                }
+                TileObject::LightSource(tile_id, light_level) => {
+                    buf.write_u16_le(*tile_id).await?;
+                    if full_forms {
+                        buf.write_u8(*light_level).await?;
+                    }
+                }
+                TileObject::Stackable(tile_id, count) => {
+                    buf.write_u16_le(*tile_id).await?;
+                    if full_forms {
+                        buf.write_u8(*count).await?;
+                    }
+                }
+                TileObject::Creature(id, name, outfit) => {
+                    if full_forms {
+                        buf.write_all(&encode_character(*id, name, *outfit, Direction::South).await?)
+                            .await?;
+                    }
+                }
+            }
+        }
+    }
+
+    if position == player.position {
+        if full_forms {
+            buf.write_all(&encode_character(player.id, &player.name, Outfit::human(player.outfit), Direction::South).await?)
+                .await?;
+        } else {
+            buf.write_u8(AuxiliaryHeaderSend::Character as u8).await?;
+            buf.write_outfit_colors(player.outfit).await?;
+        }
+    }
+
+    if !full_forms {
+        buf.write_u8(0xff).await?;
+    }
+
+    buf.write_u8(0xff).await?;
+    Ok(buf.into_inner())
+}
+
+/// Where a player is in the stack of its tile. Players are described after the objects of their
+/// tile, see `encode_tile`, and never share one
+pub fn player_stack_pos(protocol: Protocol, position: Position) -> u8 {
+    let full_forms = protocol.capabilities().forms == MessageForms::Full;
+    let described = MAP.get().unwrap().get_tile_objects(position).map_or(0, |tile| {
+        tile.iter()
+            .filter(|tile_object| full_forms || !matches!(tile_object, TileObject::Creature(..)))
+            .count()
+    });
+    u8::try_from(described).unwrap_or(u8::MAX)
+}
+
+async fn encode_character(id: u32, name: &str, outfit: Outfit, direction: Direction) -> Result<Vec<u8>> {
+    let mut buf = Cursor::new(vec![]);
+
+    buf.write_u8(AuxiliaryHeaderSend::Character as u8).await?;
+    buf.write_u32_le(0).await?; //knows creature
+    buf.write_u32_le(id).await?;
+    buf.write_string_with_fixed_length(name, 30).await?;
+    buf.write_u8(HealthStatus::Healthy as u8).await?;
+    buf.write_u8(direction as u8).await?;
+
+    buf.write_u8(outfit.outfit_type as u8).await?;
+    buf.write_outfit_colors(outfit.colors).await?;
+
+    //light level=0
+    buf.write_u8(0).await?;
+
+    Ok(buf.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_encode_chat() -> Result<()> {
+        let message = ServerMessage::Chat {
+            chat_type: ChatType::Normal,
+            message: "hi".to_string(),
+            sender: Some("Bob".to_string()),
+            position: Some(Position::new(50, 50, 7)),
+        };
+
+        assert_eq!(
+            message.encode(Protocol::Tibia650).await?,
+            [
+                0x65, 0x00, 0x32, 0x00, 0x32, 0x00, 0x07, 0x53, b'B', b'o', b'b', 0x09, b'h', b'i',
+                0x00
+            ]
+        );
+        assert_eq!(
+            message.encode(Protocol::Tibia103).await?,
+            [0x00, 0x00, 0x00, 0x00, 0x65, 0x32, 0x32, 0x53, b'B', b'o', b'b', 0x09, b'h', b'i', 0x00]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encode_raw() -> Result<()> {
+        let message = ServerMessage::Raw {
+            header: Some(0x0033),
+            payload: vec![0x01, 0x02],
+        };
+
+        assert_eq!(message.encode(Protocol::Tibia650).await?, [0x33, 0x00, 0x01, 0x02]);
+        assert_eq!(message.encode(Protocol::Tibia400).await?, [0x33, 0x01, 0x02]);
+        assert_eq!(message.encode(Protocol::Tibia103).await?, [0x00, 0x00, 0x00, 0x00, 0x33, 0x01, 0x02]);
+        assert!(ServerMessage::Raw { header: Some(0x0133), payload: vec![] }
+            .encode(Protocol::Tibia400)
+            .await
+            .is_err());
+        assert_eq!(
+            ServerMessage::Raw { header: None, payload: vec![0x0f, 0x00] }
+                .encode(Protocol::Tibia650)
+                .await?,
+            [0x0f, 0x00]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encode_unsupported() -> Result<()> {
+        assert!(ServerMessage::WorldLight(6).encode(Protocol::Tibia103).await.is_err());
+        assert!(
+            ServerMessage::ChangeDirection {
+                id: 1,
+                direction: Direction::North
+            }
+            .encode(Protocol::Tibia103)
+            .await?
+            .is_empty()
+        );
+
+        Ok(())
+    }
+}
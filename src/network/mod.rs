@@ -1,2 +1,8 @@
 pub mod connection;
+pub mod dissector;
+pub mod docs;
 pub mod header;
+pub mod layout;
+pub mod message;
+pub mod proxy;
+pub mod replay;
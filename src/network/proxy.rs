@@ -0,0 +1,233 @@
+//! Sits between a client and another server, forwarding every byte unchanged except for the
+//! character list, and logging both directions decoded field by field.
+use crate::{
+    network::{
+        connection::recorder::RecordDirection,
+        dissector,
+        layout::LoginMessage,
+    },
+    Protocol,
+};
+use anyhow::{anyhow, Result};
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, SocketAddrV4},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task,
+};
+
+/// Game server of each character seen in a character list, where the client's second
+/// connection has to go
+type GameServers = Arc<Mutex<HashMap<String, SocketAddr>>>;
+
+/// Accepts clients on the listener and forwards each of them to `upstream`, or to the game
+/// server of the character when logging in with a character from a proxied character list
+pub async fn proxy(listener: TcpListener, upstream: SocketAddr) -> Result<()> {
+    log::info!("Proxy listening on address {}, upstream {upstream}", listener.local_addr()?);
+    let game_servers = GameServers::default();
+
+    loop {
+        let (client, client_address) = listener.accept().await?;
+        log::info!("New proxied connection: {client_address}");
+
+        let game_servers = game_servers.clone();
+        task::spawn(async move {
+            match handle_client(client, upstream, game_servers).await {
+                Ok(()) => log::info!("Proxied connection with {client_address} finished."),
+                Err(err) => log::error!("Proxied connection with {client_address} failed: {err}"),
+            }
+        });
+    }
+}
+
+async fn handle_client(mut client: TcpStream, upstream: SocketAddr, game_servers: GameServers) -> Result<()> {
+    let login = read_frame(&mut client, false)
+        .await?
+        .ok_or_else(|| anyhow!("Client disconnected before login"))?;
+    let login_kind = LoginMessage::from_length(login.len() as u16);
+
+    let decoded = dissector::decode_login_frame(&login).await;
+    let upstream = match &decoded {
+        Ok(message) => {
+            let name = message.text("name").unwrap_or_default();
+            game_servers.lock().unwrap().get(name).copied().unwrap_or(upstream)
+        }
+        Err(_) => upstream,
+    };
+
+    let mut server = TcpStream::connect(upstream).await?;
+    log::info!("Forwarding {} to {upstream}", client.peer_addr()?);
+    write_frame(&mut server, &login, false).await?;
+
+    // The login is already upstream, so an unknown protocol is passed through like an unknown
+    // login instead of ending the session
+    let protocol = decoded.and_then(|message| {
+        log::info!("[{:?}] {message}", RecordDirection::ClientToServer);
+        Ok(Protocol::try_from(message.number("protocol").unwrap_or_default() as u16)?)
+    });
+    let protocol = match protocol {
+        Ok(protocol) => {
+            log::info!("Protocol detected: {protocol:?}");
+            protocol
+        }
+        Err(err) => {
+            log::warn!("Unknown login message, forwarding without decoding: {err}");
+            tokio::io::copy_bidirectional(&mut client, &mut server).await?;
+            return Ok(());
+        }
+    };
+
+    if login_kind == LoginMessage::AccountLogin {
+        let mut character_list = read_frame(&mut server, false)
+            .await?
+            .ok_or_else(|| anyhow!("Server disconnected before sending the character list"))?;
+        match dissector::decode_character_list(&character_list, protocol).await {
+            Ok(message) => log::info!("[{:?}] {message}", RecordDirection::ServerToClient),
+            Err(err) => log::warn!("Error decoding character list: {err}"),
+        }
+
+        let proxy_address = match client.local_addr()? {
+            SocketAddr::V4(address) => address,
+            SocketAddr::V6(_) => return Err(anyhow!("Game does not support ipv6")),
+        };
+        for (name, address) in rewrite_character_list(&mut character_list, proxy_address)? {
+            log::info!("Character {name} redirected from {address} to {proxy_address}");
+            game_servers.lock().unwrap().insert(name, address.into());
+        }
+
+        write_frame(&mut client, &character_list, false).await?;
+        tokio::io::copy_bidirectional(&mut client, &mut server).await?;
+        return Ok(());
+    }
+
+    let (client_reader, client_writer) = client.into_split();
+    let (server_reader, server_writer) = server.into_split();
+    tokio::try_join!(
+        forward(client_reader, server_writer, RecordDirection::ClientToServer, protocol),
+        forward(server_reader, client_writer, RecordDirection::ServerToClient, protocol),
+    )?;
+
+    Ok(())
+}
+
+/// Forwards frames until the reader is closed, logging their messages
+async fn forward<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    mut reader: R,
+    mut writer: W,
+    direction: RecordDirection,
+    protocol: Protocol,
+) -> Result<()> {
+    let from_server = direction == RecordDirection::ServerToClient;
+    while let Some(frame) = read_frame(&mut reader, from_server).await? {
+        let decoded = if from_server {
+            dissector::decode_server_frame(&frame, protocol).await
+        } else {
+            dissector::decode_client_frame(&frame, protocol)
+                .await
+                .map(|message| vec![message])
+        };
+        match decoded {
+            Ok(messages) => {
+                for message in messages {
+                    log::info!("[{direction:?}] {message}");
+                }
+            }
+            Err(err) => log::warn!("[{direction:?}] Error decoding {frame:02x?}: {err}"),
+        }
+
+        write_frame(&mut writer, &frame, from_server).await?;
+    }
+
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Reads a frame, without its length. Returns `None` when the stream ends before a new frame.
+/// Only the frames sent by the server after the login count the 2 bytes of their length.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, length_counts_itself: bool) -> Result<Option<Vec<u8>>> {
+    let length = match reader.read_u16_le().await {
+        Ok(length) => length as usize,
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let length = if length_counts_itself {
+        length
+            .checked_sub(2)
+            .ok_or_else(|| anyhow!("Invalid frame length {length}"))?
+    } else {
+        length
+    };
+
+    let mut frame = vec![0_u8; length];
+    reader.read_exact(&mut frame).await?;
+    Ok(Some(frame))
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &[u8], length_counts_itself: bool) -> Result<()> {
+    let length = if length_counts_itself {
+        frame.len() + 2
+    } else {
+        frame.len()
+    };
+    writer.write_u16_le(length as u16).await?;
+    writer.write_all(frame).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Points every character of the list to `address`, returning the name and original address of
+/// each character. Same layout as `prepare_character_list`
+fn rewrite_character_list(frame: &mut [u8], address: SocketAddrV4) -> Result<Vec<(String, SocketAddrV4)>> {
+    let too_short = || anyhow!("Character list too short");
+
+    let count = *frame.get(1).ok_or_else(too_short)?;
+    let mut offset = 2;
+    let mut characters = vec![];
+    for _ in 0..count {
+        let mut strings = vec![];
+        for _ in 0..2 {
+            let length = frame.get(offset..offset + 2).ok_or_else(too_short)?;
+            let length = u16::from_le_bytes([length[0], length[1]]) as usize;
+            let string = frame.get(offset + 2..offset + 2 + length).ok_or_else(too_short)?;
+            strings.push(string.iter().map(|&c| c as char).collect::<String>());
+            offset += 2 + length;
+        }
+
+        let entry = frame.get_mut(offset..offset + 6).ok_or_else(too_short)?;
+        let ip = [entry[0], entry[1], entry[2], entry[3]];
+        let port = u16::from_le_bytes([entry[4], entry[5]]);
+        entry[..4].copy_from_slice(&address.ip().octets());
+        entry[4..].copy_from_slice(&address.port().to_le_bytes());
+        offset += 6;
+
+        characters.push((strings.swap_remove(0), SocketAddrV4::new(ip.into(), port)));
+    }
+
+    Ok(characters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_character_list() -> Result<()> {
+        let mut frame = vec![0x64, 0x01, 0x03, 0x00, b'B', b'o', b'b', 0x02, 0x00, b'l', b'b'];
+        frame.extend([10, 0, 0, 1, 0x03, 0x1c]);
+        let proxy_address = SocketAddrV4::new([127, 0, 0, 1].into(), 7172);
+
+        let characters = rewrite_character_list(&mut frame, proxy_address)?;
+
+        assert_eq!(
+            characters,
+            vec![("Bob".to_string(), SocketAddrV4::new([10, 0, 0, 1].into(), 7171))]
+        );
+        assert_eq!(&frame[11..], &[127, 0, 0, 1, 0x04, 0x1c]);
+        assert!(rewrite_character_list(&mut frame[..12], proxy_address).is_err());
+        Ok(())
+    }
+}
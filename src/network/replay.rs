@@ -0,0 +1,360 @@
+use crate::{
+    network::{
+        connection::{
+            recorder::{self, RecordDirection, RecordEntry},
+            Connection,
+        },
+        dissector::{
+            decode_character_list, decode_server_frame, split_client_frames, split_server_frames,
+            FieldValue, MessageKind,
+        },
+        header::HeaderSend,
+        layout::LoginMessage,
+    },
+    world::message::PlayerToWorldMessage,
+    Protocol,
+};
+use anyhow::{anyhow, Result};
+use std::{fmt::Display, path::Path, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::Sender,
+    task,
+    time::timeout,
+};
+
+/// How long the client waits for each frame the server sent in the recording
+const FRAME_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A frame sent by the client in the recording, with the number of server frames recorded after
+/// it. Steps and chat are answered by the world, after a round trip through its queue, while
+/// the connection answers everything else right away. Sent all at once, the client frames would
+/// be answered in another order than recorded, so each one waits for its answers
+struct Step {
+    frame: Vec<u8>,
+    answers: usize,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct FrameDifference {
+    pub frame: usize,
+    pub offset: usize,
+    pub expected: Option<Vec<u8>>,
+    pub actual: Option<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub struct ReplayReport {
+    pub expected_frames: usize,
+    pub actual_frames: usize,
+    pub differences: Vec<FrameDifference>,
+}
+
+/// Replays the client side of a recording against a server running in this process,
+/// comparing every frame sent by the server with the recorded ones.
+pub async fn replay(path: &Path, sender: Sender<PlayerToWorldMessage>) -> Result<ReplayReport> {
+    let entries: Vec<RecordEntry> = recorder::read_json_lines(path)?;
+    let protocol = entries
+        .first()
+        .ok_or_else(|| anyhow!("Recording {path:?} is empty"))?
+        .protocol()?;
+    log::info!("Replaying {} entries from {path:?}, protocol={protocol:?}", entries.len());
+
+    let login = match entries.iter().find(|entry| entry.direction == RecordDirection::ClientToServer) {
+        Some(entry) => {
+            let bytes = entry.bytes()?;
+            let frame = split_client_frames(&bytes)?
+                .first()
+                .copied()
+                .ok_or_else(|| anyhow!("Recording {path:?} has no login"))?;
+            LoginMessage::from_length(frame.len() as u16)
+        }
+        None => return Err(anyhow!("Recording {path:?} has no login")),
+    };
+
+    let mut steps: Vec<Step> = vec![];
+    let mut expected = vec![];
+    for entry in entries.iter() {
+        let bytes = entry.bytes()?;
+        match entry.direction {
+            RecordDirection::ClientToServer => steps.push(Step {
+                frame: bytes,
+                answers: 0,
+            }),
+            RecordDirection::ServerToClient => {
+                if let Some(step) = steps.last_mut() {
+                    step.answers += split_frames(&bytes, login)?.len();
+                }
+                expected.extend(bytes);
+            }
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let server_address = listener.local_addr()?;
+    let server = task::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        Connection::run(stream, sender).await;
+        Ok::<(), anyhow::Error>(())
+    });
+
+    let (mut reader, mut writer) = TcpStream::connect(server_address).await?.into_split();
+    let mut actual = vec![];
+    for step in steps {
+        writer.write_all(&step.frame).await?;
+        writer.flush().await?;
+        for _ in 0..step.answers {
+            match timeout(FRAME_TIMEOUT, read_frame(&mut reader, login)).await {
+                Ok(Ok(frame)) => actual.extend(frame),
+                // Missing frames show up in the comparison
+                Ok(Err(_)) | Err(_) => break,
+            }
+        }
+    }
+    writer.shutdown().await?;
+    reader.read_to_end(&mut actual).await?;
+    server.await??;
+
+    let expected = match login {
+        LoginMessage::AccountLogin => rebase_game_server(&expected, &actual).await?,
+        LoginMessage::PlayerLogin | LoginMessage::NewPlayer => {
+            rebase_player_id(&expected, &actual, protocol).await?
+        }
+    };
+    let report = compare_frames(split_frames(&expected, login)?, split_frames(&actual, login)?);
+    log::info!("{report}");
+    Ok(report)
+}
+
+/// Splits the bytes sent by the server into frames. Account logins are answered with the
+/// character list, whose length does not count itself
+fn split_frames(bytes: &[u8], login: LoginMessage) -> Result<Vec<&[u8]>> {
+    match login {
+        LoginMessage::AccountLogin => split_client_frames(bytes),
+        LoginMessage::PlayerLogin | LoginMessage::NewPlayer => split_server_frames(bytes),
+    }
+}
+
+/// Reads a server frame, with its length
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, login: LoginMessage) -> Result<Vec<u8>> {
+    let length = reader.read_u16_le().await?;
+    let frame_length = match login {
+        LoginMessage::AccountLogin => length as usize + 2,
+        LoginMessage::PlayerLogin | LoginMessage::NewPlayer if length < 2 => {
+            return Err(anyhow!("Invalid frame length {length}"));
+        }
+        LoginMessage::PlayerLogin | LoginMessage::NewPlayer => length as usize,
+    };
+    let mut frame = vec![0_u8; frame_length];
+    frame[..2].copy_from_slice(&length.to_le_bytes());
+    reader.read_exact(&mut frame[2..]).await?;
+    Ok(frame)
+}
+
+/// Player ids come from a counter shared by every session of the server, so the replayed player
+/// only gets the recorded id when both sessions were the first of their server. The recorded id
+/// is replaced by the replayed one wherever it appears as a u32, map descriptions included
+pub async fn rebase_player_id(expected: &[u8], actual: &[u8], protocol: Protocol) -> Result<Vec<u8>> {
+    let (Some(recorded), Some(replayed)) = (player_id(expected, protocol).await, player_id(actual, protocol).await)
+    else {
+        return Ok(expected.to_vec());
+    };
+
+    let mut rebased = vec![];
+    for frame in split_server_frames(expected)? {
+        let (length, body) = frame.split_at(2);
+        rebased.extend(length);
+        let mut offset = 0;
+        while offset < body.len() {
+            if body[offset..].starts_with(&recorded.to_le_bytes()) {
+                rebased.extend(replayed.to_le_bytes());
+                offset += 4;
+            } else {
+                rebased.push(body[offset]);
+                offset += 1;
+            }
+        }
+    }
+    Ok(rebased)
+}
+
+/// Character lists send the client to the address of the game server, and the replayed server
+/// listens on another port than the recorded one. The recorded address is replaced by the
+/// replayed one
+pub async fn rebase_game_server(expected: &[u8], actual: &[u8]) -> Result<Vec<u8>> {
+    let (Some(recorded), Some(replayed)) = (game_server(expected).await, game_server(actual).await) else {
+        return Ok(expected.to_vec());
+    };
+
+    let mut rebased = vec![];
+    for frame in split_client_frames(expected)? {
+        rebased.extend((frame.len() as u16).to_le_bytes());
+        let mut offset = 0;
+        while offset < frame.len() {
+            if frame[offset..].starts_with(&recorded) {
+                rebased.extend(replayed);
+                offset += recorded.len();
+            } else {
+                rebased.push(frame[offset]);
+                offset += 1;
+            }
+        }
+    }
+    Ok(rebased)
+}
+
+/// The address of the first character of a character list, as sent
+async fn game_server(bytes: &[u8]) -> Option<[u8; 6]> {
+    let frame = *split_client_frames(bytes).ok()?.first()?;
+    // Character lists are the same on every protocol that has them
+    let message = decode_character_list(frame, Protocol::Tibia650).await.ok()?;
+    let FieldValue::List(characters) = message.field("characters")? else {
+        return None;
+    };
+    let mut address = [0_u8; 6];
+    for field in characters.first()? {
+        match (field.name, &field.value) {
+            ("ip", FieldValue::Ipv4(ip)) => address[..4].copy_from_slice(&ip.octets()),
+            ("port", FieldValue::Number(port)) => address[4..].copy_from_slice(&(*port as u16).to_le_bytes()),
+            _ => {}
+        }
+    }
+    Some(address)
+}
+
+/// The id sent to the player on login. Clients up to 2.x are not told their id
+async fn player_id(bytes: &[u8], protocol: Protocol) -> Option<u32> {
+    for frame in split_server_frames(bytes).ok()? {
+        let Ok(messages) = decode_server_frame(&frame[2..], protocol).await else {
+            continue;
+        };
+        if let Some(login) = messages
+            .iter()
+            .find(|message| message.kind == MessageKind::Server(HeaderSend::Login))
+        {
+            return login.number("id");
+        }
+    }
+    None
+}
+
+/// Compares two streams of server bytes frame by frame
+pub fn compare(expected: &[u8], actual: &[u8]) -> Result<ReplayReport> {
+    Ok(compare_frames(split_server_frames(expected)?, split_server_frames(actual)?))
+}
+
+fn compare_frames(expected: Vec<&[u8]>, actual: Vec<&[u8]>) -> ReplayReport {
+    let mut differences = vec![];
+    for frame in 0..expected.len().max(actual.len()) {
+        let expected_frame = expected.get(frame);
+        let actual_frame = actual.get(frame);
+        if expected_frame != actual_frame {
+            let offset = match (expected_frame, actual_frame) {
+                (Some(expected_frame), Some(actual_frame)) => expected_frame
+                    .iter()
+                    .zip(actual_frame.iter())
+                    .position(|(a, b)| a != b)
+                    .unwrap_or_else(|| expected_frame.len().min(actual_frame.len())),
+                _ => 0,
+            };
+
+            differences.push(FrameDifference {
+                frame,
+                offset,
+                expected: expected_frame.map(|frame| frame.to_vec()),
+                actual: actual_frame.map(|frame| frame.to_vec()),
+            });
+        }
+    }
+
+    ReplayReport {
+        expected_frames: expected.len(),
+        actual_frames: actual.len(),
+        differences,
+    }
+}
+
+impl Display for ReplayReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Replay finished: expected frames={}, actual frames={}, differences={}",
+            self.expected_frames,
+            self.actual_frames,
+            self.differences.len()
+        )?;
+        for difference in self.differences.iter() {
+            match (&difference.expected, &difference.actual) {
+                (Some(expected), Some(actual)) => writeln!(
+                    f,
+                    "frame {}: differs at offset {}\n  expected={expected:02x?}\n  actual  ={actual:02x?}",
+                    difference.frame, difference.offset
+                )?,
+                (Some(expected), None) => {
+                    writeln!(f, "frame {}: missing, expected={expected:02x?}", difference.frame)?
+                }
+                (None, Some(actual)) => {
+                    writeln!(f, "frame {}: unexpected, actual={actual:02x?}", difference.frame)?
+                }
+                (None, None) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare() -> Result<()> {
+        let expected = [0x04, 0x00, 0x01, 0x02, 0x03, 0x00, 0x0a];
+        let actual = [0x04, 0x00, 0x01, 0x03, 0x03, 0x00, 0x0a, 0x03, 0x00, 0x0b];
+
+        let report = compare(&expected, &actual)?;
+
+        assert_eq!(report.expected_frames, 2);
+        assert_eq!(report.actual_frames, 3);
+        assert_eq!(
+            report.differences,
+            vec![
+                FrameDifference {
+                    frame: 0,
+                    offset: 3,
+                    expected: Some(vec![0x04, 0x00, 0x01, 0x02]),
+                    actual: Some(vec![0x04, 0x00, 0x01, 0x03]),
+                },
+                FrameDifference {
+                    frame: 2,
+                    offset: 0,
+                    expected: None,
+                    actual: Some(vec![0x03, 0x00, 0x0b]),
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rebase_player_id() -> Result<()> {
+        // Login of player 0x012c, then the player turning
+        let expected = [
+            0x07, 0x00, 0x01, 0x2c, 0x01, 0x00, 0x00,
+            0x08, 0x00, 0xfa, 0x2c, 0x01, 0x00, 0x00, 0x02,
+        ];
+        // Same session, replayed as player 0x0100
+        let actual = [
+            0x07, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00,
+            0x08, 0x00, 0xfa, 0x00, 0x01, 0x00, 0x00, 0x02,
+        ];
+
+        assert_eq!(compare(&expected, &actual)?.differences.len(), 2);
+        let expected = rebase_player_id(&expected, &actual, Protocol::Tibia300).await?;
+        assert_eq!(expected, actual);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,343 @@
+//! What changes from one protocol version to the next. The codecs ask the table instead of
+//! comparing versions, so supporting a new version starts by adding its row here.
+use crate::{chat::ChatType, network::header::HeaderSend, Protocol};
+
+/// Width of the header of messages sent to the client
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HeaderFormat {
+    /// u8 header preceded by 4 bytes of unknown meaning, always zero
+    U8WithPrefix,
+    U8,
+    U16,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PositionFormat {
+    /// u8 x and y, no floors
+    Short,
+    /// u16 x, u16 y and u8 z
+    Long,
+}
+
+/// How queued messages are sent to the client
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Framing {
+    /// Each message in its own frame
+    Individual,
+    /// Every message of a flush in the same frame
+    Bundled,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum GenderEncoding {
+    /// Female = 0, male = 1
+    FemaleZero,
+    /// Female = 2, male = 1
+    FemaleTwo,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SkillLayout {
+    /// Skills are not sent
+    None,
+    /// sword, club, gauche, fist, missile, shield, throwing, fishing
+    Tibia400,
+    /// sword, club, axe, distance, shield, fist, fishing
+    Standard,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StatsLayout {
+    /// health, capacity
+    Basic,
+    /// health, capacity, intelligence, strength, dexterity, u16 experience, level
+    Tibia300,
+    /// health, capacity, u32 experience, level, mana, magic level, ammunition
+    Standard,
+}
+
+/// Details of the player in the data window, the set data message and new player logins
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PlayerDataLayout {
+    /// password, gender, outfit, real name, location and e-mail
+    Account,
+    /// The account details followed by a comment
+    AccountWithComment,
+    /// gender and outfit only
+    Character,
+}
+
+/// 1.03 sends several messages in a shorter form than later versions
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MessageForms {
+    /// Login without the player id, equipped items without stack, map items without their
+    /// count, fluid or light, and no creature but the player, sent as its outfit colors only.
+    /// From the client, pushes without count and new players with a 48 byte location
+    Short,
+    Full,
+}
+
+/// How clients log in
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LoginFlow {
+    /// Player login, or new player to create the character first
+    PlayerOrNewPlayer,
+    /// Player login only
+    Player,
+    /// Account login, answered with the character list, then player login without the prefix
+    Account,
+}
+
+/// How a refused step is undone. The client moves the character before the server answers
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WalkRefusal {
+    /// The map is sent again around the real position of the player
+    Map,
+    /// The map is sent again, then the character turns to the refused step
+    MapAndTurn,
+}
+
+#[derive(Debug)]
+pub struct Capabilities {
+    pub header: HeaderFormat,
+    pub position: PositionFormat,
+    pub framing: Framing,
+    pub gender: GenderEncoding,
+    pub skills: SkillLayout,
+    pub stats: StatsLayout,
+    pub player_data: PlayerDataLayout,
+    pub forms: MessageForms,
+    pub login: LoginFlow,
+    /// The message of the day starts with its number
+    pub numbered_message_of_the_day: bool,
+    /// Added to the position of chat messages, 3.0 clients draw them one tile up and left of it
+    pub chat_position_offset: (i16, i16, i8),
+    /// Chat type of messages from the server itself, without a sender. `None` when the client
+    /// shows no such message, they are then said by the player
+    pub server_chat: Option<ChatType>,
+    pub walk_refusal: WalkRefusal,
+    /// Headers of messages the client understands, from the matrix in `network::header`
+    pub headers: &'static [HeaderSend],
+    pub max_outfit_type: u8,
+    pub max_magic_effect: u8,
+    /// Chat type of private messages. Every version uses the blue console one until another is
+    /// found to fit a version better
+    pub private_message: ChatType,
+}
+
+impl Capabilities {
+    pub fn supports(&self, header: HeaderSend) -> bool {
+        self.headers.contains(&header)
+    }
+}
+
+use HeaderSend::*;
+
+const HEADERS_103: &[HeaderSend] = &[
+    Unknown0x0000, Login, Error, DataWindow, Info, MessageOfTheDay, Map, MoveOneTileNorth,
+    MoveOneTileEast, MoveOneTileSouth, MoveOneTileWest, CloseContainer, OpenContainer,
+    EquippedItem, RemoveEquippedItem, UpdateObject, GreenChat, Chat, UserList, UserInfo,
+    StatusMessage, Echo,
+];
+
+const HEADERS_300: &[HeaderSend] = &[
+    Login, Error, DataWindow, Info, MessageOfTheDay, Map, MoveOneTileNorth, MoveOneTileEast,
+    MoveOneTileSouth, MoveOneTileWest, Unknown0x000f, CloseContainer, OpenContainer,
+    EquippedItem, RemoveEquippedItem, UpdateInventoryItem, UpdateObject, MagicEffect, Text,
+    WorldLight, UpdateCharacter, Unknown0x0033, Stats, GreenChat, Chat, UserList, UserInfo,
+    StatusMessage, Echo,
+];
+
+const HEADERS_310: &[HeaderSend] = &[
+    Login, Error, DataWindow, Info, MessageOfTheDay, Map, MoveOneTileNorth, MoveOneTileEast,
+    MoveOneTileSouth, MoveOneTileWest, Unknown0x000f, CloseContainer, OpenContainer,
+    EquippedItem, RemoveEquippedItem, UpdateInventoryItem, UpdateObject, MagicEffect,
+    ProjectileEffect, Text, WorldLight, UpdateCharacter, Unknown0x0033, Stats, GreenChat, Chat,
+    UserList, UserInfo, StatusMessage, Echo,
+];
+
+const HEADERS_400: &[HeaderSend] = &[
+    Login, Error, DataWindow, Info, MessageOfTheDay, Map, MoveOneTileNorth, MoveOneTileEast,
+    MoveOneTileSouth, MoveOneTileWest, Unknown0x000f, CloseContainer, OpenContainer,
+    EquippedItem, RemoveEquippedItem, UpdateInventoryItem, UpdateObject, MagicEffect,
+    ProjectileEffect, Text, WorldLight, UpdateCharacter, Unknown0x0033, Unknown0x0034, Stats,
+    Skills, GreenChat, Chat, UserList, UserInfo, StatusMessage, Echo,
+];
+
+const HEADERS_620: &[HeaderSend] = &[
+    Login, Error, DataWindow, Info, MessageOfTheDay, Map, MoveOneTileNorth, MoveOneTileEast,
+    MoveOneTileSouth, MoveOneTileWest, Unknown0x000f, CloseContainer, OpenContainer,
+    EquippedItem, RemoveEquippedItem, UpdateInventoryItem, UpdateObject, MagicEffect,
+    ProjectileEffect, Text, WorldLight, UpdateCharacter, Unknown0x0033, Stats, Skills,
+    GreenChat, Chat, UserList, UserInfo, StatusMessage, Echo,
+];
+
+const HEADERS_640: &[HeaderSend] = &[
+    Login, Error, DataWindow, Info, MessageOfTheDay, Map, MoveOneTileNorth, MoveOneTileEast,
+    MoveOneTileSouth, MoveOneTileWest, Unknown0x000f, CloseContainer, OpenContainer,
+    EquippedItem, RemoveEquippedItem, UpdateInventoryItem, UpdateObject, MagicEffect,
+    ProjectileEffect, Text, HouseText, WorldLight, UpdateCharacter, Unknown0x0033, Stats,
+    Skills, GreenChat, Chat, UserList, UserInfo, StatusMessage, Echo,
+];
+
+const TIBIA_103: Capabilities = Capabilities {
+    header: HeaderFormat::U8WithPrefix,
+    position: PositionFormat::Short,
+    framing: Framing::Individual,
+    gender: GenderEncoding::FemaleZero,
+    skills: SkillLayout::None,
+    stats: StatsLayout::Basic,
+    player_data: PlayerDataLayout::Account,
+    forms: MessageForms::Short,
+    login: LoginFlow::PlayerOrNewPlayer,
+    numbered_message_of_the_day: false,
+    chat_position_offset: (0, 0, 0),
+    server_chat: None,
+    walk_refusal: WalkRefusal::Map,
+    headers: HEADERS_103,
+    max_outfit_type: 0,
+    max_magic_effect: 0,
+    private_message: ChatType::BlueConsoleWhiteScreen,
+};
+
+const TIBIA_300: Capabilities = Capabilities {
+    header: HeaderFormat::U8,
+    position: PositionFormat::Long,
+    framing: Framing::Individual,
+    gender: GenderEncoding::FemaleZero,
+    skills: SkillLayout::None,
+    stats: StatsLayout::Tibia300,
+    player_data: PlayerDataLayout::Account,
+    forms: MessageForms::Full,
+    login: LoginFlow::PlayerOrNewPlayer,
+    numbered_message_of_the_day: false,
+    chat_position_offset: (1, 1, 0),
+    server_chat: None,
+    walk_refusal: WalkRefusal::MapAndTurn,
+    headers: HEADERS_300,
+    max_outfit_type: 30,
+    max_magic_effect: 3,
+    private_message: ChatType::BlueConsoleWhiteScreen,
+};
+
+const TIBIA_310: Capabilities = Capabilities {
+    chat_position_offset: (0, 0, 0),
+    headers: HEADERS_310,
+    ..TIBIA_300
+};
+
+const TIBIA_400: Capabilities = Capabilities {
+    skills: SkillLayout::Tibia400,
+    stats: StatsLayout::Standard,
+    player_data: PlayerDataLayout::AccountWithComment,
+    headers: HEADERS_400,
+    max_outfit_type: 36,
+    max_magic_effect: 15,
+    ..TIBIA_310
+};
+
+const TIBIA_412: Capabilities = Capabilities {
+    header: HeaderFormat::U16,
+    skills: SkillLayout::Standard,
+    numbered_message_of_the_day: true,
+    ..TIBIA_400
+};
+
+const TIBIA_501: Capabilities = Capabilities {
+    gender: GenderEncoding::FemaleTwo,
+    server_chat: Some(ChatType::RedConsoleWhiteScreen),
+    max_outfit_type: 38,
+    max_magic_effect: 16,
+    ..TIBIA_412
+};
+
+// The matrix has no headers for 5.10, assumed to be the same as 5.01
+const TIBIA_510: Capabilities = Capabilities {
+    framing: Framing::Bundled,
+    player_data: PlayerDataLayout::Character,
+    max_outfit_type: 47,
+    ..TIBIA_501
+};
+
+const TIBIA_620: Capabilities = Capabilities {
+    headers: HEADERS_620,
+    max_outfit_type: 74,
+    max_magic_effect: 17,
+    ..TIBIA_510
+};
+
+const TIBIA_640: Capabilities = Capabilities {
+    login: LoginFlow::Player,
+    headers: HEADERS_640,
+    ..TIBIA_620
+};
+
+// The matrix has no headers for 6.94, assumed to be the same as 6.61
+const TIBIA_650: Capabilities = Capabilities {
+    login: LoginFlow::Account,
+    max_outfit_type: 75,
+    ..TIBIA_640
+};
+
+impl Protocol {
+    pub const ALL: [Self; 13] = [
+        Self::Tibia103,
+        Self::Tibia300,
+        Self::Tibia310,
+        Self::Tibia400,
+        Self::Tibia412,
+        Self::Tibia501,
+        Self::Tibia510,
+        Self::Tibia620,
+        Self::Tibia630,
+        Self::Tibia640,
+        Self::Tibia650,
+        Self::Tibia661,
+        Self::Tibia694,
+    ];
+
+    pub const fn capabilities(self) -> &'static Capabilities {
+        match self {
+            Self::Tibia103 => &TIBIA_103,
+            Self::Tibia300 => &TIBIA_300,
+            Self::Tibia310 => &TIBIA_310,
+            Self::Tibia400 => &TIBIA_400,
+            Self::Tibia412 => &TIBIA_412,
+            Self::Tibia501 => &TIBIA_501,
+            Self::Tibia510 => &TIBIA_510,
+            Self::Tibia620 | Self::Tibia630 => &TIBIA_620,
+            Self::Tibia640 => &TIBIA_640,
+            Self::Tibia650 | Self::Tibia661 | Self::Tibia694 => &TIBIA_650,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities_match_version_checks() {
+        assert_eq!(Protocol::Tibia400.capabilities().header, HeaderFormat::U8);
+        assert_eq!(Protocol::Tibia412.capabilities().header, HeaderFormat::U16);
+        assert_eq!(Protocol::Tibia501.capabilities().framing, Framing::Individual);
+        assert_eq!(Protocol::Tibia510.capabilities().framing, Framing::Bundled);
+        assert!(!Protocol::Tibia103.capabilities().supports(HeaderSend::Stats));
+        assert!(Protocol::Tibia501.capabilities().supports(HeaderSend::Unknown0x0034));
+        assert!(!Protocol::Tibia620.capabilities().supports(HeaderSend::Unknown0x0034));
+        assert!(Protocol::Tibia661.capabilities().supports(HeaderSend::HouseText));
+        assert_eq!(Protocol::Tibia300.capabilities().stats, StatsLayout::Tibia300);
+        assert_eq!(Protocol::Tibia400.capabilities().stats, StatsLayout::Standard);
+        assert!(!Protocol::Tibia400.capabilities().numbered_message_of_the_day);
+        assert!(Protocol::Tibia412.capabilities().numbered_message_of_the_day);
+        assert_eq!(Protocol::Tibia501.capabilities().player_data, PlayerDataLayout::AccountWithComment);
+        assert_eq!(Protocol::Tibia510.capabilities().player_data, PlayerDataLayout::Character);
+        assert_eq!(Protocol::Tibia310.capabilities().chat_position_offset, (0, 0, 0));
+        assert_eq!(Protocol::Tibia400.capabilities().server_chat, None);
+        assert_eq!(Protocol::Tibia103.capabilities().walk_refusal, WalkRefusal::Map);
+        assert_eq!(Protocol::Tibia694.capabilities().walk_refusal, WalkRefusal::MapAndTurn);
+        assert_eq!(Protocol::Tibia630.capabilities().login, LoginFlow::PlayerOrNewPlayer);
+        assert_eq!(Protocol::Tibia640.capabilities().login, LoginFlow::Player);
+        assert_eq!(Protocol::Tibia694.capabilities().login, LoginFlow::Account);
+    }
+}
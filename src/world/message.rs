@@ -1,13 +1,112 @@
-use tokio::sync::mpsc::UnboundedSender;
+use crate::{
+    character::{Direction, OutfitColors},
+    chat::ChatType,
+    map::position::Position,
+};
+use tokio::sync::mpsc::Sender;
 
 #[derive(Clone, Debug)]
 pub enum PlayerToWorldMessage {
-    LoadPlayer(u32, UnboundedSender<WorldToPlayerMessage>),
+    LoadPlayer(CharacterView, Sender<WorldToPlayerMessage>),
     UnloadPlayer(u32),
-    Walk(u32),
+    /// The player asks to step one tile in the direction
+    Walk(u32, Direction),
+    /// The player asks for the steps to the position
+    AutoWalk(u32, Position),
+    Turn(u32, Direction),
+    ChangeOutfit(u32, OutfitColors),
+    /// The player said the message, to be heard by the players in range of the chat type
+    Chat(u32, ChatType, String),
+    /// The player sent the message to the named player, wherever it is
+    PrivateMessage(u32, String, String),
 }
 
-#[derive(Clone, Copy, Debug)]
+/// A player as seen by the other players
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CharacterView {
+    pub id: u32,
+    pub name: String,
+    pub position: Position,
+    pub direction: Direction,
+    pub outfit: OutfitColors,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum WorldToPlayerMessage {
+    /// The player was placed on the position, the nearest free tile to where it logged in
+    Loaded(Position),
     WorldLight(u8),
+    /// The step asked by the player was made
+    Walked {
+        from: Position,
+        to: Position,
+        direction: Direction,
+    },
+    /// The step asked by the player was refused, the player only turned to the direction
+    WalkBlocked(Direction),
+    /// Steps to the position asked by the player, to be walked one by one
+    AutoWalkPath(Vec<Direction>),
+    /// The position asked by the player can't be reached
+    AutoWalkUnreachable,
+    /// Another player logged in or came into view
+    CharacterAppeared(CharacterView),
+    /// Another player logged out or left the view from the position
+    CharacterDisappeared {
+        id: u32,
+        position: Position,
+    },
+    CharacterMoved {
+        id: u32,
+        from: Position,
+        to: Position,
+        direction: Direction,
+    },
+    CharacterTurned {
+        id: u32,
+        position: Position,
+        direction: Direction,
+    },
+    CharacterOutfitChanged {
+        id: u32,
+        outfit: OutfitColors,
+    },
+    /// A message said by a player in range, or by the player itself
+    Chat {
+        chat_type: ChatType,
+        message: String,
+        sender: String,
+        position: Position,
+    },
+    /// A message sent to the player only
+    PrivateMessage {
+        sender: String,
+        message: String,
+    },
+    /// The private message of the player reached the named player
+    PrivateMessageSent(String),
+    /// The private message of the player was not sent, nobody with the name is online
+    PlayerNotOnline(String),
+}
+
+impl WorldToPlayerMessage {
+    /// Non-essential messages may be dropped when the client can't keep up
+    pub const fn is_essential(&self) -> bool {
+        match self {
+            Self::WorldLight(_) => false,
+            Self::Loaded(_)
+            | Self::Walked { .. }
+            | Self::WalkBlocked(_)
+            | Self::AutoWalkPath(_)
+            | Self::AutoWalkUnreachable
+            | Self::CharacterAppeared(_)
+            | Self::CharacterDisappeared { .. }
+            | Self::CharacterMoved { .. }
+            | Self::CharacterTurned { .. }
+            | Self::CharacterOutfitChanged { .. }
+            | Self::Chat { .. }
+            | Self::PrivateMessage { .. }
+            | Self::PrivateMessageSent(_)
+            | Self::PlayerNotOnline(_) => true,
+        }
+    }
 }
@@ -3,67 +3,86 @@ use tokio::{
     time::interval,
     sync::{
         RwLock,
-        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender}
+        mpsc::{channel, error::TrySendError, Receiver, Sender}
     }
 };
+use crate::{config::SlowClientPolicy, map::MAP};
 use tokio_stream::{
     StreamExt,
     wrappers::IntervalStream
 };
 use message::{PlayerToWorldMessage, WorldToPlayerMessage};
+use players::Players;
 use std::{
-    collections::BTreeMap,
     sync::Arc,
     time::Duration,
 };
 
 pub mod message;
+mod players;
 
 pub struct World {
-    sender: UnboundedSender<PlayerToWorldMessage>,
-    receiver: UnboundedReceiver<PlayerToWorldMessage>,
+    sender: Sender<PlayerToWorldMessage>,
+    receiver: Receiver<PlayerToWorldMessage>,
 }
 
 #[derive(Default, Debug, Clone, Copy)]
 pub struct WorldOptions {
     pub day_night_cycle_enabled: bool,
+    pub slow_client_policy: SlowClientPolicy,
 }
 
 impl World {
-    pub fn new() -> Arc<RwLock<World>> {
-        let (sender, receiver) = unbounded_channel();
+    /// Players wait for room to send their requests once `queue_size` of them are pending
+    pub fn new(queue_size: usize) -> Arc<RwLock<World>> {
+        let (sender, receiver) = channel(queue_size);
 
         Arc::new(RwLock::new(World { sender, receiver }))
     }
 
-    pub fn sender(&self) -> UnboundedSender<PlayerToWorldMessage> {
+    pub fn sender(&self) -> Sender<PlayerToWorldMessage> {
         self.sender.clone()
     }
 
     pub fn init_loop(world: &Arc<RwLock<World>>, world_options: WorldOptions) {
-        let senders = Arc::new(RwLock::new(BTreeMap::new()));
-        task::spawn(Self::message_loop(world.clone(), senders.clone()));
-        task::spawn(Self::world_loop(world.clone(), world_options, senders));
+        let players = Arc::new(RwLock::new(Players::new(world_options.slow_client_policy)));
+        task::spawn(Self::message_loop(world.clone(), players.clone()));
+        task::spawn(Self::world_loop(world.clone(), world_options, players));
     }
 
-    async fn message_loop(
-        world: Arc<RwLock<World>>,
-        senders: Arc<RwLock<BTreeMap<u32, UnboundedSender<WorldToPlayerMessage>>>>,
-    ) {
+    async fn message_loop(world: Arc<RwLock<World>>, players: Arc<RwLock<Players>>) {
         loop {
             let receiver = &mut world.write().await.receiver;
             if let Some(message) = receiver.recv().await {
+                let mut players = players.write().await;
                 match message {
-                    PlayerToWorldMessage::LoadPlayer(player_id, sender) => {
-                        log::debug!("Load player {player_id}");
-                        senders.write().await.insert(player_id, sender);
+                    PlayerToWorldMessage::LoadPlayer(character, sender) => {
+                        log::debug!("Load player {}", character.id);
+                        players.load(character, sender, MAP.get().unwrap());
                     }
                     PlayerToWorldMessage::UnloadPlayer(player_id) => {
                         log::debug!("Unload player {player_id}");
-                        senders.write().await.remove(&player_id);
+                        players.unload(player_id);
+                    }
+                    PlayerToWorldMessage::Walk(player_id, direction) => {
+                        log::trace!("Received player {player_id} walk {direction:?}");
+                        players.walk(player_id, direction, MAP.get().unwrap());
+                    }
+                    PlayerToWorldMessage::AutoWalk(player_id, position) => {
+                        log::trace!("Received player {player_id} auto walk to {position}");
+                        players.auto_walk(player_id, position, MAP.get().unwrap());
+                    }
+                    PlayerToWorldMessage::Turn(player_id, direction) => {
+                        players.turn(player_id, direction);
+                    }
+                    PlayerToWorldMessage::ChangeOutfit(player_id, outfit) => {
+                        players.change_outfit(player_id, outfit);
                     }
-                    PlayerToWorldMessage::Walk(player_id) => {
-                        log::trace!("Received player {player_id} walk")
+                    PlayerToWorldMessage::Chat(player_id, chat_type, message) => {
+                        players.chat(player_id, chat_type, &message);
+                    }
+                    PlayerToWorldMessage::PrivateMessage(player_id, receiver, message) => {
+                        players.private_message(player_id, &receiver, &message);
                     }
                 }
             }
@@ -73,7 +92,7 @@ impl World {
     async fn world_loop(
         _world: Arc<RwLock<World>>,
         world_options: WorldOptions,
-        senders: Arc<RwLock<BTreeMap<u32, UnboundedSender<WorldToPlayerMessage>>>>,
+        players: Arc<RwLock<Players>>,
     ) {
         let mut hour = 0;
         let mut interval = IntervalStream::new(interval(Duration::from_secs(3)));
@@ -82,14 +101,39 @@ impl World {
             let light_level = Self::hour_to_light_level(hour);
 
             // log::trace!("Hour: {}, light_level: {}", hour, light_level);
-            for (_player_id, sender) in senders.read().await.iter() {
-                if world_options.day_night_cycle_enabled {
-                    let _ = sender.send(WorldToPlayerMessage::WorldLight(light_level));
-                }
+            if world_options.day_night_cycle_enabled {
+                players
+                    .write()
+                    .await
+                    .send_to_all(WorldToPlayerMessage::WorldLight(light_level));
             }
         }
     }
 
+    /// Sends a message without waiting for a slow player. Returns false if the player has to
+    /// be disconnected, which is done by dropping its sender.
+    fn send_to_player(
+        player_id: u32,
+        sender: &Sender<WorldToPlayerMessage>,
+        message: WorldToPlayerMessage,
+        policy: SlowClientPolicy,
+    ) -> bool {
+        match sender.try_send(message) {
+            Ok(()) => true,
+            Err(TrySendError::Full(message))
+                if policy == SlowClientPolicy::DropNonEssential && !message.is_essential() =>
+            {
+                log::debug!("Player {player_id} is not keeping up, dropping {message:?}");
+                true
+            }
+            Err(TrySendError::Full(message)) => {
+                log::warn!("Player {player_id} is not keeping up, disconnecting on {message:?}");
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
     const fn hour_to_light_level(hour: u8) -> u8 {
         match hour {
             0..=4 | 22..=23 => 1,
@@ -103,3 +147,19 @@ impl World {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::channel;
+
+    #[test]
+    fn test_send_to_slow_player() {
+        let (sender, _receiver) = channel(1);
+        let light = WorldToPlayerMessage::WorldLight(6);
+
+        assert!(World::send_to_player(1, &sender, light.clone(), SlowClientPolicy::Disconnect));
+        assert!(World::send_to_player(1, &sender, light.clone(), SlowClientPolicy::DropNonEssential));
+        assert!(!World::send_to_player(1, &sender, light, SlowClientPolicy::Disconnect));
+    }
+}
@@ -0,0 +1,508 @@
+use super::{
+    message::{CharacterView, WorldToPlayerMessage},
+    World,
+};
+use crate::{
+    character::{Direction, OutfitColors},
+    chat::{ChatType, WHISPER_FROM_AFAR},
+    config::SlowClientPolicy,
+    map::{position::Position, Map},
+};
+use std::collections::BTreeMap;
+use tokio::sync::mpsc::Sender;
+
+/// Farthest a player is placed from where it logs in, when that tile is taken
+const LOGIN_PLACEMENT_RANGE: i16 = 8;
+
+struct LoadedPlayer {
+    character: CharacterView,
+    sender: Sender<WorldToPlayerMessage>,
+}
+
+/// Every logged in player, with what the others see of it. Changes are sent to the players
+/// whose viewport contains the affected tiles
+pub struct Players {
+    players: BTreeMap<u32, LoadedPlayer>,
+    policy: SlowClientPolicy,
+    /// Players that could not be sent a message, unloaded once the current change is sent
+    unreachable: Vec<u32>,
+}
+
+impl Players {
+    pub fn new(policy: SlowClientPolicy) -> Self {
+        Self {
+            players: BTreeMap::new(),
+            policy,
+            unreachable: vec![],
+        }
+    }
+
+    /// Places the player on the nearest free tile to where it logs in, so players never share
+    /// a tile. The player is told where before anything else
+    pub fn load(&mut self, mut character: CharacterView, sender: Sender<WorldToPlayerMessage>, map: &Map) {
+        let id = character.id;
+        self.unload(id);
+
+        match self.free_tile_near(character.position, map) {
+            Some(position) => character.position = position,
+            None => log::warn!("No free tile near {} for player {id}, placing it there anyway", character.position),
+        }
+        self.unreachable.extend(self.send(id, &sender, WorldToPlayerMessage::Loaded(character.position)));
+
+        for other in self.players.values() {
+            if other.character.position.is_in_viewport(character.position) {
+                self.unreachable.extend(self.send(
+                    id,
+                    &sender,
+                    WorldToPlayerMessage::CharacterAppeared(other.character.clone()),
+                ));
+            }
+        }
+        self.send_to_spectators(character.position, id, || {
+            WorldToPlayerMessage::CharacterAppeared(character.clone())
+        });
+
+        self.players.insert(id, LoadedPlayer { character, sender });
+        self.unload_unreachable();
+    }
+
+    pub fn unload(&mut self, id: u32) {
+        if let Some(player) = self.players.remove(&id) {
+            let position = player.character.position;
+            self.send_to_spectators(position, id, || {
+                WorldToPlayerMessage::CharacterDisappeared { id, position }
+            });
+            self.unload_unreachable();
+        }
+    }
+
+    /// Moves the player one tile, unless the tile is blocked by the map or another player
+    pub fn walk(&mut self, id: u32, direction: Direction, map: &Map) {
+        let Some(player) = self.players.get(&id) else {
+            return;
+        };
+        let from = player.character.position;
+        let to = from + direction;
+        if !map.is_walkable(to) || self.is_occupied(to) {
+            log::trace!("Player {id} can't walk from {from} to {to}");
+            self.unreachable.extend(self.send(id, &player.sender, WorldToPlayerMessage::WalkBlocked(direction)));
+            self.turn(id, direction);
+            return;
+        }
+
+        let player = self.players.get_mut(&id).unwrap();
+        player.character.position = to;
+        player.character.direction = direction;
+        let character = player.character.clone();
+        let sender = player.sender.clone();
+        self.unreachable.extend(self.send(id, &sender, WorldToPlayerMessage::Walked { from, to, direction }));
+
+        for (&other_id, other) in self.players.iter().filter(|(other_id, _)| **other_id != id) {
+            let other_position = other.character.position;
+            let seen = match (from.is_in_viewport(other_position), to.is_in_viewport(other_position)) {
+                (true, true) => Some(WorldToPlayerMessage::CharacterMoved {
+                    id,
+                    from,
+                    to,
+                    direction,
+                }),
+                (true, false) => Some(WorldToPlayerMessage::CharacterDisappeared { id, position: from }),
+                (false, true) => Some(WorldToPlayerMessage::CharacterAppeared(character.clone())),
+                (false, false) => None,
+            };
+            if let Some(message) = seen {
+                self.unreachable.extend(self.send(other_id, &other.sender, message));
+            }
+
+            // What the player sees of the others changes as well
+            let sees = match (other_position.is_in_viewport(from), other_position.is_in_viewport(to)) {
+                (true, false) => Some(WorldToPlayerMessage::CharacterDisappeared {
+                    id: other_id,
+                    position: other_position,
+                }),
+                (false, true) => Some(WorldToPlayerMessage::CharacterAppeared(other.character.clone())),
+                _ => None,
+            };
+            if let Some(message) = sees {
+                self.unreachable.extend(self.send(id, &sender, message));
+            }
+        }
+        self.unload_unreachable();
+    }
+
+    /// Finds the steps to the position, with the same rules as walking. Players in the way
+    /// make the path go around them
+    pub fn auto_walk(&mut self, id: u32, to: Position, map: &Map) {
+        let Some(player) = self.players.get(&id) else {
+            return;
+        };
+        let path = map.find_path(player.character.position, to, |position| !self.is_occupied(position));
+        let message = match path {
+            Some(steps) => WorldToPlayerMessage::AutoWalkPath(steps),
+            None => WorldToPlayerMessage::AutoWalkUnreachable,
+        };
+        self.unreachable.extend(self.send(id, &player.sender, message));
+        self.unload_unreachable();
+    }
+
+    pub fn turn(&mut self, id: u32, direction: Direction) {
+        let Some(player) = self.players.get_mut(&id) else {
+            return;
+        };
+        player.character.direction = direction;
+        let position = player.character.position;
+        self.send_to_spectators(position, id, || WorldToPlayerMessage::CharacterTurned {
+            id,
+            position,
+            direction,
+        });
+        self.unload_unreachable();
+    }
+
+    pub fn change_outfit(&mut self, id: u32, outfit: OutfitColors) {
+        let Some(player) = self.players.get_mut(&id) else {
+            return;
+        };
+        player.character.outfit = outfit;
+        let position = player.character.position;
+        self.send_to_spectators(position, id, || {
+            WorldToPlayerMessage::CharacterOutfitChanged { id, outfit }
+        });
+        self.unload_unreachable();
+    }
+
+    /// Sends the message to the players that hear it, including the one who said it
+    pub fn chat(&mut self, id: u32, chat_type: ChatType, message: &str) {
+        let Some(speaker) = self.players.get(&id) else {
+            return;
+        };
+        let (sender, position) = (speaker.character.name.clone(), speaker.character.position);
+
+        for (&listener_id, listener) in self.players.iter() {
+            let listener_position = listener.character.position;
+            let message = if chat_type.is_heard(position, listener_position) {
+                message
+            } else if chat_type == ChatType::Whisper && position.is_in_viewport(listener_position) {
+                WHISPER_FROM_AFAR
+            } else {
+                continue;
+            };
+            let chat = WorldToPlayerMessage::Chat {
+                chat_type,
+                message: message.to_string(),
+                sender: sender.clone(),
+                position,
+            };
+            self.unreachable.extend(self.send(listener_id, &listener.sender, chat));
+        }
+        self.unload_unreachable();
+    }
+
+    /// Sends the message to the player with the name, whatever the case, and tells the sender
+    /// whether it was delivered
+    pub fn private_message(&mut self, id: u32, receiver: &str, message: &str) {
+        let Some(sender) = self.players.get(&id) else {
+            return;
+        };
+        let sender_name = sender.character.name.clone();
+
+        let reply = match self
+            .players
+            .iter()
+            .find(|(_, player)| player.character.name.eq_ignore_ascii_case(receiver))
+        {
+            Some((&receiver_id, player)) => {
+                let private_message = WorldToPlayerMessage::PrivateMessage {
+                    sender: sender_name,
+                    message: message.to_string(),
+                };
+                self.unreachable.extend(self.send(receiver_id, &player.sender, private_message));
+                WorldToPlayerMessage::PrivateMessageSent(player.character.name.clone())
+            }
+            None => WorldToPlayerMessage::PlayerNotOnline(receiver.to_string()),
+        };
+        if let Some(sender) = self.players.get(&id) {
+            self.unreachable.extend(self.send(id, &sender.sender, reply));
+        }
+        self.unload_unreachable();
+    }
+
+    /// Sends the message to every player
+    pub fn send_to_all(&mut self, message: WorldToPlayerMessage) {
+        for (&id, player) in self.players.iter() {
+            self.unreachable.extend(self.send(id, &player.sender, message.clone()));
+        }
+        self.unload_unreachable();
+    }
+
+    fn is_occupied(&self, position: Position) -> bool {
+        self.players.values().any(|player| player.character.position == position)
+    }
+
+    /// The walkable tile without a player closest to the position, on the same floor
+    fn free_tile_near(&self, position: Position, map: &Map) -> Option<Position> {
+        let range = -LOGIN_PLACEMENT_RANGE..=LOGIN_PLACEMENT_RANGE;
+        let mut offsets: Vec<(i16, i16)> = range
+            .clone()
+            .flat_map(|y| range.clone().map(move |x| (x, y)))
+            .collect();
+        offsets.sort_by_key(|&(x, y)| x * x + y * y);
+
+        offsets
+            .into_iter()
+            .map(|(x, y)| position + (x, y, 0))
+            .find(|&position| map.is_walkable(position) && !self.is_occupied(position))
+    }
+
+    /// Sends the message to the players other than `id` that see the position
+    fn send_to_spectators(
+        &mut self,
+        position: Position,
+        id: u32,
+        message: impl Fn() -> WorldToPlayerMessage,
+    ) {
+        for (&other_id, other) in self.players.iter() {
+            if other_id != id && position.is_in_viewport(other.character.position) {
+                self.unreachable.extend(self.send(other_id, &other.sender, message()));
+            }
+        }
+    }
+
+    /// Returns the id of the player if it has to be disconnected
+    fn send(&self, id: u32, sender: &Sender<WorldToPlayerMessage>, message: WorldToPlayerMessage) -> Option<u32> {
+        (!World::send_to_player(id, sender, message, self.policy)).then_some(id)
+    }
+
+    /// Disconnects the players that could not be sent a message, by dropping their senders
+    fn unload_unreachable(&mut self) {
+        while let Some(id) = self.unreachable.pop() {
+            self.unload(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::{channel, Receiver};
+
+    fn character(id: u32, position: Position) -> CharacterView {
+        CharacterView {
+            id,
+            name: format!("Player {id}"),
+            position,
+            direction: Direction::South,
+            outfit: OutfitColors::new(1, 2, 3, 4),
+        }
+    }
+
+    fn map() -> Map {
+        Map::checkerboard_pattern(100, 100, 0, 0, Position::new(50, 50, 7))
+    }
+
+    /// Loads the player where it asks to be, which is free in every test
+    fn load(players: &mut Players, character: CharacterView) -> Receiver<WorldToPlayerMessage> {
+        let (sender, mut receiver) = channel(16);
+        let position = character.position;
+        players.load(character, sender, &map());
+        assert_eq!(receiver.try_recv(), Ok(WorldToPlayerMessage::Loaded(position)));
+        receiver
+    }
+
+    fn received(receiver: &mut Receiver<WorldToPlayerMessage>) -> Vec<WorldToPlayerMessage> {
+        std::iter::from_fn(|| receiver.try_recv().ok()).collect()
+    }
+
+    #[test]
+    fn test_viewport() {
+        let center = Position::new(50, 50, 7);
+
+        assert!(Position::new(42, 44, 7).is_in_viewport(center));
+        assert!(Position::new(59, 57, 7).is_in_viewport(center));
+        assert!(!Position::new(41, 50, 7).is_in_viewport(center));
+        assert!(!Position::new(60, 50, 7).is_in_viewport(center));
+        assert!(!Position::new(50, 58, 7).is_in_viewport(center));
+        assert!(!Position::new(50, 50, 6).is_in_viewport(center));
+    }
+
+    #[test]
+    fn test_spectators() {
+        let mut players = Players::new(SlowClientPolicy::Disconnect);
+        let mut first = load(&mut players, character(1, Position::new(50, 50, 7)));
+        let mut second = load(&mut players, character(2, Position::new(58, 50, 7)));
+
+        assert_eq!(
+            received(&mut first),
+            [WorldToPlayerMessage::CharacterAppeared(character(2, Position::new(58, 50, 7)))]
+        );
+        assert_eq!(
+            received(&mut second),
+            [WorldToPlayerMessage::CharacterAppeared(character(1, Position::new(50, 50, 7)))]
+        );
+
+        players.walk(2, Direction::East, &map());
+        assert_eq!(
+            received(&mut first),
+            [WorldToPlayerMessage::CharacterMoved {
+                id: 2,
+                from: Position::new(58, 50, 7),
+                to: Position::new(59, 50, 7),
+                direction: Direction::East,
+            }]
+        );
+
+        // The first player is out of the view of the second one before being out of its own
+        assert_eq!(
+            received(&mut second),
+            [
+                WorldToPlayerMessage::Walked {
+                    from: Position::new(58, 50, 7),
+                    to: Position::new(59, 50, 7),
+                    direction: Direction::East,
+                },
+                WorldToPlayerMessage::CharacterDisappeared {
+                    id: 1,
+                    position: Position::new(50, 50, 7),
+                },
+            ]
+        );
+
+        players.walk(2, Direction::East, &map());
+        assert_eq!(
+            received(&mut first),
+            [WorldToPlayerMessage::CharacterDisappeared {
+                id: 2,
+                position: Position::new(59, 50, 7),
+            }]
+        );
+
+        players.turn(2, Direction::North);
+        players.unload(2);
+        assert!(received(&mut first).is_empty());
+    }
+
+    #[test]
+    fn test_auto_walk_around_players() {
+        let mut players = Players::new(SlowClientPolicy::Disconnect);
+        let mut first = load(&mut players, character(1, Position::new(50, 50, 7)));
+        load(&mut players, character(2, Position::new(51, 50, 7)));
+        received(&mut first);
+
+        players.auto_walk(1, Position::new(52, 50, 7), &map());
+        let [WorldToPlayerMessage::AutoWalkPath(steps)] = &received(&mut first)[..] else {
+            panic!("no path");
+        };
+        assert_eq!(steps.len(), 4);
+
+        players.auto_walk(1, Position::new(51, 50, 7), &map());
+        assert_eq!(received(&mut first), [WorldToPlayerMessage::AutoWalkUnreachable]);
+    }
+
+    #[test]
+    fn test_chat_range() {
+        let mut players = Players::new(SlowClientPolicy::Disconnect);
+        let mut speaker = load(&mut players, character(1, Position::new(50, 50, 7)));
+        let mut near = load(&mut players, character(2, Position::new(51, 51, 7)));
+        let mut far = load(&mut players, character(3, Position::new(58, 50, 7)));
+        let mut upstairs = load(&mut players, character(4, Position::new(50, 50, 6)));
+        for receiver in [&mut speaker, &mut near, &mut far, &mut upstairs] {
+            received(receiver);
+        }
+        let chat = |chat_type, message: &str| WorldToPlayerMessage::Chat {
+            chat_type,
+            message: message.to_string(),
+            sender: "Player 1".to_string(),
+            position: Position::new(50, 50, 7),
+        };
+
+        players.chat(1, ChatType::Whisper, "psst");
+        assert_eq!(received(&mut speaker), [chat(ChatType::Whisper, "psst")]);
+        assert_eq!(received(&mut near), [chat(ChatType::Whisper, "psst")]);
+        assert_eq!(received(&mut far), [chat(ChatType::Whisper, WHISPER_FROM_AFAR)]);
+        assert!(received(&mut upstairs).is_empty());
+
+        players.chat(1, ChatType::Normal, "hi");
+        assert_eq!(received(&mut far), [chat(ChatType::Normal, "hi")]);
+        assert!(received(&mut upstairs).is_empty());
+
+        players.chat(1, ChatType::Yell, "hey");
+        assert_eq!(received(&mut upstairs), [chat(ChatType::Yell, "hey")]);
+    }
+
+    #[test]
+    fn test_private_message() {
+        let mut players = Players::new(SlowClientPolicy::Disconnect);
+        let mut sender = load(&mut players, character(1, Position::new(50, 50, 7)));
+        let mut receiver = load(&mut players, character(2, Position::new(10, 10, 5)));
+        received(&mut sender);
+        received(&mut receiver);
+
+        players.private_message(1, "player 2", "hi");
+        assert_eq!(
+            received(&mut receiver),
+            [WorldToPlayerMessage::PrivateMessage {
+                sender: "Player 1".to_string(),
+                message: "hi".to_string(),
+            }]
+        );
+        assert_eq!(
+            received(&mut sender),
+            [WorldToPlayerMessage::PrivateMessageSent("Player 2".to_string())]
+        );
+
+        players.private_message(1, "Player 3", "hi");
+        assert_eq!(
+            received(&mut sender),
+            [WorldToPlayerMessage::PlayerNotOnline("Player 3".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_login_on_free_tile() {
+        let mut players = Players::new(SlowClientPolicy::Disconnect);
+        let mut first = load(&mut players, character(1, Position::new(50, 50, 7)));
+
+        let (sender, mut second) = channel(16);
+        players.load(character(2, Position::new(50, 50, 7)), sender, &map());
+        assert_eq!(
+            received(&mut second),
+            [
+                WorldToPlayerMessage::Loaded(Position::new(50, 49, 7)),
+                WorldToPlayerMessage::CharacterAppeared(character(1, Position::new(50, 50, 7))),
+            ]
+        );
+        assert_eq!(
+            received(&mut first),
+            [WorldToPlayerMessage::CharacterAppeared(character(2, Position::new(50, 49, 7)))]
+        );
+
+        // Off the map, the nearest tile of the map is used
+        let (sender, mut third) = channel(16);
+        players.load(character(3, Position::new(50, 100, 7)), sender, &map());
+        assert_eq!(received(&mut third)[0], WorldToPlayerMessage::Loaded(Position::new(50, 99, 7)));
+    }
+
+    #[test]
+    fn test_walk_blocked() {
+        let mut players = Players::new(SlowClientPolicy::Disconnect);
+        let mut first = load(&mut players, character(1, Position::new(50, 50, 7)));
+        let mut second = load(&mut players, character(2, Position::new(51, 50, 7)));
+        received(&mut first);
+        received(&mut second);
+
+        players.walk(1, Direction::East, &map());
+        assert_eq!(received(&mut first), [WorldToPlayerMessage::WalkBlocked(Direction::East)]);
+        assert_eq!(
+            received(&mut second),
+            [WorldToPlayerMessage::CharacterTurned {
+                id: 1,
+                position: Position::new(50, 50, 7),
+                direction: Direction::East,
+            }]
+        );
+
+        let mut third = load(&mut players, character(3, Position::new(99, 50, 7)));
+        players.walk(3, Direction::East, &map());
+        assert_eq!(received(&mut third), [WorldToPlayerMessage::WalkBlocked(Direction::East)]);
+    }
+}
@@ -0,0 +1,499 @@
+use anyhow::Result;
+use legbone::{
+    character::{Direction, Gender, OutfitColors},
+    client::{Client, NewPlayer},
+    config::{self, Config, CONFIG},
+    io::hex,
+    map::{self, position::Position, MapType},
+    network::{
+        connection::{
+            prepare_character_list,
+            recorder::{RecordDirection, RecordEntry},
+            transport::MemoryTransport,
+            Connection,
+        },
+        dissector::{self, FieldValue, MessageKind},
+        header::{AuxiliaryHeaderSend, HeaderReceive, HeaderSend},
+        proxy, replay,
+    },
+    world::{message::PlayerToWorldMessage, World, WorldOptions},
+    Protocol,
+};
+use std::{net::SocketAddr, sync::Once};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::Sender,
+    task,
+};
+
+static INIT: Once = Once::new();
+
+/// Starts a world with its own message loop
+async fn start_world() -> Sender<PlayerToWorldMessage> {
+    INIT.call_once(|| {
+        let config = Config {
+            server: config::Server {
+                ip: [127, 0, 0, 1].into(),
+                port: 0,
+                debug_commands: true,
+            },
+            world: config::World {
+                map: config::Map {
+                    map_type: MapType::Checkerboard,
+                    file: None,
+                    tile: None,
+                },
+                day_night_cycle: false,
+            },
+            recording: None,
+            discoveries: None,
+            connection: Default::default(),
+        };
+        map::init_map(&config.world.map).unwrap();
+        CONFIG.set(config).unwrap();
+    });
+
+    let world = World::new(config::Connection::default().inbound_queue_size);
+    let sender = world.read().await.sender();
+    World::init_loop(
+        &world,
+        WorldOptions {
+            day_night_cycle_enabled: false,
+            ..Default::default()
+        },
+    );
+    sender
+}
+
+/// Starts a server with its own world on a random local port
+async fn start_server() -> Result<SocketAddr> {
+    let sender = start_world().await;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    task::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            task::spawn(Connection::run(stream, sender.clone()));
+        }
+    });
+
+    Ok(address)
+}
+
+async fn login(address: SocketAddr, protocol: Protocol, name: &str) -> Result<Client> {
+    let mut client = Client::connect(address, protocol).await?;
+    client.login(name, "secret").await?;
+    client.receive_until(HeaderSend::Login).await?;
+    Ok(client)
+}
+
+#[tokio::test]
+async fn test_login_walk_and_chat() -> Result<()> {
+    let address = start_server().await?;
+
+    for protocol in [
+        Protocol::Tibia103,
+        Protocol::Tibia300,
+        Protocol::Tibia310,
+        Protocol::Tibia400,
+        Protocol::Tibia412,
+        Protocol::Tibia501,
+        Protocol::Tibia510,
+        Protocol::Tibia620,
+        Protocol::Tibia630,
+        Protocol::Tibia640,
+        Protocol::Tibia650,
+        Protocol::Tibia661,
+        Protocol::Tibia694,
+    ] {
+        let mut client = login(address, protocol, "Tester").await?;
+
+        client.walk(Direction::North).await?;
+        let message = client.receive_until(HeaderSend::MoveOneTileNorth).await?;
+        assert!(matches!(message.field("map"), Some(FieldValue::Map(map)) if !map.is_empty()));
+
+        client.chat("hello").await?;
+        let message = client.receive_until(HeaderSend::Chat).await?;
+        assert!(message.text("message").unwrap().ends_with("hello"), "{protocol:?}: {message}");
+
+        client.logout().await?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_create_new_player() -> Result<()> {
+    let address = start_server().await?;
+
+    for protocol in [
+        Protocol::Tibia103,
+        Protocol::Tibia300,
+        Protocol::Tibia310,
+        Protocol::Tibia400,
+        Protocol::Tibia412,
+        Protocol::Tibia501,
+    ] {
+        let mut client = Client::connect(address, protocol).await?;
+        client
+            .create_new_player(&NewPlayer {
+                name: "Newbie".to_string(),
+                password: "secret".to_string(),
+                gender: Gender::Female,
+                outfit: OutfitColors::new(1, 2, 3, 4),
+                real_name: "Real Name".to_string(),
+                location: "Somewhere".to_string(),
+                email: "newbie@example.com".to_string(),
+                comment: "Hi".to_string(),
+            })
+            .await?;
+        client.receive_until(HeaderSend::Login).await?;
+
+        client.request_change_data().await?;
+        let message = client.receive_until(HeaderSend::DataWindow).await?;
+        assert_eq!(message.text("name"), Some("Newbie"), "{protocol:?}");
+        assert!(
+            matches!(message.field("gender"), Some(FieldValue::Gender(Gender::Female))),
+            "{protocol:?}: {message}"
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_account_login() -> Result<()> {
+    let address = start_server().await?;
+
+    let characters = Client::account_login(address, Protocol::Tibia650, 123456, "secret").await?;
+    assert!(!characters.is_empty());
+
+    let character = &characters[0];
+    assert_eq!(SocketAddr::V4(character.address), address);
+    let mut client = Client::connect(address, Protocol::Tibia650).await?;
+    client.login(&character.name, "secret").await?;
+    client.receive_until(HeaderSend::Login).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_account_login_with_trailing_bytes() -> Result<()> {
+    let address = start_server().await?;
+    let mut stream = TcpStream::connect(address).await?;
+
+    let mut frame = vec![0x01, 0x01, 0x00];
+    frame.extend((Protocol::Tibia650 as u16).to_le_bytes());
+    frame.extend(123456_u32.to_le_bytes());
+    frame.extend(6_u16.to_le_bytes());
+    frame.extend(b"secret");
+    frame.extend([0x00; 4]);
+    stream.write_all(&[&(frame.len() as u16).to_le_bytes()[..], &frame].concat()).await?;
+
+    let length = stream.read_u16_le().await?;
+    let mut character_list = vec![0_u8; length as usize];
+    stream.read_exact(&mut character_list).await?;
+    let message = dissector::decode_character_list(&character_list, Protocol::Tibia650).await?;
+    assert!(message.number("character_count").is_some_and(|count| count > 0));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_replay_account_login() -> Result<()> {
+    let sender = start_world().await;
+
+    let mut login = vec![0x01, 0x01, 0x00];
+    login.extend((Protocol::Tibia650 as u16).to_le_bytes());
+    login.extend(123456_u32.to_le_bytes());
+    login.extend(6_u16.to_le_bytes());
+    login.extend(b"secret");
+    // Recorded on another server, the replayed one is on a port of its own
+    let character_list = prepare_character_list(SocketAddr::from(([192, 168, 0, 2], 7171))).await?;
+    let entries = [
+        (RecordDirection::ClientToServer, login),
+        (RecordDirection::ServerToClient, character_list),
+    ]
+    .map(|(direction, frame)| RecordEntry {
+        timestamp: 0,
+        direction,
+        protocol: Protocol::Tibia650 as u16,
+        bytes: hex::encode(&[&(frame.len() as u16).to_le_bytes()[..], &frame].concat()),
+        messages: vec![],
+    });
+    let path = std::env::temp_dir().join(format!("legbone-account-login-{}.jsonl", std::process::id()));
+    let lines: Vec<String> = entries.iter().map(serde_json::to_string).collect::<Result<_, _>>()?;
+    std::fs::write(&path, lines.join("\n"))?;
+
+    let report = replay::replay(&path, sender).await;
+    std::fs::remove_file(&path)?;
+    let report = report?;
+    assert_eq!(report.expected_frames, 1);
+    assert_eq!(report.actual_frames, 1);
+    assert!(report.differences.is_empty(), "{report}");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_proxy() -> Result<()> {
+    let server_address = start_server().await?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    task::spawn(proxy::proxy(listener, server_address));
+
+    let characters = Client::account_login(address, Protocol::Tibia650, 123456, "secret").await?;
+    let character = &characters[0];
+    assert_eq!(SocketAddr::V4(character.address), address);
+
+    let mut client = Client::connect(address, Protocol::Tibia650).await?;
+    client.login(&character.name, "secret").await?;
+    client.receive_until(HeaderSend::Login).await?;
+    client.walk(Direction::North).await?;
+    client.receive_until(HeaderSend::MoveOneTileNorth).await?;
+    client.logout().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_proxy_unknown_protocol() -> Result<()> {
+    let upstream = TcpListener::bind("127.0.0.1:0").await?;
+    let upstream_address = upstream.local_addr()?;
+    let server = task::spawn(async move {
+        let (mut stream, _) = upstream.accept().await?;
+        let mut login = [0_u8; 69];
+        stream.read_exact(&mut login).await?;
+        stream.write_all(b"pong").await?;
+        anyhow::Ok(login)
+    });
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    task::spawn(proxy::proxy(listener, upstream_address));
+
+    let mut login = vec![67, 0, 0x00, 0x00, 0x01, 0x01, 0x00];
+    login.extend(999_u16.to_le_bytes());
+    login.resize(69, 0);
+    let mut client = TcpStream::connect(address).await?;
+    client.write_all(&login).await?;
+
+    let mut pong = [0_u8; 4];
+    client.read_exact(&mut pong).await?;
+    assert_eq!(&pong, b"pong");
+    assert_eq!(server.await??.to_vec(), login);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_probe() -> Result<()> {
+    let address = start_server().await?;
+    let mut client = login(address, Protocol::Tibia650, "Prober").await?;
+
+    client.chat("\\d probe echo").await?;
+    client.receive_until(HeaderSend::Echo).await?;
+    client.send(HeaderReceive::Echo, &[]).await?;
+    let message = client.receive_until(HeaderSend::StatusMessage).await?;
+    assert_eq!(message.text("message"), Some("echo done"));
+
+    client.logout().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_raw_header_outside_the_table() -> Result<()> {
+    let address = start_server().await?;
+    let mut client = login(address, Protocol::Tibia650, "Raw").await?;
+
+    // No message of the server uses 0x35, it is sent anyway
+    client.chat("\\d hdr 35 0102").await?;
+    let message = loop {
+        let message = client.receive().await?;
+        if message.kind == MessageKind::Unknown(0x35) {
+            break message;
+        }
+    };
+    assert_eq!(message.remainder, [0x01, 0x02]);
+
+    client.logout().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_players_see_each_other() -> Result<()> {
+    let address = start_server().await?;
+    let mut alice = login(address, Protocol::Tibia650, "Alice").await?;
+    let mut bob = login(address, Protocol::Tibia400, "Bob").await?;
+
+    for (client, name) in [(&mut alice, "Bob"), (&mut bob, "Alice")] {
+        let message = client.receive_until(HeaderSend::UpdateObject).await?;
+        assert_eq!(message.number("update_type"), Some(1), "{message}");
+        // Above the ground, the only object of checkerboard tiles
+        assert_eq!(message.number("stack_pos"), Some(1), "{message}");
+        let message = client.receive().await?;
+        assert_eq!(message.kind, MessageKind::Auxiliary(AuxiliaryHeaderSend::Character));
+        assert_eq!(message.text("name"), Some(name), "{message}");
+    }
+
+    bob.walk(Direction::North).await?;
+    let message = alice.receive_until(HeaderSend::UpdateObject).await?;
+    assert_eq!(message.number("update_type"), Some(0), "{message}");
+    let message = alice.receive_until(HeaderSend::UpdateObject).await?;
+    assert_eq!(message.number("update_type"), Some(1), "{message}");
+    let Some(&FieldValue::Position(position)) = message.field("position") else {
+        panic!("{message}");
+    };
+
+    bob.logout().await?;
+    let message = alice.receive_until(HeaderSend::UpdateObject).await?;
+    assert_eq!(message.number("update_type"), Some(0), "{message}");
+    assert_eq!(message.field("position"), Some(&FieldValue::Position(position)));
+
+    alice.logout().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_chat_between_protocols() -> Result<()> {
+    let address = start_server().await?;
+    let mut alice = login(address, Protocol::Tibia300, "Alice").await?;
+    let mut bob = login(address, Protocol::Tibia650, "Bob").await?;
+
+    bob.chat("#y hello").await?;
+    for client in [&mut bob, &mut alice] {
+        let message = client.receive_until(HeaderSend::Chat).await?;
+        assert_eq!(message.text("message"), Some("Bob\tHELLO"), "{message}");
+    }
+
+    // 3.0 clients draw messages one tile up and left of the position
+    alice.chat("hi").await?;
+    let message = alice.receive_until(HeaderSend::Chat).await?;
+    assert_eq!(message.field("position"), Some(&FieldValue::Position(Position::new(51, 51, 7))));
+    let message = bob.receive_until(HeaderSend::Chat).await?;
+    assert_eq!(message.field("position"), Some(&FieldValue::Position(Position::new(50, 50, 7))));
+    assert_eq!(message.text("message"), Some("Alice\thi"));
+
+    alice.logout().await?;
+    bob.logout().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_private_message() -> Result<()> {
+    let address = start_server().await?;
+    let mut alice = login(address, Protocol::Tibia300, "Alice").await?;
+    let mut bob = login(address, Protocol::Tibia650, "Bob").await?;
+    let message = bob.receive_until(HeaderSend::StatusMessage).await?;
+    assert_eq!(message.text("message"), Some("Hello, World!"));
+
+    bob.chat("*alice* hi there").await?;
+    let message = alice.receive_until(HeaderSend::Chat).await?;
+    assert_eq!(message.text("message"), Some("Bob\thi there"));
+    assert_eq!(
+        message.number("chat_type"),
+        Some(Protocol::Tibia300.capabilities().private_message as u32)
+    );
+    let message = bob.receive_until(HeaderSend::StatusMessage).await?;
+    assert_eq!(message.text("message"), Some("Message sent to Alice."));
+
+    bob.chat("*Carol* hi").await?;
+    let message = bob.receive_until(HeaderSend::StatusMessage).await?;
+    assert_eq!(message.text("message"), Some("A player with this name is not online."));
+
+    alice.logout().await?;
+    bob.logout().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_walk_blocked_by_player() -> Result<()> {
+    let address = start_server().await?;
+    let mut alice = login(address, Protocol::Tibia650, "Alice").await?;
+    let mut bob = login(address, Protocol::Tibia300, "Bob").await?;
+
+    // Bob can't log in on the tile of Alice, the nearest free one is north of it
+    let message = bob.receive_until(HeaderSend::Map).await?;
+    assert_eq!(message.field("position"), Some(&FieldValue::Position(Position::new(50, 49, 7))));
+
+    let message = alice.receive_until(HeaderSend::StatusMessage).await?;
+    assert_eq!(message.text("message"), Some("Hello, World!"));
+    alice.walk(Direction::North).await?;
+    let message = alice.receive_until(HeaderSend::StatusMessage).await?;
+    assert_eq!(message.text("message"), Some("Sorry, not possible."));
+    // The client took the step already, it is put back where it is
+    let message = alice.receive().await?;
+    assert_eq!(message.kind, MessageKind::Server(HeaderSend::Map));
+    assert_eq!(message.field("position"), Some(&FieldValue::Position(Position::new(50, 50, 7))));
+
+    alice.walk(Direction::East).await?;
+    alice.receive_until(HeaderSend::MoveOneTileEast).await?;
+
+    alice.logout().await?;
+    bob.logout().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_auto_walk() -> Result<()> {
+    let address = start_server().await?;
+    let mut client = login(address, Protocol::Tibia650, "Walker").await?;
+    let message = client.receive_until(HeaderSend::StatusMessage).await?;
+    assert_eq!(message.text("message"), Some("Hello, World!"));
+
+    client.auto_walk(Position::new(52, 49, 7)).await?;
+    let mut steps = vec![];
+    while steps.len() < 3 {
+        let message = client.receive().await?;
+        if let MessageKind::Server(header @ (HeaderSend::MoveOneTileNorth | HeaderSend::MoveOneTileEast)) = message.kind {
+            steps.push(header);
+        }
+    }
+    steps.sort_by_key(|header| *header as u16);
+    assert_eq!(
+        steps,
+        [HeaderSend::MoveOneTileNorth, HeaderSend::MoveOneTileEast, HeaderSend::MoveOneTileEast]
+    );
+
+    client.auto_walk(Position::new(500, 49, 7)).await?;
+    let message = client.receive_until(HeaderSend::StatusMessage).await?;
+    assert_eq!(message.text("message"), Some("There is no way."));
+
+    client.logout().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unexpected_bytes_keep_the_session() -> Result<()> {
+    let address = start_server().await?;
+    let mut client = login(address, Protocol::Tibia650, "Sloppy").await?;
+
+    // Not a direction, only this message is dropped
+    client.send(HeaderReceive::Walk, &[0x09]).await?;
+    // A step with a byte more than known, the step is still made
+    client.send(HeaderReceive::Walk, &[Direction::North as u8, 0x00]).await?;
+    client.receive_until(HeaderSend::MoveOneTileNorth).await?;
+
+    client.logout().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_session_over_memory_transport() -> Result<()> {
+    let sender = start_world().await;
+
+    let (transport, stream) =
+        MemoryTransport::pair(([127, 0, 0, 1], 7171).into(), ([127, 0, 0, 1], 50000).into());
+    let server = task::spawn(Connection::run(transport, sender));
+
+    let mut client = Client::new(stream, Protocol::Tibia400);
+    client.login("Tester", "secret").await?;
+    client.receive_until(HeaderSend::Login).await?;
+
+    client.walk(Direction::East).await?;
+    client.receive_until(HeaderSend::MoveOneTileEast).await?;
+
+    client.logout().await?;
+    server.await?;
+
+    Ok(())
+}
@@ -0,0 +1,217 @@
+//! Every message the client sends, encoded for each protocol, has to decode back to itself and
+//! with the layouts of `network::layout`, which the protocol documentation is generated from, to
+//! the values of the message. The decoder and the documentation can't drift apart without
+//! failing here.
+use anyhow::{anyhow, Result};
+use legbone::{
+    character::{Direction, FightMode, FightStance, OutfitColors},
+    map::position::Position,
+    network::{
+        dissector::{self, FieldValue},
+        header::HeaderReceive,
+        layout,
+        message::client::{ClientMessage, PlayerDetails},
+    },
+    protocol::{MessageForms, PlayerDataLayout},
+    Protocol,
+};
+
+/// One message of every kind, with the optional fields the protocol sends
+fn messages(protocol: Protocol) -> Vec<ClientMessage> {
+    let capabilities = protocol.capabilities();
+    let position = Position::new(50, 49, 7);
+    let details = match capabilities.player_data {
+        PlayerDataLayout::Character => None,
+        player_data => Some(PlayerDetails {
+            password: "secret".to_string(),
+            real_name: "Bob".to_string(),
+            location: "Here".to_string(),
+            email: "bob@example.com".to_string(),
+            comment: (player_data == PlayerDataLayout::AccountWithComment)
+                .then(|| "Hi".to_string()),
+        }),
+    };
+
+    vec![
+        ClientMessage::UserList,
+        ClientMessage::PlayerInfo {
+            name: "Bob".to_string(),
+        },
+        ClientMessage::Walk {
+            direction: Direction::West,
+        },
+        ClientMessage::AutoWalk { position },
+        ClientMessage::LookAt { position },
+        ClientMessage::Chat {
+            message: b"hello".to_vec(),
+        },
+        ClientMessage::ChangeDirection {
+            direction: Direction::North,
+        },
+        ClientMessage::Comment {
+            comment: "nice".to_string(),
+        },
+        ClientMessage::Push {
+            from: position,
+            object_id: 0x0123,
+            stack_pos: 1,
+            to: Position::new(51, 49, 7),
+            count: (capabilities.forms == MessageForms::Full).then_some(2),
+        },
+        ClientMessage::UseItem {
+            item_type: 1,
+            position,
+            item_id: 0x0456,
+            stack_pos: 2,
+            unknown: 4,
+        },
+        ClientMessage::CloseContainer { local_id: 3 },
+        ClientMessage::RequestChangeData,
+        ClientMessage::SetData {
+            outfit: OutfitColors::new(1, 2, 3, 4),
+            details,
+        },
+        ClientMessage::SetText { data: vec![1, 2] },
+        ClientMessage::HouseText { data: vec![3] },
+        ClientMessage::ChangeMode {
+            fight_mode: FightMode::Defensive,
+            fight_stance: FightStance::Chase,
+        },
+        ClientMessage::ExitBattle,
+        ClientMessage::SetTarget { id: 0x01020304 },
+        ClientMessage::Echo,
+        ClientMessage::Logout,
+    ]
+}
+
+fn number(value: impl Into<u32>) -> FieldValue {
+    FieldValue::Number(value.into())
+}
+
+fn text(text: &str) -> FieldValue {
+    FieldValue::Text(text.to_string())
+}
+
+/// The fields of the layouts that hold the values of the message
+fn expected_fields(message: &ClientMessage) -> Vec<(&'static str, FieldValue)> {
+    match message {
+        ClientMessage::PlayerInfo { name } => vec![("name", text(name))],
+        ClientMessage::Walk { direction } | ClientMessage::ChangeDirection { direction } => {
+            vec![("direction", number(*direction as u8))]
+        }
+        ClientMessage::AutoWalk { position } | ClientMessage::LookAt { position } => {
+            vec![("position", FieldValue::Position(*position))]
+        }
+        ClientMessage::Chat { message } => {
+            vec![("message", FieldValue::Text(message.iter().map(|&byte| byte as char).collect()))]
+        }
+        ClientMessage::Comment { comment } => vec![("comment", text(comment))],
+        ClientMessage::Push {
+            from,
+            object_id,
+            stack_pos,
+            to,
+            count,
+        } => {
+            let mut fields = vec![
+                ("from", FieldValue::Position(*from)),
+                ("object_id", number(*object_id)),
+                ("stack_pos", number(*stack_pos)),
+                ("to", FieldValue::Position(*to)),
+            ];
+            fields.extend(count.map(|count| ("count", number(count))));
+            fields
+        }
+        ClientMessage::UseItem {
+            item_type,
+            position,
+            item_id,
+            stack_pos,
+            unknown,
+        } => vec![
+            ("item_type", number(*item_type)),
+            ("position", FieldValue::Position(*position)),
+            ("item_id", number(*item_id)),
+            ("stack_pos", number(*stack_pos)),
+            ("unknown", FieldValue::Bytes(vec![*unknown])),
+        ],
+        ClientMessage::CloseContainer { local_id } => vec![("local_id", number(*local_id))],
+        ClientMessage::SetData { outfit, details } => {
+            let mut fields = vec![("outfit", FieldValue::Outfit(*outfit))];
+            if let Some(details) = details {
+                fields.extend([
+                    ("password", text(&details.password)),
+                    ("real_name", text(&details.real_name)),
+                    ("location", text(&details.location)),
+                    ("email", text(&details.email)),
+                ]);
+                fields.extend(details.comment.as_deref().map(|comment| ("comment", text(comment))));
+            }
+            fields
+        }
+        ClientMessage::ChangeMode {
+            fight_mode,
+            fight_stance,
+        } => vec![
+            ("fight_mode", number(*fight_mode as u8)),
+            ("fight_stance", number(*fight_stance as u8)),
+        ],
+        ClientMessage::SetTarget { id } => vec![("id", number(*id))],
+        _ => vec![],
+    }
+}
+
+#[tokio::test]
+async fn test_client_messages_match_layouts() -> Result<()> {
+    let mut failures = vec![];
+    for protocol in Protocol::ALL {
+        let messages = messages(protocol);
+        for header in (0..=u8::MAX as u16).filter_map(|code| HeaderReceive::try_from(code).ok()) {
+            if !messages.iter().any(|message| message.header() == header) {
+                failures.push(format!("{header:?} {protocol:?}: no message to check"));
+            }
+        }
+
+        for message in messages {
+            let header = message.header();
+            let bytes = message.encode(protocol).await?;
+            match ClientMessage::decode(&bytes, protocol) {
+                Ok(decoded) if decoded == message => {}
+                Ok(decoded) => failures.push(format!("{header:?} {protocol:?}: decodes to {decoded:?}")),
+                Err(err) => failures.push(format!("{header:?} {protocol:?}: does not decode: {err}")),
+            }
+
+            // Messages of unknown layout are only kept as bytes
+            if layout::client_message_layout(header, protocol).is_none() {
+                continue;
+            }
+            let decoded = match dissector::decode_client_frame(&bytes, protocol).await {
+                Ok(decoded) => decoded,
+                Err(err) => {
+                    failures.push(format!("{header:?} {protocol:?}: does not match its layout: {err}"));
+                    continue;
+                }
+            };
+            if !decoded.remainder.is_empty() {
+                failures.push(format!(
+                    "{header:?} {protocol:?}: longer than its layout, {} bytes left",
+                    decoded.remainder.len()
+                ));
+            }
+            for (name, value) in expected_fields(&message) {
+                let actual = decoded.field(name);
+                if actual != Some(&value) {
+                    failures.push(format!(
+                        "{header:?} {protocol:?}: decodes {name} to {actual:?} instead of {value:?}"
+                    ));
+                }
+            }
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("{} client messages differ:\n{}", failures.len(), failures.join("\n")))
+    }
+}
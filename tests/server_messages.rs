@@ -0,0 +1,460 @@
+//! Golden fixtures of the messages sent to the client. Every file of
+//! `tests/fixtures/server_messages` holds a message and the bytes it encodes to with each
+//! protocol, without the length of the frame. Set `UPDATE_FIXTURES=1` to write the bytes the
+//! server encodes now into the fixtures, e.g. after adding one, and review the diff.
+//!
+//! The bytes must also decode with the layouts of `network::layout`, which the protocol
+//! documentation is generated from, to the values of the message, so the documentation can't
+//! drift from the encoders. Raw messages are left out, their payloads are arbitrary.
+use anyhow::{anyhow, Result};
+use legbone::{
+    character::{CharacterUpdateType, OutfitColors, OutfitType},
+    chat::{encoding, ChatType},
+    config,
+    io::hex,
+    map::{self, position::Position, MapType},
+    network::{
+        connection::prepare_character_list,
+        dissector::{self, DecodedField, FieldValue, MessageKind},
+        header::{AuxiliaryHeaderSend, HeaderSend},
+        message::server::ServerMessage,
+    },
+    protocol::{MessageForms, PositionFormat, SkillLayout, StatsLayout},
+    Protocol,
+};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+
+const FIXTURES: &str = "tests/fixtures/server_messages";
+/// Bytes of messages that fail to encode with the protocol
+const UNSUPPORTED: &str = "unsupported";
+
+#[derive(Serialize, Deserialize)]
+struct Fixture {
+    /// What the fixture guards against, e.g. a quirk of an old client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message: Option<ServerMessage>,
+    /// Server address of a character list, sent before the protocol is known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    character_list: Option<SocketAddr>,
+    /// Encoded bytes as hex by protocol number
+    #[serde(default)]
+    bytes: BTreeMap<u16, String>,
+}
+
+impl Fixture {
+    async fn encode(&self, protocol: Protocol) -> Result<String> {
+        let encoded = match (&self.message, self.character_list) {
+            (Some(message), None) => message.encode(protocol).await,
+            (None, Some(address)) => prepare_character_list(address).await,
+            _ => return Err(anyhow!("Fixtures need either a message or a character list")),
+        };
+        Ok(encoded.map_or_else(|_| UNSUPPORTED.to_string(), |bytes| hex::encode(&bytes)))
+    }
+
+    /// Raw messages carry whatever payload the debug commands send, even after unknown headers
+    fn has_layout(&self) -> bool {
+        !matches!(self.message, Some(ServerMessage::Raw { .. }))
+    }
+
+    /// Decodes the encoded bytes with the layouts, failing if any byte is left undecoded or if
+    /// a field does not hold the value of the message
+    async fn check_layout(&self, protocol: Protocol, bytes: &[u8]) -> Result<()> {
+        let (decoded, expected) = match (&self.message, self.character_list) {
+            (Some(message), _) => (
+                dissector::decode_server_frame(bytes, protocol).await?,
+                expected_messages(message, protocol)?,
+            ),
+            (None, Some(address)) => (
+                vec![dissector::decode_character_list(bytes, protocol).await?],
+                vec![expected_character_list(address)],
+            ),
+            (None, None) => return Err(anyhow!("Fixtures need either a message or a character list")),
+        };
+
+        if let Some(message) = decoded.iter().find(|message| !message.remainder.is_empty()) {
+            return Err(anyhow!("{:?} has no layout, {} bytes left", message.kind, message.remainder.len()));
+        }
+        let kinds = |messages: &[Expected]| messages.iter().map(|(kind, _)| *kind).collect::<Vec<_>>();
+        let decoded_kinds: Vec<_> = decoded.iter().map(|message| message.kind).collect();
+        if decoded_kinds != kinds(&expected) {
+            return Err(anyhow!("decodes to {decoded_kinds:?} instead of {:?}", kinds(&expected)));
+        }
+        for (message, (kind, fields)) in decoded.iter().zip(expected) {
+            for (name, value) in fields {
+                let actual = message.field(name);
+                if actual != Some(&value) {
+                    return Err(anyhow!("{kind:?} decodes {name} to {actual:?} instead of {value:?}"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A message as the layouts must decode it, with the fields that come from the message. Fields
+/// filled in by the encoder alone, like map descriptions, are not listed
+type Expected = (MessageKind, Vec<(&'static str, FieldValue)>);
+
+fn number(value: impl Into<u32>) -> FieldValue {
+    FieldValue::Number(value.into())
+}
+
+/// Short positions have no floor, they are read back on the ground floor
+fn sent_position(position: Position, protocol: Protocol) -> FieldValue {
+    if protocol.capabilities().position == PositionFormat::Long {
+        return FieldValue::Position(position);
+    }
+    // The coordinates are private, their serialized form is used instead
+    let mut value = serde_json::to_value(position).expect("Positions serialize");
+    value["z"] = 7.into();
+    FieldValue::Position(serde_json::from_value(value).expect("Positions deserialize"))
+}
+
+/// Strings are decoded byte by byte
+fn text(bytes: &[u8]) -> FieldValue {
+    FieldValue::Text(bytes.iter().map(|&byte| byte as char).collect())
+}
+
+fn expected_messages(message: &ServerMessage, protocol: Protocol) -> Result<Vec<Expected>> {
+    let capabilities = protocol.capabilities();
+    let full_forms = capabilities.forms == MessageForms::Full;
+    let server = MessageKind::Server;
+
+    let expected = match message {
+        ServerMessage::Login { player_id } => {
+            let fields = if full_forms { vec![("id", number(*player_id))] } else { vec![] };
+            vec![(server(HeaderSend::Login), fields)]
+        }
+        ServerMessage::Error(message) => vec![(server(HeaderSend::Error), vec![("message", text(message.as_bytes()))])],
+        ServerMessage::Info(message) => vec![(server(HeaderSend::Info), vec![("message", text(message.as_bytes()))])],
+        ServerMessage::StatusMessage(message) => {
+            vec![(server(HeaderSend::StatusMessage), vec![("message", text(message.as_bytes()))])]
+        }
+        ServerMessage::GreenChat(message) => {
+            vec![(server(HeaderSend::GreenChat), vec![("message", text(message.as_bytes()))])]
+        }
+        ServerMessage::MessageOfTheDay { number: message_number, message } => {
+            let mut fields = vec![("message", text(message.as_bytes()))];
+            if capabilities.numbered_message_of_the_day {
+                fields.push(("message_number", number(*message_number)));
+            }
+            vec![(server(HeaderSend::MessageOfTheDay), fields)]
+        }
+        ServerMessage::DataWindow { name, gender, outfit } => vec![(
+            server(HeaderSend::DataWindow),
+            vec![
+                ("name", text(name.as_bytes())),
+                ("gender", FieldValue::Gender(*gender)),
+                ("outfit", FieldValue::Outfit(*outfit)),
+            ],
+        )],
+        ServerMessage::Map { player } => {
+            vec![(server(HeaderSend::Map), vec![("position", sent_position(player.position, protocol))])]
+        }
+        ServerMessage::MoveOneTile { direction, .. } => vec![(server((*direction).into()), vec![])],
+        ServerMessage::EquippedItem { slot, item, stack } => {
+            let mut fields = vec![("slot", number(*slot as u8)), ("item_id", number(*item))];
+            if full_forms {
+                fields.push(("stack", number(*stack)));
+            }
+            vec![(server(HeaderSend::EquippedItem), fields)]
+        }
+        ServerMessage::UpdateObject { .. }
+        | ServerMessage::UpdateOutfit { .. }
+        | ServerMessage::ChangeDirection { .. }
+        | ServerMessage::Character { .. }
+            if !full_forms =>
+        {
+            vec![]
+        }
+        ServerMessage::UpdateObject {
+            position,
+            update_type,
+            stack_pos,
+        } => vec![(
+            server(HeaderSend::UpdateObject),
+            vec![
+                ("position", sent_position(*position, protocol)),
+                ("update_type", number(*update_type as u8)),
+                ("stack_pos", number(*stack_pos)),
+            ],
+        )],
+        ServerMessage::UpdateCharacter { id, update_type, value } => vec![(
+            server(HeaderSend::UpdateCharacter),
+            vec![
+                ("id", number(*id)),
+                ("update_type", number(*update_type as u8)),
+                ("value", number(*value)),
+            ],
+        )],
+        ServerMessage::UpdateOutfit {
+            id,
+            outfit_type,
+            colors,
+        } => vec![(
+            server(HeaderSend::UpdateCharacter),
+            vec![
+                ("id", number(*id)),
+                ("update_type", number(CharacterUpdateType::Outfit as u8)),
+                ("outfit_type", number(*outfit_type as u8)),
+                ("outfit", FieldValue::Outfit(*colors)),
+            ],
+        )],
+        ServerMessage::ChangeDirection { id, direction } => vec![(
+            MessageKind::Auxiliary(AuxiliaryHeaderSend::ChangeDirection),
+            vec![("direction", number(*direction as u8)), ("id", number(*id))],
+        )],
+        ServerMessage::Character {
+            id,
+            name,
+            direction,
+            outfit,
+        } => {
+            // The fields of outfits are private, their serialized form is used instead
+            let outfit = serde_json::to_value(outfit)?;
+            let outfit_type: OutfitType = serde_json::from_value(outfit["outfit_type"].clone())?;
+            let colors: OutfitColors = serde_json::from_value(outfit["colors"].clone())?;
+            vec![(
+                MessageKind::Auxiliary(AuxiliaryHeaderSend::Character),
+                vec![
+                    ("id", number(*id)),
+                    ("name", text(name.as_bytes())),
+                    ("direction", number(*direction as u8)),
+                    ("outfit_type", number(outfit_type as u8)),
+                    ("outfit", FieldValue::Outfit(colors)),
+                ],
+            )]
+        }
+        ServerMessage::MagicEffect { effect, position } => vec![(
+            server(HeaderSend::MagicEffect),
+            vec![("position", sent_position(*position, protocol)), ("effect", number(*effect as u8))],
+        )],
+        ServerMessage::WorldLight(light_level) => {
+            vec![(server(HeaderSend::WorldLight), vec![("light_level", number(*light_level))])]
+        }
+        ServerMessage::Stats(stats) => {
+            let mut names = vec!["health_points", "capacity"];
+            names.extend(match capabilities.stats {
+                StatsLayout::Basic => vec![],
+                StatsLayout::Tibia300 => {
+                    vec!["intelligence", "strength", "dexterity", "experience_points", "experience_level"]
+                }
+                StatsLayout::Standard => {
+                    vec!["experience_points", "experience_level", "mana_points", "magic_level", "ammunition"]
+                }
+            });
+            let stats = serde_json::to_value(stats)?;
+            let fields = names
+                .into_iter()
+                .map(|name| {
+                    let value = stats_value(&stats, name)?;
+                    // Experience is sent as u16 to 3.x clients
+                    let value = match (capabilities.stats, name) {
+                        (StatsLayout::Tibia300, "experience_points") => value as u16 as u32,
+                        _ => value,
+                    };
+                    Ok((name, number(value)))
+                })
+                .collect::<Result<_>>()?;
+            vec![(server(HeaderSend::Stats), fields)]
+        }
+        ServerMessage::Skills(skills) => {
+            // Fields named after the skill they hold
+            let names: &[(&str, &str)] = if capabilities.skills == SkillLayout::Tibia400 {
+                &[
+                    ("sword", "sword"),
+                    ("club", "club"),
+                    ("gauche", "gauche"),
+                    ("fist", "fist"),
+                    ("missile", "missile"),
+                    ("shield", "shield"),
+                    ("throwing", "distance"),
+                    ("fishing", "fishing"),
+                ]
+            } else {
+                &[
+                    ("sword", "sword"),
+                    ("club", "club"),
+                    ("axe", "axe"),
+                    ("distance", "distance"),
+                    ("shield", "shield"),
+                    ("fist", "fist"),
+                    ("fishing", "fishing"),
+                ]
+            };
+            let skills = serde_json::to_value(skills)?;
+            let fields = names
+                .iter()
+                .map(|&(field, skill)| Ok((field, number(stats_value(&skills, skill)?))))
+                .collect::<Result<_>>()?;
+            vec![(server(HeaderSend::Skills), fields)]
+        }
+        ServerMessage::Chat {
+            chat_type,
+            message,
+            sender,
+            position,
+        } => {
+            let mut bytes = sender.as_ref().map(|sender| format!("{sender}\t").into_bytes()).unwrap_or_default();
+            bytes.extend(match chat_type {
+                ChatType::Yell => encoding::translate_upper(&message.to_uppercase()),
+                _ => encoding::translate(message),
+            });
+            let sent = position.map_or(Position::new(0, 0, 0), |position| {
+                position + capabilities.chat_position_offset
+            });
+            vec![(
+                server(HeaderSend::Chat),
+                vec![
+                    ("position", sent_position(sent, protocol)),
+                    ("chat_type", number(*chat_type as u8)),
+                    ("message", text(&bytes)),
+                ],
+            )]
+        }
+        ServerMessage::UserInfo { name } => vec![(
+            server(HeaderSend::UserInfo),
+            vec![("text", text(format!("INFO: name={name}").as_bytes()))],
+        )],
+        ServerMessage::UserList { names } => {
+            let names: String = names.iter().map(|name| format!("{name}\n")).collect();
+            vec![(server(HeaderSend::UserList), vec![("text", text(names.as_bytes()))])]
+        }
+        ServerMessage::OpenContainer {
+            local_id,
+            item_id,
+            items,
+        } => vec![(
+            server(HeaderSend::OpenContainer),
+            vec![
+                ("local_id", number(*local_id)),
+                ("item_id", number(*item_id)),
+                ("items", FieldValue::Items(items.clone())),
+            ],
+        )],
+        ServerMessage::CloseContainer { local_id } => {
+            vec![(server(HeaderSend::CloseContainer), vec![("local_id", number(*local_id))])]
+        }
+        ServerMessage::Empty(header) => vec![(server(*header), vec![])],
+        ServerMessage::Raw { .. } => return Err(anyhow!("Raw messages have no layout")),
+        ServerMessage::Compound(messages) => messages
+            .iter()
+            .map(|message| expected_messages(message, protocol))
+            .collect::<Result<Vec<_>>>()?
+            .concat(),
+    };
+    Ok(expected)
+}
+
+fn stats_value(values: &Value, name: &str) -> Result<u32> {
+    values[name]
+        .as_u64()
+        .map(|value| value as u32)
+        .ok_or_else(|| anyhow!("No value for {name}"))
+}
+
+/// The single character of the list, on the game server at the address
+fn expected_character_list(address: SocketAddr) -> Expected {
+    let IpAddr::V4(ip) = address.ip() else {
+        unreachable!("Character lists are only sent for IPv4");
+    };
+    let field = |name, value| DecodedField { name, value };
+    (
+        MessageKind::CharacterList,
+        vec![
+            ("character_count", number(1_u8)),
+            (
+                "characters",
+                FieldValue::List(vec![vec![
+                    field("name", text(b"Player")),
+                    field("world", text(b"legbone")),
+                    field("ip", FieldValue::Ipv4(ip)),
+                    field("port", number(address.port())),
+                ]]),
+            ),
+        ],
+    )
+}
+
+#[tokio::test]
+async fn test_server_message_fixtures() -> Result<()> {
+    map::init_map(&config::Map {
+        map_type: MapType::Checkerboard,
+        file: None,
+        tile: None,
+    })?;
+    let update = std::env::var_os("UPDATE_FIXTURES").is_some();
+
+    let mut paths: Vec<_> = std::fs::read_dir(FIXTURES)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    paths.sort();
+
+    let mut failures = vec![];
+    for path in &paths {
+        let mut fixture: Fixture = serde_json::from_str(&std::fs::read_to_string(path)?)
+            .map_err(|err| anyhow!("Invalid fixture {path:?}: {err}"))?;
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+
+        let mut changed = false;
+        for protocol in Protocol::ALL {
+            let actual = fixture.encode(protocol).await?;
+            if actual != UNSUPPORTED && fixture.has_layout() {
+                if let Err(err) = fixture.check_layout(protocol, &hex::decode(&actual)?).await {
+                    failures.push(format!("{name} {protocol:?}: does not match its layout: {err}"));
+                }
+            }
+            let expected = fixture.bytes.get(&(protocol as u16));
+            if expected != Some(&actual) {
+                failures.push(describe(&name, protocol, expected, &actual));
+                fixture.bytes.insert(protocol as u16, actual);
+                changed = true;
+            }
+        }
+
+        if update && changed {
+            write_fixture(path, &fixture)?;
+        }
+    }
+
+    if failures.is_empty() || (update && failures.iter().all(|failure| !failure.contains("layout"))) {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} of {} encodings differ from the fixtures, run with UPDATE_FIXTURES=1 to accept the new bytes:\n{}",
+            failures.len(),
+            paths.len() * Protocol::ALL.len(),
+            failures.join("\n")
+        ))
+    }
+}
+
+fn describe(name: &str, protocol: Protocol, expected: Option<&String>, actual: &str) -> String {
+    match expected {
+        None => format!("{name} {protocol:?}: no fixture, encodes to {actual}"),
+        Some(expected) => {
+            let offset = expected
+                .as_bytes()
+                .chunks(2)
+                .zip(actual.as_bytes().chunks(2))
+                .position(|(expected, actual)| expected != actual)
+                .unwrap_or(expected.len().min(actual.len()) / 2);
+            format!("{name} {protocol:?}: first difference at byte {offset}\n  expected {expected}\n  actual   {actual}")
+        }
+    }
+}
+
+fn write_fixture(path: &Path, fixture: &Fixture) -> Result<()> {
+    let mut json = serde_json::to_string_pretty(fixture)?;
+    json.push('\n');
+    std::fs::write(path, json)?;
+    Ok(())
+}